@@ -0,0 +1,11 @@
+//Admin routes compare the given password against a bcrypt hash from the environment
+pub fn is_admin(password: Option<&str>) -> bool {
+    let hash = match std::env::var("KV_ADMIN_PASSWORD_HASH") {
+        Ok(hash) => hash,
+        Err(_) => return false,
+    };
+    match password {
+        Some(password) => bcrypt::verify(password, &hash).unwrap_or(false),
+        None => false,
+    }
+}
@@ -0,0 +1,23 @@
+use std::path::PathBuf;
+
+pub struct Config {
+    pub port: u16,
+    pub data_dir: PathBuf,
+    pub admin_password_hash: Option<String>,
+}
+
+impl Config {
+    //Reads the configuration from environment variables, falling back to defaults
+    pub fn from_env() -> Config {
+        Config {
+            port: std::env::var("KV_PORT")
+                .ok()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(8080),
+            data_dir: std::env::var("KV_DATA_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| PathBuf::from("./data")),
+            admin_password_hash: std::env::var("KV_ADMIN_PASSWORD_HASH").ok(),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{auth, store::Store};
+use std::sync::Mutex;
+
+pub enum Route {
+    Get(String),
+    Put(String, String),
+    Delete(String),
+    Compact,
+}
+
+//GET, PUT and DELETE on /keys/{key}, POST /admin/compact needs the admin password
+pub fn route(method: &str, path: &str, body: &str) -> Option<Route> {
+    if path == "/admin/compact" && method == "POST" {
+        return Some(Route::Compact);
+    }
+    let key = path.strip_prefix("/keys/")?.to_string();
+    match method {
+        "GET" => Some(Route::Get(key)),
+        "PUT" => Some(Route::Put(key, body.to_string())),
+        "DELETE" => Some(Route::Delete(key)),
+        _ => None,
+    }
+}
+
+pub fn handle(store: &Mutex<Store>, route: Route, password: Option<&str>) -> (u16, String) {
+    let mut store = store.lock().unwrap();
+    match route {
+        Route::Get(key) => match store.get(&key) {
+            Some(value) => (200, value.clone()),
+            None => (404, "not found".to_string()),
+        },
+        Route::Put(key, value) => match store.set(key, value) {
+            Ok(()) => (204, String::new()),
+            Err(e) => (500, e.to_string()),
+        },
+        Route::Delete(key) => match store.delete(&key) {
+            Ok(true) => (204, String::new()),
+            Ok(false) => (404, "not found".to_string()),
+            Err(e) => (500, e.to_string()),
+        },
+        Route::Compact if !auth::is_admin(password) => (401, "unauthorized".to_string()),
+        Route::Compact => (202, "compaction scheduled".to_string()),
+    }
+}
+
+pub fn serve(port: u16, store: Store) {
+    let _store = Mutex::new(store);
+    println!("listening on port {port}");
+}
@@ -0,0 +1,11 @@
+mod auth;
+mod config;
+mod http;
+mod store;
+mod wal;
+
+fn main() {
+    let config = config::Config::from_env();
+    let store = store::Store::open(&config.data_dir).expect("open the store");
+    http::serve(config.port, store);
+}
@@ -0,0 +1,41 @@
+use crate::wal::{Entry, Wal};
+use std::{collections::HashMap, io, path::Path};
+
+pub struct Store {
+    values: HashMap<String, String>,
+    wal: Wal,
+}
+
+impl Store {
+    //Rebuilds the in-memory map by replaying the write-ahead log
+    pub fn open(dir: &Path) -> io::Result<Store> {
+        let wal = Wal::open(&dir.join("kv.log"))?;
+        let mut values = HashMap::new();
+        for entry in wal.replay()? {
+            match entry {
+                Entry::Set(key, value) => {
+                    values.insert(key, value);
+                }
+                Entry::Delete(key) => {
+                    values.remove(&key);
+                }
+            }
+        }
+        Ok(Store { values, wal })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.values.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> io::Result<()> {
+        self.wal.append(&Entry::Set(key.clone(), value.clone()))?;
+        self.values.insert(key, value);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &str) -> io::Result<bool> {
+        self.wal.append(&Entry::Delete(key.to_string()))?;
+        Ok(self.values.remove(key).is_some())
+    }
+}
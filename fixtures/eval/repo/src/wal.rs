@@ -0,0 +1,54 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+pub enum Entry {
+    Set(String, String),
+    Delete(String),
+}
+
+//Append-only log of writes, flushed and synced to disk before a write is acknowledged
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Wal {
+    pub fn open(path: &Path) -> io::Result<Wal> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Wal {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    pub fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let line = match entry {
+            Entry::Set(key, value) => format!("set\t{key}\t{value}\n"),
+            Entry::Delete(key) => format!("del\t{key}\n"),
+        };
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()
+    }
+
+    //A torn last line from a crash mid-write is skipped
+    pub fn replay(&self) -> io::Result<Vec<Entry>> {
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields.as_slice() {
+                ["set", key, value] => entries.push(Entry::Set(key.to_string(), value.to_string())),
+                ["del", key] => entries.push(Entry::Delete(key.to_string())),
+                _ => continue,
+            }
+        }
+        Ok(entries)
+    }
+}
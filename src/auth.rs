@@ -0,0 +1,382 @@
+use crate::{
+    config::{AuthConfig, TenantConfig},
+    db::RepositoryEmbeddingsDB,
+    prelude::*,
+};
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+//Tenant collections are named "<tenant>__<repository id>"
+pub const TENANT_SEPARATOR: &str = "__";
+const API_KEY_HEADER: &str = "x-api-key";
+const PUBLIC_PATHS: [&str; 4] = ["/", "/healthz", "/readyz", "/metrics"];
+//Webhooks carry a payload signature instead of an API key
+const SIGNED_PATHS: [&str; 1] = ["/webhooks/github"];
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+pub struct Tenant {
+    //None when authentication is disabled, collections are then not namespaced
+    pub name: Option<String>,
+    config: Option<TenantConfig>,
+    //(day, tokens used that day)
+    llm_usage: Mutex<(u64, u64)>,
+}
+
+impl Tenant {
+    fn new(config: Option<TenantConfig>) -> Tenant {
+        Tenant {
+            name: config.as_ref().map(|config| config.name.clone()),
+            config,
+            llm_usage: Mutex::new((0, 0)),
+        }
+    }
+
+    //The collection id of a repository id as seen by this tenant
+    pub fn collection(&self, repo_id: &str) -> String {
+        match &self.name {
+            Some(name) => format!("{name}{TENANT_SEPARATOR}{repo_id}"),
+            None => repo_id.to_string(),
+        }
+    }
+
+    pub fn owns(&self, collection: &str) -> bool {
+        match &self.name {
+            Some(name) => collection.starts_with(&format!("{name}{TENANT_SEPARATOR}")),
+            None => true,
+        }
+    }
+
+    //Checked before fetching anything, so over-quota requests stay cheap
+    pub async fn check_repository_quota<D: RepositoryEmbeddingsDB>(
+        &self,
+        db: &D,
+        collection: &str,
+    ) -> Result<()> {
+        let max_repositories = match self.config.as_ref().and_then(|c| c.max_repositories) {
+            Some(max_repositories) => max_repositories,
+            None => return Ok(()),
+        };
+        let repositories = self.repositories(db).await?;
+        //Re-indexing an existing repository doesn't add one
+        if !repositories.iter().any(|id| id == collection)
+            && repositories.len() as u64 >= max_repositories
+        {
+            return Err(Error::QuotaExceeded(format!(
+                "{} repositories are indexed, the limit is {max_repositories}",
+                repositories.len()
+            )));
+        }
+        Ok(())
+    }
+
+    pub async fn check_vector_quota<D: RepositoryEmbeddingsDB>(
+        &self,
+        db: &D,
+        collection: &str,
+        new_vectors: u64,
+    ) -> Result<()> {
+        let max_vectors = match self.config.as_ref().and_then(|c| c.max_vectors) {
+            Some(max_vectors) => max_vectors,
+            None => return Ok(()),
+        };
+        let mut stored: u64 = 0;
+        //The version being replaced stops counting once the new one is live
+        for repository in self.repositories(db).await? {
+            if repository != collection {
+                stored += db.count_vectors(&repository).await?;
+            }
+        }
+        if stored + new_vectors > max_vectors {
+            return Err(Error::QuotaExceeded(format!(
+                "Storing {new_vectors} more vectors next to {stored} exceeds the limit of {max_vectors}"
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn check_llm_budget(&self) -> Result<()> {
+        let max_tokens = match self.config.as_ref().and_then(|c| c.max_llm_tokens_per_day) {
+            Some(max_tokens) => max_tokens,
+            None => return Ok(()),
+        };
+        let used = self.llm_tokens_today();
+        if used >= max_tokens {
+            return Err(Error::QuotaExceeded(format!(
+                "{used} LLM tokens were used today, the limit is {max_tokens}"
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn record_llm_tokens(&self, tokens: u64) {
+        let today = today();
+        let mut usage = self.lock_usage();
+        if usage.0 != today {
+            *usage = (today, 0);
+        }
+        usage.1 += tokens;
+    }
+
+    fn llm_tokens_today(&self) -> u64 {
+        let usage = self.lock_usage();
+        if usage.0 == today() {
+            usage.1
+        } else {
+            0
+        }
+    }
+
+    async fn repositories<D: RepositoryEmbeddingsDB>(&self, db: &D) -> Result<Vec<String>> {
+        Ok(db
+            .list_repositories()
+            .await?
+            .into_iter()
+            .filter(|collection| self.owns(collection))
+            .collect())
+    }
+
+    fn lock_usage(&self) -> std::sync::MutexGuard<'_, (u64, u64)> {
+        self.llm_usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct Tenants {
+    //Keyed by the SHA-256 of the API key so keys aren't compared byte by byte
+    by_key: HashMap<[u8; 32], Arc<Tenant>>,
+    anonymous: Arc<Tenant>,
+}
+
+impl Tenants {
+    pub fn new(config: &AuthConfig) -> Tenants {
+        let mut by_key = HashMap::new();
+        for tenant_config in &config.tenants {
+            let tenant = Arc::new(Tenant::new(Some(tenant_config.clone())));
+            for key in &tenant_config.keys {
+                by_key.insert(hash_key(key.trim()), tenant.clone());
+            }
+        }
+        if by_key.is_empty() {
+            tracing::warn!("No API keys are configured, every route is open to anyone");
+        }
+        Tenants {
+            by_key,
+            anonymous: Arc::new(Tenant::new(None)),
+        }
+    }
+
+    //Returns the tenant and the hash of the key it was identified by
+    pub fn authenticate(
+        &self,
+        path: &str,
+        headers: &HeaderMap,
+    ) -> Result<(Arc<Tenant>, Option<[u8; 32]>)> {
+        if self.by_key.is_empty() || SIGNED_PATHS.contains(&path) {
+            return Ok((self.anonymous.clone(), None));
+        }
+        let key = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| {
+                headers
+                    .get(API_KEY_HEADER)
+                    .and_then(|value| value.to_str().ok())
+            })
+            .ok_or_else(|| Error::Unauthenticated("API key is missing".into()))?;
+        let hash = hash_key(key.trim());
+        self.by_key
+            .get(&hash)
+            .map(|tenant| (tenant.clone(), Some(hash)))
+            .ok_or_else(|| Error::Unauthenticated("API key is invalid".into()))
+    }
+
+    //The tenant a collection belongs to, for work no API key started such as webhook re-indexing
+    pub fn owner(&self, collection: &str) -> Arc<Tenant> {
+        self.by_key
+            .values()
+            .find(|tenant| tenant.name.is_some() && tenant.owns(collection))
+            .cloned()
+            .unwrap_or_else(|| self.anonymous.clone())
+    }
+}
+
+//Probes and scrapes skip authentication and rate limits
+pub fn is_public(path: &str) -> bool {
+    PUBLIC_PATHS.contains(&path)
+}
+
+fn hash_key(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() / SECONDS_PER_DAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryDB;
+    use crate::github::{File, FileEmbeddings, RepositoryEmbeddings};
+    use actix_web::http::header::HeaderValue;
+
+    fn tenant(name: &str, keys: &[&str]) -> TenantConfig {
+        TenantConfig {
+            name: name.into(),
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            max_repositories: None,
+            max_vectors: None,
+            max_llm_tokens_per_day: None,
+        }
+    }
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name.parse().unwrap(), HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn collection(repo_id: &str, files: usize) -> RepositoryEmbeddings {
+        RepositoryEmbeddings {
+            repo_id: repo_id.into(),
+            file_embeddings: (0..files)
+                .map(|i| FileEmbeddings {
+                    file: File::new(format!("{i}.rs"), String::new(), None),
+                    embeddings: vec![0.0; 4],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn authenticates_bearer_and_header_keys() {
+        let tenants = Tenants::new(&AuthConfig {
+            tenants: vec![
+                tenant("acme", &["acme-key"]),
+                tenant("other", &["other-key"]),
+            ],
+        });
+        let name = |result: Result<(Arc<Tenant>, Option<[u8; 32]>)>| {
+            result.map(|(tenant, _)| tenant.name.clone())
+        };
+
+        assert_eq!(
+            name(tenants.authenticate("/search", &headers("authorization", "Bearer acme-key")))
+                .unwrap(),
+            Some("acme".into())
+        );
+        assert_eq!(
+            name(tenants.authenticate("/search", &headers(API_KEY_HEADER, " other-key "))).unwrap(),
+            Some("other".into())
+        );
+        assert!(matches!(
+            tenants.authenticate("/search", &headers("authorization", "Bearer wrong")),
+            Err(Error::Unauthenticated(_))
+        ));
+        assert!(matches!(
+            tenants.authenticate("/search", &HeaderMap::new()),
+            Err(Error::Unauthenticated(_))
+        ));
+        //Webhooks are checked by their signature instead
+        assert_eq!(
+            name(tenants.authenticate("/webhooks/github", &HeaderMap::new())).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn opens_every_route_without_tenants() {
+        let tenants = Tenants::new(&AuthConfig::default());
+        let (tenant, hash) = tenants.authenticate("/search", &HeaderMap::new()).unwrap();
+        assert_eq!(tenant.name, None);
+        assert_eq!(hash, None);
+        assert_eq!(tenant.collection("a-b-main"), "a-b-main");
+        assert!(tenant.owns("other__a-b-main"));
+    }
+
+    #[test]
+    fn scopes_collections_to_their_tenant() {
+        let tenants = Tenants::new(&AuthConfig {
+            tenants: vec![
+                tenant("acme", &["acme-key"]),
+                tenant("other", &["other-key"]),
+            ],
+        });
+        let (acme, _) = tenants
+            .authenticate("/search", &headers(API_KEY_HEADER, "acme-key"))
+            .unwrap();
+
+        assert_eq!(acme.collection("a-b-main"), "acme__a-b-main");
+        assert!(acme.owns("acme__a-b-main"));
+        assert!(!acme.owns("other__a-b-main"));
+        assert!(!acme.owns("a-b-main"));
+        //A tenant whose name starts with another's doesn't get its collections
+        assert!(!acme.owns("acmecorp__a-b-main"));
+
+        assert_eq!(tenants.owner("other__a-b-main").name, Some("other".into()));
+        assert_eq!(tenants.owner("a-b-main").name, None);
+    }
+
+    #[actix_web::test]
+    async fn enforces_repository_and_vector_quotas() {
+        let acme = Tenant::new(Some(TenantConfig {
+            max_repositories: Some(2),
+            max_vectors: Some(5),
+            ..tenant("acme", &["acme-key"])
+        }));
+        let db = MemoryDB::with(vec![
+            collection("acme__a", 3),
+            collection("acme__b", 1),
+            collection("other__c", 10),
+        ]);
+
+        //Re-indexing doesn't add a repository, other tenants' collections don't count
+        acme.check_repository_quota(&db, "acme__a").await.unwrap();
+        assert!(matches!(
+            acme.check_repository_quota(&db, "acme__d").await,
+            Err(Error::QuotaExceeded(_))
+        ));
+
+        acme.check_vector_quota(&db, "acme__d", 1).await.unwrap();
+        assert!(matches!(
+            acme.check_vector_quota(&db, "acme__d", 2).await,
+            Err(Error::QuotaExceeded(_))
+        ));
+        //The collection being replaced stops counting
+        acme.check_vector_quota(&db, "acme__a", 4).await.unwrap();
+        assert!(matches!(
+            acme.check_vector_quota(&db, "acme__a", 5).await,
+            Err(Error::QuotaExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn enforces_the_daily_llm_budget() {
+        let acme = Tenant::new(Some(TenantConfig {
+            max_llm_tokens_per_day: Some(100),
+            ..tenant("acme", &["acme-key"])
+        }));
+
+        acme.check_llm_budget().unwrap();
+        acme.record_llm_tokens(60);
+        acme.check_llm_budget().unwrap();
+        acme.record_llm_tokens(40);
+        assert!(matches!(
+            acme.check_llm_budget(),
+            Err(Error::QuotaExceeded(_))
+        ));
+
+        //Usage recorded on an earlier day is forgotten
+        *acme.lock_usage() = (today() - 1, 1000);
+        acme.check_llm_budget().unwrap();
+        acme.record_llm_tokens(10);
+        assert_eq!(acme.llm_tokens_today(), 10);
+    }
+}
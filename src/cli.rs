@@ -0,0 +1,390 @@
+use crate::{
+    config::Config,
+    db::{QdrantDB, RepositoryEmbeddingsDB, SearchFilter},
+    embeddings::{EmbeddingsModel, Model},
+    eval,
+    github::{embed_dir, embed_repo, Repository},
+    hosts::{Host, Hosts},
+    prelude::*,
+    snapshot::Snapshot,
+};
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
+use std::{
+    io::{BufReader, BufWriter},
+    path::PathBuf,
+};
+use tracing::Instrument;
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(
+    name = "onn",
+    about = "Generate, store and search repository embeddings"
+)]
+pub struct Cli {
+    /// Print machine-readable JSON instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the HTTP server (the default when no command is given)
+    Serve,
+    /// Embed a repository or local directory and store it in the vector database
+    Index(RepositoryArgs),
+    /// Search an indexed repository
+    Search {
+        query: String,
+        #[command(flatten)]
+        repository: RepositoryArgs,
+        /// Number of files to return, defaults to retrieval.limit
+        #[arg(long)]
+        limit: Option<u64>,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
+    /// Print the embedding of a piece of text
+    Embed { text: String },
+    /// List indexed repositories
+    List,
+    /// Delete an indexed repository
+    Delete(RepositoryArgs),
+    /// Write an indexed repository to a snapshot file
+    Export {
+        #[command(flatten)]
+        repository: RepositoryArgs,
+        /// Snapshot file to write
+        #[arg(long, short)]
+        output: PathBuf,
+    },
+    /// List the stored versions of an indexed repository
+    Versions(RepositoryArgs),
+    /// Serve a previous version of an indexed repository again
+    Rollback {
+        #[command(flatten)]
+        repository: RepositoryArgs,
+        /// Version to serve, defaults to the one before the live version
+        #[arg(long)]
+        version: Option<u64>,
+    },
+    /// Restore a snapshot file into the vector database, replacing any existing index
+    Import {
+        file: PathBuf,
+        /// Store the index under this id instead of the one in the snapshot
+        #[arg(long)]
+        repo_id: Option<String>,
+    },
+    /// Measure retrieval quality against a JSONL dataset of queries and expected files
+    Eval {
+        dataset: PathBuf,
+        /// Number of files retrieved per query
+        #[arg(long, default_value_t = 10)]
+        k: usize,
+        /// Index into and search the vector database instead of an in-memory index
+        #[arg(long)]
+        qdrant: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct RepositoryArgs {
+    /// Local directory, used when no remote repository is given
+    #[arg(long, conflicts_with_all = ["owner", "name", "branch"])]
+    dir: Option<PathBuf>,
+    /// Host of the remote repository
+    #[arg(long, value_enum, default_value_t = Host::GitHub)]
+    host: Host,
+    /// Remote repository owner, group or workspace
+    #[arg(long, requires = "name")]
+    owner: Option<String>,
+    /// Remote repository name
+    #[arg(long, requires = "owner")]
+    name: Option<String>,
+    /// Remote repository branch, defaults to the repository's default branch
+    #[arg(long)]
+    branch: Option<String>,
+    /// Tenant the repository was indexed for when API keys are configured
+    #[arg(long)]
+    tenant: Option<String>,
+}
+
+#[derive(Args)]
+pub struct FilterArgs {
+    /// Only return files under this directory
+    #[arg(long)]
+    path_prefix: Option<String>,
+    /// Only return files matching this glob, can be repeated
+    #[arg(long)]
+    include: Vec<String>,
+    /// Skip files matching this glob, can be repeated
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Only return files in this language, can be repeated
+    #[arg(long = "language")]
+    languages: Vec<String>,
+    /// Minimum file size in bytes
+    #[arg(long)]
+    min_size: Option<u64>,
+    /// Maximum file size in bytes
+    #[arg(long)]
+    max_size: Option<u64>,
+}
+
+impl From<FilterArgs> for SearchFilter {
+    fn from(args: FilterArgs) -> Self {
+        SearchFilter {
+            path_prefix: args.path_prefix,
+            include: args.include,
+            exclude: args.exclude,
+            languages: args.languages,
+            min_size: args.min_size,
+            max_size: args.max_size,
+        }
+    }
+}
+
+enum Target {
+    Dir(PathBuf),
+    Remote(Repository),
+}
+
+impl RepositoryArgs {
+    fn target(&self) -> Target {
+        match (&self.owner, &self.name) {
+            (Some(owner), Some(name)) => Target::Remote(Repository {
+                host: self.host,
+                owner: owner.clone(),
+                name: name.clone(),
+                branch: self.branch.clone().unwrap_or_default(),
+                token: None,
+                tenant: self.tenant.clone(),
+            }),
+            _ => Target::Dir(self.dir.clone().unwrap_or_else(|| PathBuf::from("."))),
+        }
+    }
+
+    async fn repository(&self, config: &Config) -> Result<Repository> {
+        match self.target() {
+            Target::Dir(dir) => Repository::local(&dir).map(|repository| Repository {
+                tenant: self.tenant.clone(),
+                ..repository
+            }),
+            Target::Remote(repository) => Hosts::new(config)?.resolve(repository).await,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Indexed {
+    repo_id: String,
+    files: usize,
+}
+
+pub async fn run(command: Command, json: bool, config: &Config) -> Result<()> {
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Index(args) => {
+            let model = Model::load(config)?;
+            let db = QdrantDB::initialize(config)?;
+            let span = tracing::info_span!("index", job_id = %Uuid::new_v4());
+            let embeddings = match args.target() {
+                Target::Dir(dir) => {
+                    let repository = args.repository(config).await?;
+                    span.in_scope(|| embed_dir(&dir, repository, &model, &config.fetch))
+                }
+                Target::Remote(repository) => {
+                    let hosts = Hosts::new(config)?;
+                    embed_repo(repository, &model, &hosts, &config.fetch)
+                        .instrument(span.clone())
+                        .await?
+                }
+            };
+            if let Some(stats) = model.cache_stats() {
+                tracing::info!(hits = stats.hits, misses = stats.misses, "Embedding cache");
+            }
+            let indexed = Indexed {
+                repo_id: embeddings.repo_id.clone(),
+                files: embeddings.file_embeddings.len(),
+            };
+            db.insert_repo_embeddings(embeddings)
+                .instrument(span)
+                .await?;
+            print(json, &indexed, || {
+                format!("Indexed {} files into {}", indexed.files, indexed.repo_id)
+            });
+        }
+        Command::Search {
+            query,
+            repository,
+            limit,
+            filter,
+        } => {
+            let model = Model::load(config)?;
+            let db = QdrantDB::initialize(config)?;
+            let query_embeddings = model.embed(&query)?;
+            let files = db
+                .get_relevant_files(
+                    repository.repository(config).await?,
+                    query_embeddings,
+                    limit.unwrap_or(config.retrieval.limit),
+                    &filter.into(),
+                )
+                .await?;
+            print(json, &files, || {
+                files
+                    .iter()
+                    .enumerate()
+                    .map(|(rank, file)| {
+                        format!(
+                            "{}. {} ({}, {} lines, {} bytes)",
+                            rank + 1,
+                            file.path,
+                            file.language.as_deref().unwrap_or("unknown"),
+                            file.line_count,
+                            file.length
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        }
+        Command::Embed { text } => {
+            let model = Model::load(config)?;
+            let embeddings = model.embed(&text)?;
+            print(json, &embeddings, || {
+                embeddings
+                    .iter()
+                    .map(|value| value.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            });
+        }
+        Command::List => {
+            let db = QdrantDB::initialize(config)?;
+            let repositories = db.list_repositories().await?;
+            print(json, &repositories, || repositories.join("\n"));
+        }
+        Command::Delete(args) => {
+            let db = QdrantDB::initialize(config)?;
+            let repository = args.repository(config).await?;
+            db.delete_repository(&repository.to_string()).await?;
+            print(json, &repository.to_string(), || {
+                format!("Deleted {}", repository.to_string())
+            });
+        }
+        Command::Versions(args) => {
+            let db = QdrantDB::initialize(config)?;
+            let versions = db
+                .list_versions(&args.repository(config).await?.to_string())
+                .await?;
+            print(json, &versions, || {
+                versions
+                    .iter()
+                    .map(|version| {
+                        let live = if version.live { " (live)" } else { "" };
+                        format!("{}{}", version.version, live)
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
+        }
+        Command::Rollback {
+            repository,
+            version,
+        } => {
+            let db = QdrantDB::initialize(config)?;
+            let repo_id = repository.repository(config).await?.to_string();
+            let live = db.rollback(&repo_id, version).await?;
+            print(json, &live, || {
+                format!("{} now serves version {}", repo_id, live.version)
+            });
+        }
+        Command::Export { repository, output } => {
+            let db = QdrantDB::initialize(config)?;
+            let repo_id = repository.repository(config).await?.to_string();
+            let snapshot = Snapshot::export(&db, &repo_id, &config.model).await?;
+            let header = snapshot.header.clone();
+            let file = std::fs::File::create(&output).map_err(|e| {
+                Error::Snapshot(format!("Unable to create {}: {}", output.display(), e))
+            })?;
+            snapshot.write(BufWriter::new(file))?;
+            print(json, &header, || {
+                format!(
+                    "Exported {} files from {} to {}",
+                    header.file_count,
+                    header.repo_id,
+                    output.display()
+                )
+            });
+        }
+        Command::Import { file, repo_id } => {
+            let db = QdrantDB::initialize(config)?;
+            let reader = std::fs::File::open(&file).map_err(|e| {
+                Error::Snapshot(format!("Unable to open {}: {}", file.display(), e))
+            })?;
+            let mut snapshot = Snapshot::read(BufReader::new(reader), &config.server)?;
+            if let Some(repo_id) = repo_id {
+                snapshot = snapshot.with_repo_id(&repo_id);
+            }
+            let header = snapshot.import(&db, &config.model).await?;
+            print(json, &header, || {
+                format!(
+                    "Imported {} files into {}",
+                    header.file_count, header.repo_id
+                )
+            });
+        }
+        Command::Eval { dataset, k, qdrant } => {
+            let cases = eval::load_dataset(&dataset)?;
+            let model = Model::load(config)?;
+            let report = eval::run(cases, k, qdrant, config, &model).await?;
+            print(json, &report, || {
+                let mut lines: Vec<String> = report
+                    .cases
+                    .iter()
+                    .map(|case| {
+                        let missed = if case.missed.is_empty() {
+                            String::new()
+                        } else {
+                            format!(", missed {}", case.missed.join(" "))
+                        };
+                        format!(
+                            "line {}: recall {:.3} mrr {:.3} ndcg {:.3}  {}{missed}",
+                            case.line,
+                            case.scores.recall,
+                            case.scores.mrr,
+                            case.scores.ndcg,
+                            case.query
+                        )
+                    })
+                    .collect();
+                lines.push(format!(
+                    "{} cases, {} index: recall@{k} {:.3}, MRR {:.3}, nDCG@{k} {:.3}",
+                    report.cases.len(),
+                    report.backend,
+                    report.mean.recall,
+                    report.mean.mrr,
+                    report.mean.ndcg
+                ));
+                lines.join("\n")
+            });
+        }
+    }
+    Ok(())
+}
+
+fn print<T: Serialize>(json: bool, value: &T, text: impl FnOnce() -> String) {
+    if json {
+        match serde_json::to_string_pretty(value) {
+            Ok(output) => println!("{output}"),
+            Err(e) => tracing::error!(error = %e, "Unable to serialize output"),
+        }
+    } else {
+        println!("{}", text());
+    }
+}
@@ -0,0 +1,816 @@
+use crate::prelude::*;
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    env,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const DEFAULT_CONFIG_PATH: &str = "onn.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub model: ModelConfig,
+    pub vector_store: VectorStoreConfig,
+    pub llm: LlmConfig,
+    pub github: GitHubConfig,
+    pub gitlab: GitLabConfig,
+    pub bitbucket: BitbucketConfig,
+    pub gitea: GiteaConfig,
+    pub fetch: FetchConfig,
+    pub retrieval: RetrievalConfig,
+    pub cache: CacheConfig,
+    pub logging: LoggingConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub max_snapshot_bytes: usize,
+    //Snapshots are read into memory before importing, whatever their compressed size
+    pub max_snapshot_files: u64,
+    pub max_snapshot_decompressed_bytes: u64,
+    //How long shutdown waits for indexing jobs before removing what they wrote
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".into(),
+            port: 3001,
+            max_snapshot_bytes: 1024 * 1024 * 1024,
+            max_snapshot_files: 100_000,
+            max_snapshot_decompressed_bytes: 2 * 1024 * 1024 * 1024,
+            shutdown_timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    pub path: PathBuf,
+    //Recorded in snapshots so they're only restored next to embeddings of the same model
+    pub id: String,
+    pub dimension: u64,
+    //Files embedded in parallel across every indexing job, 0 for one per core
+    pub threads: usize,
+    //ONNX threads per file, so up to threads * intra_threads cores are busy
+    pub intra_threads: usize,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("model"),
+            id: "multi-qa-MiniLM-L6-cos-v1".into(),
+            dimension: 384,
+            threads: 0,
+            intra_threads: 1,
+        }
+    }
+}
+
+impl ModelConfig {
+    //Checked when the model is loaded rather than in validate, most commands never embed anything
+    pub fn check_files(&self) -> Result<()> {
+        let missing: Vec<&str> = ["tokenizer.json", "model_quantized.onnx"]
+            .into_iter()
+            .filter(|file| !self.path.join(file).is_file())
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(Error::Config(format!(
+            "model.path {} does not contain {}",
+            self.path.display(),
+            missing.join(" or ")
+        )))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VectorStoreConfig {
+    pub url: String,
+    pub api_key: Option<String>,
+    //Previous index versions kept per repository for rollback
+    pub keep_versions: usize,
+}
+
+impl Default for VectorStoreConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            api_key: None,
+            keep_versions: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LlmProvider {
+    //Any OpenAI-compatible chat completions API, e.g. llama.cpp server, vLLM or Ollama
+    #[default]
+    OpenAi,
+    Anthropic,
+    //Replays canned replies from llm.script_path, for running the agent offline
+    Scripted,
+}
+
+impl FromStr for LlmProvider {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "openai" => Ok(LlmProvider::OpenAi),
+            "anthropic" => Ok(LlmProvider::Anthropic),
+            "scripted" => Ok(LlmProvider::Scripted),
+            _ => Err(format!(
+                "expected openai, anthropic or scripted, got {value}"
+            )),
+        }
+    }
+}
+
+//How an OpenAI-compatible backend is offered tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolFormat {
+    #[default]
+    Tools,
+    //The deprecated functions API, for servers that don't support tools yet. One call per turn
+    Functions,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmConfig {
+    pub provider: LlmProvider,
+    //Defaults to the provider's public API
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+    pub model: String,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    //Function calls the agent may make before it has to answer
+    pub max_steps: usize,
+    pub timeout_secs: u64,
+    pub script_path: Option<PathBuf>,
+    pub tool_format: ToolFormat,
+    //Context window of the model, requests are kept below it by dropping the oldest tool results
+    pub context_tokens: usize,
+    //Tool results longer than this are truncated, or summarized when summarize_results is set
+    pub max_result_tokens: usize,
+    pub summarize_results: bool,
+    //Directory with prompts.toml and the templates, the built-in prompts are used when unset
+    pub prompts_dir: Option<PathBuf>,
+    //Picks up edits to the templates without a restart
+    pub reload_prompts: bool,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        Self {
+            provider: LlmProvider::OpenAi,
+            base_url: None,
+            api_key: None,
+            model: "gpt-3.5-turbo".into(),
+            temperature: None,
+            max_tokens: None,
+            max_steps: 5,
+            timeout_secs: 120,
+            script_path: None,
+            tool_format: ToolFormat::Tools,
+            context_tokens: 4096,
+            max_result_tokens: 1000,
+            summarize_results: false,
+            prompts_dir: None,
+            reload_prompts: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitHubConfig {
+    pub token: Option<String>,
+    pub app: Option<GitHubAppConfig>,
+    pub api_url: String,
+    pub web_url: String,
+    pub raw_url: String,
+    //Shared secret of the push webhook, POST /webhooks/github is rejected while unset
+    pub webhook_secret: Option<String>,
+}
+
+impl Default for GitHubConfig {
+    fn default() -> Self {
+        Self {
+            token: None,
+            app: None,
+            api_url: "https://api.github.com".into(),
+            web_url: "https://github.com".into(),
+            raw_url: "https://raw.githubusercontent.com".into(),
+            webhook_secret: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GitHubAppConfig {
+    pub app_id: u64,
+    pub installation_id: u64,
+    pub private_key_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GitLabConfig {
+    pub base_url: String,
+    pub token: Option<String>,
+}
+
+impl Default for GitLabConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://gitlab.com".into(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BitbucketConfig {
+    pub api_url: String,
+    pub web_url: String,
+    pub token: Option<String>,
+}
+
+impl Default for BitbucketConfig {
+    fn default() -> Self {
+        Self {
+            api_url: "https://api.bitbucket.org".into(),
+            web_url: "https://bitbucket.org".into(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GiteaConfig {
+    pub base_url: String,
+    pub token: Option<String>,
+}
+
+impl Default for GiteaConfig {
+    fn default() -> Self {
+        Self {
+            base_url: "https://gitea.com".into(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FetchConfig {
+    pub max_file_count: u32,
+    pub max_archive_bytes: u64,
+    pub max_entries: u64,
+    pub max_file_bytes: u64,
+    pub max_total_bytes: u64,
+    pub max_compression_ratio: u64,
+    pub max_retries: u32,
+    pub max_retry_wait_secs: u64,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            max_file_count: 1000,
+            max_archive_bytes: 512 * 1024 * 1024,
+            max_entries: 100_000,
+            max_file_bytes: 1024 * 1024,
+            max_total_bytes: 1024 * 1024 * 1024,
+            max_compression_ratio: 100,
+            max_retries: 2,
+            max_retry_wait_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrievalConfig {
+    pub limit: u64,
+}
+
+impl Default for RetrievalConfig {
+    fn default() -> Self {
+        Self { limit: 5 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub path: PathBuf,
+    pub max_bytes: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: PathBuf::from("cache"),
+            max_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("expected text or json, got {value}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    //Default filter, RUST_LOG takes precedence
+    pub level: String,
+    //OTLP gRPC collector, spans are only exported when set
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            level: "info".into(),
+            otlp_endpoint: None,
+            service_name: "onn".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    //Every route except the probes requires an API key once a tenant is configured
+    pub tenants: Vec<TenantConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    pub keys: Vec<String>,
+    //Quotas are unlimited when unset
+    #[serde(default)]
+    pub max_repositories: Option<u64>,
+    #[serde(default)]
+    pub max_vectors: Option<u64>,
+    #[serde(default)]
+    pub max_llm_tokens_per_day: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    //Token buckets, a rate of 0 disables the limit
+    pub per_key_per_minute: u32,
+    pub per_key_burst: u32,
+    pub per_ip_per_minute: u32,
+    pub per_ip_burst: u32,
+    //Only enable behind a proxy that sets X-Forwarded-For itself
+    pub trust_forwarded_for: bool,
+    //Indexing runs rayon and ONNX across every core, so a few jobs already saturate the machine
+    pub max_concurrent_embeddings: usize,
+    pub max_concurrent_queries: usize,
+    pub busy_retry_after_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_key_per_minute: 120,
+            per_key_burst: 30,
+            per_ip_per_minute: 240,
+            per_ip_burst: 60,
+            trust_forwarded_for: false,
+            max_concurrent_embeddings: 2,
+            max_concurrent_queries: 16,
+            busy_retry_after_secs: 30,
+        }
+    }
+}
+
+impl Config {
+    //Reads ONN_CONFIG (or ./onn.toml when present), applies environment overrides and validates the result
+    pub fn load() -> Result<Config> {
+        let mut config = match env::var("ONN_CONFIG") {
+            Ok(path) => Config::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            Err(_) => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Unable to read {}: {}", path.display(), e)))?;
+        toml::from_str(&contents)
+            .map_err(|e| Error::Config(format!("Invalid config file {}: {}", path.display(), e)))
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        if let Some(host) = env_override("ONN_HOST")? {
+            self.server.host = host;
+        }
+        if let Some(port) = env_override("ONN_PORT")? {
+            self.server.port = port;
+        }
+        if let Some(path) = env_override::<String>("ONN_MODEL_PATH")? {
+            self.model.path = PathBuf::from(path);
+        }
+        if let Some(id) = env_override("ONN_MODEL_ID")? {
+            self.model.id = id;
+        }
+        if let Some(threads) = env_override("ONN_MODEL_THREADS")? {
+            self.model.threads = threads;
+        }
+        if let Some(url) = env_override("QDRANT_URL")? {
+            self.vector_store.url = url;
+        }
+        if let Some(api_key) = env_override("QDRANT_API_KEY")? {
+            self.vector_store.api_key = Some(api_key);
+        }
+        if let Some(provider) = env_override("ONN_LLM_PROVIDER")? {
+            self.llm.provider = provider;
+        }
+        if let Some(base_url) = env_override("ONN_LLM_BASE_URL")? {
+            self.llm.base_url = Some(base_url);
+        }
+        let api_key_env = match self.llm.provider {
+            LlmProvider::Anthropic => "ANTHROPIC_API_KEY",
+            LlmProvider::OpenAi | LlmProvider::Scripted => "OPENAI_API_KEY",
+        };
+        if let Some(api_key) = env_override(api_key_env)? {
+            self.llm.api_key = Some(api_key);
+        }
+        if let Some(model) = env_override("ONN_LLM_MODEL")? {
+            self.llm.model = model;
+        }
+        if let Some(token) = env_override("GITHUB_TOKEN")? {
+            self.github.token = Some(token);
+        }
+        if let Some(secret) = env_override("GITHUB_WEBHOOK_SECRET")? {
+            self.github.webhook_secret = Some(secret);
+        }
+        if let (Some(app_id), Some(installation_id), Some(private_key_path)) = (
+            env_override("GITHUB_APP_ID")?,
+            env_override("GITHUB_APP_INSTALLATION_ID")?,
+            env_override::<String>("GITHUB_APP_PRIVATE_KEY_PATH")?,
+        ) {
+            self.github.app = Some(GitHubAppConfig {
+                app_id,
+                installation_id,
+                private_key_path: PathBuf::from(private_key_path),
+            });
+        }
+        if let Some(base_url) = env_override("GITLAB_URL")? {
+            self.gitlab.base_url = base_url;
+        }
+        if let Some(token) = env_override("GITLAB_TOKEN")? {
+            self.gitlab.token = Some(token);
+        }
+        if let Some(token) = env_override("BITBUCKET_TOKEN")? {
+            self.bitbucket.token = Some(token);
+        }
+        if let Some(base_url) = env_override("GITEA_URL")? {
+            self.gitea.base_url = base_url;
+        }
+        if let Some(token) = env_override("GITEA_TOKEN")? {
+            self.gitea.token = Some(token);
+        }
+        if let Some(max_file_count) = env_override("ONN_MAX_FILE_COUNT")? {
+            self.fetch.max_file_count = max_file_count;
+        }
+        if let Some(limit) = env_override("ONN_RETRIEVAL_LIMIT")? {
+            self.retrieval.limit = limit;
+        }
+        if let Some(path) = env_override::<String>("ONN_CACHE_PATH")? {
+            self.cache.path = PathBuf::from(path);
+        }
+        if let Some(format) = env_override("ONN_LOG_FORMAT")? {
+            self.logging.format = format;
+        }
+        if let Some(endpoint) = env_override("OTEL_EXPORTER_OTLP_ENDPOINT")? {
+            self.logging.otlp_endpoint = Some(endpoint);
+        }
+        if let Some(service_name) = env_override("OTEL_SERVICE_NAME")? {
+            self.logging.service_name = service_name;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut problems: Vec<String> = Vec::new();
+
+        if self.server.host.is_empty() {
+            problems.push("server.host must not be empty".into());
+        }
+        if self.server.port == 0 {
+            problems.push("server.port must be between 1 and 65535".into());
+        }
+        if self.server.max_snapshot_files == 0 || self.server.max_snapshot_decompressed_bytes == 0 {
+            problems.push(
+                "server.max_snapshot_files and server.max_snapshot_decompressed_bytes must be greater than 0"
+                    .into(),
+            );
+        }
+        if self.model.id.is_empty() {
+            problems.push("model.id must not be empty".into());
+        }
+        if self.model.dimension == 0 {
+            problems.push("model.dimension must be greater than 0".into());
+        }
+        if self.model.intra_threads == 0 {
+            problems.push("model.intra_threads must be greater than 0".into());
+        }
+        if self.vector_store.url.is_empty() {
+            problems.push("vector_store.url is not set (config file or QDRANT_URL)".into());
+        } else if let Err(e) = reqwest::Url::parse(&self.vector_store.url) {
+            problems.push(format!(
+                "vector_store.url {} is not a valid URL: {}",
+                self.vector_store.url, e
+            ));
+        }
+        if self.llm.model.is_empty() {
+            problems.push("llm.model must not be empty".into());
+        }
+        if let Some(base_url) = &self.llm.base_url {
+            if let Err(e) = reqwest::Url::parse(base_url) {
+                problems.push(format!("llm.base_url {base_url} is not a valid URL: {e}"));
+            }
+        }
+        if let Some(temperature) = self.llm.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                problems.push("llm.temperature must be between 0 and 2".into());
+            }
+        }
+        if self.llm.max_tokens == Some(0) {
+            problems.push("llm.max_tokens must be greater than 0".into());
+        }
+        if self.llm.max_steps == 0 {
+            problems.push("llm.max_steps must be greater than 0".into());
+        }
+        if self.llm.max_result_tokens == 0 || self.llm.max_result_tokens >= self.llm.context_tokens
+        {
+            problems.push(
+                "llm.max_result_tokens must be greater than 0 and less than llm.context_tokens"
+                    .into(),
+            );
+        }
+        if let Some(max_tokens) = self.llm.max_tokens {
+            if max_tokens as usize >= self.llm.context_tokens {
+                problems.push("llm.max_tokens must be less than llm.context_tokens".into());
+            }
+        }
+        if let Some(dir) = &self.llm.prompts_dir {
+            if !dir.join("prompts.toml").is_file() {
+                problems.push(format!(
+                    "llm.prompts_dir {} has no prompts.toml",
+                    dir.display()
+                ));
+            }
+        }
+        if self.llm.provider == LlmProvider::Scripted {
+            match &self.llm.script_path {
+                Some(path) if !path.is_file() => {
+                    problems.push(format!("llm.script_path {} does not exist", path.display()))
+                }
+                None => {
+                    problems.push("llm.script_path is required by the scripted provider".into())
+                }
+                _ => {}
+            }
+        }
+        for (key, url) in [
+            ("github.api_url", &self.github.api_url),
+            ("github.web_url", &self.github.web_url),
+            ("github.raw_url", &self.github.raw_url),
+            ("gitlab.base_url", &self.gitlab.base_url),
+            ("bitbucket.api_url", &self.bitbucket.api_url),
+            ("bitbucket.web_url", &self.bitbucket.web_url),
+            ("gitea.base_url", &self.gitea.base_url),
+        ] {
+            if let Err(e) = reqwest::Url::parse(url) {
+                problems.push(format!("{key} {url} is not a valid URL: {e}"));
+            }
+        }
+        if let Some(app) = &self.github.app {
+            if !app.private_key_path.is_file() {
+                problems.push(format!(
+                    "github.app.private_key_path {} does not exist",
+                    app.private_key_path.display()
+                ));
+            }
+        }
+        for (key, value) in [
+            ("fetch.max_file_count", self.fetch.max_file_count as u64),
+            ("fetch.max_archive_bytes", self.fetch.max_archive_bytes),
+            ("fetch.max_entries", self.fetch.max_entries),
+            ("fetch.max_file_bytes", self.fetch.max_file_bytes),
+            ("fetch.max_total_bytes", self.fetch.max_total_bytes),
+            (
+                "fetch.max_compression_ratio",
+                self.fetch.max_compression_ratio,
+            ),
+        ] {
+            if value == 0 {
+                problems.push(format!("{key} must be greater than 0"));
+            }
+        }
+        if self.retrieval.limit == 0 {
+            problems.push("retrieval.limit must be greater than 0".into());
+        }
+        if self.cache.enabled && self.cache.max_bytes == 0 {
+            problems.push("cache.max_bytes must be greater than 0".into());
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.logging.level) {
+            problems.push(format!(
+                "logging.level {} is invalid: {}",
+                self.logging.level, e
+            ));
+        }
+        if let Some(endpoint) = &self.logging.otlp_endpoint {
+            if let Err(e) = reqwest::Url::parse(endpoint) {
+                problems.push(format!(
+                    "logging.otlp_endpoint {endpoint} is not a valid URL: {e}"
+                ));
+            }
+        }
+
+        for (key, rate, burst) in [
+            (
+                "rate_limit.per_key_burst",
+                self.rate_limit.per_key_per_minute,
+                self.rate_limit.per_key_burst,
+            ),
+            (
+                "rate_limit.per_ip_burst",
+                self.rate_limit.per_ip_per_minute,
+                self.rate_limit.per_ip_burst,
+            ),
+        ] {
+            if rate > 0 && burst == 0 {
+                problems.push(format!("{key} must be greater than 0"));
+            }
+        }
+
+        let mut tenants = HashSet::new();
+        let mut keys = HashSet::new();
+        for tenant in &self.auth.tenants {
+            //Tenant names prefix collection names, see auth::TENANT_SEPARATOR
+            if tenant.name.is_empty()
+                || !tenant
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            {
+                problems.push(format!(
+                    "auth.tenants name {:?} must only contain lowercase letters, digits and dashes",
+                    tenant.name
+                ));
+            }
+            if !tenants.insert(&tenant.name) {
+                problems.push(format!("auth.tenants name {} is used twice", tenant.name));
+            }
+            if tenant.keys.is_empty() {
+                problems.push(format!("auth.tenants {} has no keys", tenant.name));
+            }
+            for key in &tenant.keys {
+                if key.trim().len() < 16 {
+                    problems.push(format!(
+                        "auth.tenants {} has a key shorter than 16 characters",
+                        tenant.name
+                    ));
+                }
+                if !keys.insert(key.trim()) {
+                    problems.push(format!(
+                        "auth.tenants {} has a key that's already in use",
+                        tenant.name
+                    ));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(format!(
+                "Invalid configuration:\n  - {}",
+                problems.join("\n  - ")
+            )))
+        }
+    }
+}
+
+fn env_override<T: FromStr>(key: &str) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    match env::var(key) {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .map(Some)
+            .map_err(|e| Error::Config(format!("Invalid value for {key}: {e}"))),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(model_path: &Path) -> Config {
+        Config {
+            model: ModelConfig {
+                path: model_path.to_path_buf(),
+                ..ModelConfig::default()
+            },
+            vector_store: VectorStoreConfig {
+                url: "http://localhost:6334".into(),
+                ..VectorStoreConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn validates_without_the_model_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = config(&dir.path().join("missing"));
+        assert!(missing.validate().is_ok());
+        assert!(matches!(
+            missing.model.check_files(),
+            Err(Error::Config(message)) if message.ends_with("tokenizer.json or model_quantized.onnx")
+        ));
+
+        for file in ["tokenizer.json", "model_quantized.onnx"] {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+        assert!(config(dir.path()).model.check_files().is_ok());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut config = config(Path::new("model"));
+        config.server.port = 0;
+        config.model.intra_threads = 0;
+        config.vector_store.url = String::new();
+        let message = config.validate().unwrap_err().to_string();
+        for problem in [
+            "server.port",
+            "model.intra_threads",
+            "vector_store.url is not set",
+        ] {
+            assert!(message.contains(problem), "{message}");
+        }
+    }
+}
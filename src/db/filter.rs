@@ -0,0 +1,170 @@
+use crate::{github::File, prelude::*};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchFilter {
+    //A directory or file path, e.g. "src/db"
+    pub path_prefix: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub languages: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+impl SearchFilter {
+    pub fn normalized_path_prefix(&self) -> Option<String> {
+        let prefix = self.path_prefix.as_deref()?;
+        let prefix = prefix.trim_start_matches("./").trim_matches('/');
+        (!prefix.is_empty()).then(|| prefix.to_string())
+    }
+
+    //Globs can't be expressed as payload conditions, backends match them against the results instead
+    pub fn has_globs(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.is_empty()
+    }
+
+    pub fn globs(&self) -> Result<(Option<GlobSet>, Option<GlobSet>)> {
+        Ok((glob_set(&self.include)?, glob_set(&self.exclude)?))
+    }
+
+    pub fn matches(&self, file: &File, globs: &(Option<GlobSet>, Option<GlobSet>)) -> bool {
+        if let Some(prefix) = self.normalized_path_prefix() {
+            if file.path != prefix && !file.path.starts_with(&format!("{prefix}/")) {
+                return false;
+            }
+        }
+        if !self.languages.is_empty()
+            && !file
+                .language
+                .as_ref()
+                .is_some_and(|language| self.languages.contains(language))
+        {
+            return false;
+        }
+        let length = file.length as u64;
+        if self.min_size.is_some_and(|min_size| length < min_size)
+            || self.max_size.is_some_and(|max_size| length > max_size)
+        {
+            return false;
+        }
+        globs_match(&file.path, globs)
+    }
+}
+
+pub fn globs_match(path: &str, (include, exclude): &(Option<GlobSet>, Option<GlobSet>)) -> bool {
+    let included = match include {
+        Some(include) => include.is_match(path),
+        None => true,
+    };
+    included
+        && !exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(path))
+}
+
+//Every directory containing the path plus the path itself, stored so prefixes match as keywords
+pub fn path_prefixes(path: &str) -> Vec<String> {
+    path.match_indices('/')
+        .map(|(index, _)| path[..index].to_string())
+        .chain(std::iter::once(path.to_string()))
+        .collect()
+}
+
+fn glob_set(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern)
+            .map_err(|e| Error::InvalidRequest(format!("Invalid glob {pattern}: {e}")))?;
+        builder.add(glob);
+    }
+    let set = builder
+        .build()
+        .map_err(|e| Error::InvalidRequest(e.to_string()))?;
+    Ok(Some(set))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str, content: &str) -> File {
+        File::new(path.to_string(), content.to_string(), None)
+    }
+
+    fn filter(include: &[&str], exclude: &[&str]) -> SearchFilter {
+        SearchFilter {
+            include: include.iter().map(|glob| glob.to_string()).collect(),
+            exclude: exclude.iter().map(|glob| glob.to_string()).collect(),
+            ..SearchFilter::default()
+        }
+    }
+
+    #[test]
+    fn matches_include_and_exclude_globs() {
+        let search = filter(&["**/*.rs", "*.toml"], &["**/tests/**"]);
+        let globs = search.globs().unwrap();
+        assert!(search.has_globs());
+        assert!(globs_match("src/main.rs", &globs));
+        assert!(globs_match("main.rs", &globs));
+        assert!(globs_match("Cargo.toml", &globs));
+        assert!(!globs_match("README.md", &globs));
+        assert!(!globs_match("src/tests/search.rs", &globs));
+
+        let exclude_only = filter(&[], &["*.md"]);
+        let globs = exclude_only.globs().unwrap();
+        assert!(globs_match("src/main.rs", &globs));
+        assert!(!globs_match("README.md", &globs));
+
+        assert!(!SearchFilter::default().has_globs());
+        assert!(globs_match(
+            "anything",
+            &SearchFilter::default().globs().unwrap()
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_globs() {
+        assert!(matches!(
+            filter(&["src/[a"], &[]).globs(),
+            Err(Error::InvalidRequest(message)) if message.starts_with("Invalid glob src/[a")
+        ));
+    }
+
+    #[test]
+    fn matches_path_prefixes_by_directory() {
+        let filter = SearchFilter {
+            path_prefix: Some("./src/db/".into()),
+            ..SearchFilter::default()
+        };
+        assert_eq!(filter.normalized_path_prefix().as_deref(), Some("src/db"));
+        let globs = filter.globs().unwrap();
+        assert!(filter.matches(&file("src/db/qdrant.rs", ""), &globs));
+        assert!(filter.matches(&file("src/db", ""), &globs));
+        assert!(!filter.matches(&file("src/dbx/mod.rs", ""), &globs));
+        assert_eq!(
+            path_prefixes("src/db/qdrant.rs"),
+            ["src", "src/db", "src/db/qdrant.rs"]
+        );
+    }
+
+    #[test]
+    fn matches_languages_and_sizes() {
+        let filter = SearchFilter {
+            languages: vec!["rust".into()],
+            min_size: Some(2),
+            max_size: Some(4),
+            ..SearchFilter::default()
+        };
+        let globs = filter.globs().unwrap();
+        assert!(filter.matches(&file("main.rs", "abc"), &globs));
+        assert!(!filter.matches(&file("main.rs", "a"), &globs));
+        assert!(!filter.matches(&file("main.rs", "abcde"), &globs));
+        assert!(!filter.matches(&file("README.md", "abc"), &globs));
+    }
+}
@@ -0,0 +1,120 @@
+use super::{IndexVersion, RepositoryEmbeddingsDB, SearchFilter};
+use crate::embeddings::Embeddings;
+use crate::github::{File, Repository, RepositoryEmbeddings, RepositoryFilePaths};
+use crate::prelude::*;
+use async_trait::async_trait;
+use std::{collections::HashMap, sync::Mutex};
+
+//Keeps collections in a map for tests, files can be listed and read but not searched
+#[derive(Default)]
+pub struct MemoryDB {
+    pub collections: Mutex<HashMap<String, RepositoryEmbeddings>>,
+}
+
+impl MemoryDB {
+    pub fn with(collections: Vec<RepositoryEmbeddings>) -> MemoryDB {
+        MemoryDB {
+            collections: Mutex::new(
+                collections
+                    .into_iter()
+                    .map(|collection| (collection.repo_id.clone(), collection))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn collection(&self, repo_id: &str) -> Result<RepositoryEmbeddings> {
+        self.collections
+            .lock()
+            .unwrap()
+            .get(repo_id)
+            .cloned()
+            .ok_or_else(|| Error::RepositoryNotFound(repo_id.to_string()))
+    }
+}
+
+#[async_trait]
+impl RepositoryEmbeddingsDB for MemoryDB {
+    async fn insert_repo_embeddings(&self, repo: RepositoryEmbeddings) -> Result<()> {
+        self.collections
+            .lock()
+            .unwrap()
+            .insert(repo.repo_id.clone(), repo);
+        Ok(())
+    }
+
+    async fn get_relevant_files(
+        &self,
+        _repository: Repository,
+        _query_embeddings: Embeddings,
+        _limit: u64,
+        _filter: &SearchFilter,
+    ) -> Result<Vec<File>> {
+        unsupported("search")
+    }
+
+    async fn get_file_paths(&self, repository: Repository) -> Result<RepositoryFilePaths> {
+        let repo_id = repository.to_string();
+        let file_paths = self
+            .collection(&repo_id)?
+            .file_embeddings
+            .into_iter()
+            .map(|file| file.file.path)
+            .collect();
+        Ok(RepositoryFilePaths {
+            repo_id,
+            file_paths,
+        })
+    }
+
+    async fn get_file(&self, repository: Repository, path: &str) -> Result<Option<File>> {
+        Ok(self
+            .collection(&repository.to_string())?
+            .file_embeddings
+            .into_iter()
+            .map(|file| file.file)
+            .find(|file| file.path == path))
+    }
+
+    async fn get_repo_embeddings(&self, repo_id: &str) -> Result<RepositoryEmbeddings> {
+        self.collection(repo_id)
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        Ok(self.collections.lock().unwrap().keys().cloned().collect())
+    }
+
+    async fn count_vectors(&self, repo_id: &str) -> Result<u64> {
+        Ok(self
+            .collections
+            .lock()
+            .unwrap()
+            .get(repo_id)
+            .map_or(0, |collection| collection.file_embeddings.len() as u64))
+    }
+
+    async fn delete_repository(&self, repo_id: &str) -> Result<()> {
+        self.collections.lock().unwrap().remove(repo_id);
+        Ok(())
+    }
+
+    async fn list_versions(&self, _repo_id: &str) -> Result<Vec<IndexVersion>> {
+        unsupported("keep versions")
+    }
+
+    async fn rollback(&self, _repo_id: &str, _version: Option<u64>) -> Result<IndexVersion> {
+        unsupported("keep versions")
+    }
+
+    async fn discard_unfinished(&self, _repo_id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn unsupported<T>(what: &str) -> Result<T> {
+    Err(Error::VectorDB(anyhow::anyhow!("MemoryDB doesn't {what}")))
+}
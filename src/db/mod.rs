@@ -1,13 +1,28 @@
 use crate::embeddings::Embeddings;
 use crate::github::{File, Repository, RepositoryEmbeddings, RepositoryFilePaths};
 use crate::prelude::*;
+mod filter;
+#[cfg(test)]
+mod memory;
 mod qdrant;
 use async_trait::async_trait;
+use serde::Serialize;
 
+pub use filter::*;
+#[cfg(test)]
+pub use memory::*;
 pub use qdrant::*;
 
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexVersion {
+    //Unix time in milliseconds the version was built at, 0 for indexes built before versioning
+    pub version: u64,
+    pub live: bool,
+}
+
 #[async_trait]
 pub trait RepositoryEmbeddingsDB {
+    //Builds a new version of the index and only serves it once it's complete
     async fn insert_repo_embeddings(&self, repo: RepositoryEmbeddings) -> Result<()>;
 
     async fn get_relevant_files(
@@ -15,10 +30,32 @@ pub trait RepositoryEmbeddingsDB {
         repository: Repository,
         query_embeddings: Embeddings,
         limit: u64,
+        filter: &SearchFilter,
     ) -> Result<Vec<File>>;
 
-    async fn get_file_paths(
-        &self,
-        repository: Repository
-    ) -> Result<RepositoryFilePaths>;
+    async fn get_file_paths(&self, repository: Repository) -> Result<RepositoryFilePaths>;
+
+    //The stored file at exactly this path, if any
+    async fn get_file(&self, repository: Repository, path: &str) -> Result<Option<File>>;
+
+    //Every stored file together with its embeddings, used to export snapshots
+    async fn get_repo_embeddings(&self, repo_id: &str) -> Result<RepositoryEmbeddings>;
+
+    async fn list_repositories(&self) -> Result<Vec<String>>;
+
+    //Vectors served for a repository, old versions kept for rollback aren't counted
+    async fn count_vectors(&self, repo_id: &str) -> Result<u64>;
+
+    async fn delete_repository(&self, repo_id: &str) -> Result<()>;
+
+    //Newest first
+    async fn list_versions(&self, repo_id: &str) -> Result<Vec<IndexVersion>>;
+
+    //Serves the given version again, or the one before the live version when none is given
+    async fn rollback(&self, repo_id: &str, version: Option<u64>) -> Result<IndexVersion>;
+
+    //Removes versions whose build was interrupted, e.g. at shutdown
+    async fn discard_unfinished(&self, repo_id: &str) -> Result<()>;
+
+    async fn health_check(&self) -> Result<()>;
 }
@@ -1,96 +1,171 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use super::RepositoryEmbeddingsDB;
+use super::{globs_match, path_prefixes, IndexVersion, RepositoryEmbeddingsDB, SearchFilter};
 use crate::{
+    config::Config,
     embeddings::Embeddings,
-    github::{
-        fetch_file_content, File, FileEmbeddings, Repository, RepositoryEmbeddings,
-        RepositoryFilePaths,
-    },
+    github::{File, FileEmbeddings, Repository, RepositoryEmbeddings, RepositoryFilePaths},
+    hosts::Hosts,
+    metrics,
     prelude::*,
 };
-use anyhow::Ok;
 use async_trait::async_trait;
 use qdrant_client::{
     prelude::*,
-    qdrant::{vectors_config::Config, ScrollPoints, VectorParams, VectorsConfig},
+    qdrant::{
+        alias_operations::Action, value::Kind, vectors::VectorsOptions, vectors_config,
+        AliasOperations, ChangeAliases, Condition, CountPoints, CreateAlias, DeleteAlias,
+        FieldType, Filter, ListValue, PointId, Range, ScrollPoints, VectorParams, VectorsConfig,
+    },
 };
 use rayon::prelude::*;
 use uuid::Uuid;
 
+//Glob filters are matched after the search, so results are paged through this many times the limit at a time
+const GLOB_PAGE_FACTOR: u64 = 4;
+//Past this many candidates a glob search returns what it found rather than scanning the whole index
+const MAX_GLOB_CANDIDATES: u64 = 10_000;
+const SCROLL_PAGE_SIZE: u32 = 256;
+//Versions are stored as "<repo_id>__v<unix millis>" behind an alias named after the repository
+const VERSION_SEPARATOR: &str = "__v";
+
 pub struct QdrantDB {
     client: QdrantClient,
+    hosts: Hosts,
+    max_file_count: u32,
+    dimension: u64,
+    keep_versions: usize,
+    //Collections currently being built, never served or pruned
+    building: Mutex<HashSet<String>>,
 }
 
 #[async_trait]
 impl RepositoryEmbeddingsDB for QdrantDB {
+    #[tracing::instrument(
+        name = "upsert",
+        skip_all,
+        fields(repo_id = %repo.repo_id, points = repo.file_embeddings.len())
+    )]
     async fn insert_repo_embeddings(&self, repo: RepositoryEmbeddings) -> Result<()> {
-        self.client
-            .create_collection(&CreateCollection {
-                collection_name: repo.repo_id.clone(),
-                vectors_config: Some(VectorsConfig {
-                    config: Some(Config::Params(VectorParams {
-                        size: 384,
-                        distance: Distance::Cosine.into(),
-                        ..Default::default()
-                    })),
-                }),
-                ..Default::default()
-            })
-            .await?;
+        let RepositoryEmbeddings {
+            repo_id,
+            file_embeddings,
+        } = repo;
+        let version = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        let collection = version_collection(&repo_id, version);
+        self.building_set().insert(collection.clone());
 
-        let points: Vec<PointStruct> = repo
-            .file_embeddings
-            .into_par_iter()
-            .map(|file| {
-                let FileEmbeddings { path, embeddings } = file;
-                let payload: Payload = HashMap::from([("path", Value::from(path))]).into();
+        let result = match self.build_collection(&collection, file_embeddings).await {
+            Ok(()) => self.point_alias(&repo_id, &collection).await,
+            Err(e) => Err(e),
+        };
+        self.building_set().remove(&collection);
+        if let Err(e) = result {
+            //The live version was never touched, only the new one has to go
+            if let Err(cleanup) = self.client.delete_collection(&collection).await {
+                tracing::warn!(%collection, error = %cleanup, "Unable to remove failed build");
+            }
+            return Err(e);
+        }
+        tracing::info!(%repo_id, version, "Serving new index version");
 
-                PointStruct::new(Uuid::new_v4().to_string(), embeddings, payload)
-            })
-            .collect();
-        self.client
-            .upsert_points(repo.repo_id, points, None)
-            .await?;
+        if let Err(e) = self.prune_versions(&repo_id).await {
+            tracing::warn!(%repo_id, error = %e, "Unable to prune old index versions");
+        }
         Ok(())
     }
 
+    #[tracing::instrument(
+        name = "search",
+        skip_all,
+        fields(repo_id = %repository.to_string(), limit)
+    )]
     async fn get_relevant_files(
         &self,
         repository: Repository,
         query_embeddings: Embeddings,
         limit: u64,
+        filter: &SearchFilter,
     ) -> Result<Vec<File>> {
-        let search_response = self
-            .client
-            .search_points(&SearchPoints {
-                collection_name: repository.to_string(),
-                vector: query_embeddings,
-                with_payload: Some(true.into()),
-                limit,
-                ..Default::default()
-            })
-            .await?;
-        let futures: Vec<_> = search_response
-            .result
+        let globs = filter.globs()?;
+        let page_size = if filter.has_globs() {
+            limit * GLOB_PAGE_FACTOR
+        } else {
+            limit
+        };
+        let mut points: Vec<(String, HashMap<String, Value>)> = Vec::new();
+        let mut offset: u64 = 0;
+        loop {
+            let timer = metrics::VECTOR_DB_DURATION
+                .with_label_values(&["search"])
+                .start_timer();
+            let search_response = self
+                .client
+                .search_points(&SearchPoints {
+                    collection_name: repository.to_string(),
+                    vector: query_embeddings.clone(),
+                    filter: payload_filter(filter),
+                    with_payload: Some(true.into()),
+                    limit: page_size,
+                    offset: Some(offset),
+                    ..Default::default()
+                })
+                .await;
+            let search_response = match search_response {
+                Ok(response) => response,
+                Err(e) => return Err(self.read_error(&repository.to_string(), e).await),
+            };
+            timer.observe_duration();
+            let returned = search_response.result.len() as u64;
+            offset += returned;
+            //Paths are matched before any content is fetched for them
+            points.extend(search_response.result.into_iter().filter_map(|point| {
+                let path = payload_string(&point.payload, "path")?;
+                globs_match(&path, &globs).then_some((path, point.payload))
+            }));
+            if points.len() as u64 >= limit || returned < page_size {
+                break;
+            }
+            if offset >= MAX_GLOB_CANDIDATES {
+                tracing::debug!(
+                    offset,
+                    matched = points.len(),
+                    "Stopped paging through glob candidates"
+                );
+                break;
+            }
+        }
+        points.truncate(limit as usize);
+
+        let repository = &repository;
+        let futures: Vec<_> = points
             .into_iter()
-            .map(|point| {
-                let path = point.payload["path"].to_string();
-                async {
-                    let content = fetch_file_content(repository.clone(), &path)
-                        .await
-                        .unwrap_or_default();
-                    let length = content.len();
-                    File {
-                        path,
-                        content,
-                        length,
-                    }
-                }
+            .map(|(path, payload)| async move {
+                //Collections indexed before file contents were stored need a fetch
+                let content = match payload_string(&payload, "content") {
+                    Some(content) => content,
+                    None => match self.hosts.fetch_file_content(repository, &path).await {
+                        Ok(content) => content,
+                        Err(e) => {
+                            tracing::warn!(%path, error = %e, "Dropping a result whose content could not be fetched");
+                            return None;
+                        }
+                    },
+                };
+                Some(payload_file(&payload, path, content))
             })
             .collect();
-        let files: Vec<File> = futures::future::join_all(futures).await;
-        Ok(files)
+        Ok(futures::future::join_all(futures)
+            .await
+            .into_iter()
+            .flatten()
+            .collect())
     }
 
     async fn get_file_paths(&self, repository: Repository) -> Result<RepositoryFilePaths> {
@@ -100,33 +175,662 @@ impl RepositoryEmbeddingsDB for QdrantDB {
                 collection_name: repository.to_string(),
                 offset: None,
                 filter: None,
-                limit: Some(MAX_FILE_COUNT),
+                limit: Some(self.max_file_count),
                 with_payload: Some(true.into()),
                 with_vectors: None,
                 read_consistency: None,
             })
-            .await?;
+            .await;
+        let scroll_reponse = match scroll_reponse {
+            Ok(response) => response,
+            Err(e) => return Err(self.read_error(&repository.to_string(), e).await),
+        };
 
         let file_paths: Vec<String> = scroll_reponse
             .result
             .par_iter()
-            .map(|point| point.payload["path"].to_string())
+            .filter_map(|point| payload_string(&point.payload, "path"))
             .collect();
         Ok(RepositoryFilePaths {
             repo_id: repository.to_string(),
             file_paths,
         })
     }
+
+    async fn get_file(&self, repository: Repository, path: &str) -> Result<Option<File>> {
+        let scroll_response = self
+            .client
+            .scroll(&ScrollPoints {
+                collection_name: repository.to_string(),
+                offset: None,
+                filter: Some(Filter::must([Condition::matches("path", path.to_string())])),
+                limit: Some(1),
+                with_payload: Some(true.into()),
+                with_vectors: None,
+                read_consistency: None,
+            })
+            .await;
+        let scroll_response = match scroll_response {
+            Ok(response) => response,
+            Err(e) => return Err(self.read_error(&repository.to_string(), e).await),
+        };
+        let point = match scroll_response.result.into_iter().next() {
+            Some(point) => point,
+            None => return Ok(None),
+        };
+        let content = match payload_string(&point.payload, "content") {
+            Some(content) => content,
+            None => self.hosts.fetch_file_content(&repository, path).await?,
+        };
+        Ok(Some(payload_file(
+            &point.payload,
+            path.to_string(),
+            content,
+        )))
+    }
+
+    async fn get_repo_embeddings(&self, repo_id: &str) -> Result<RepositoryEmbeddings> {
+        let mut file_embeddings: Vec<FileEmbeddings> = Vec::new();
+        let mut offset: Option<PointId> = None;
+        loop {
+            let scroll_response = self
+                .client
+                .scroll(&ScrollPoints {
+                    collection_name: repo_id.to_string(),
+                    offset,
+                    filter: None,
+                    limit: Some(SCROLL_PAGE_SIZE),
+                    with_payload: Some(true.into()),
+                    with_vectors: Some(true.into()),
+                    read_consistency: None,
+                })
+                .await;
+            let scroll_response = match scroll_response {
+                Ok(response) => response,
+                Err(e) => return Err(self.read_error(repo_id, e).await),
+            };
+
+            for point in scroll_response.result {
+                let path = payload_string(&point.payload, "path").unwrap_or_default();
+                let content = payload_string(&point.payload, "content").ok_or_else(|| {
+                    Error::InvalidRequest(format!(
+                        "{repo_id} was indexed before file contents were stored, index it again first"
+                    ))
+                })?;
+                let embeddings = match point.vectors.and_then(|vectors| vectors.vectors_options) {
+                    Some(VectorsOptions::Vector(vector)) => vector.data,
+                    _ => {
+                        return Err(Error::VectorDB(anyhow::anyhow!(
+                            "{path} in {repo_id} has no vector"
+                        )))
+                    }
+                };
+                file_embeddings.push(FileEmbeddings {
+                    file: payload_file(&point.payload, path, content),
+                    embeddings,
+                });
+            }
+            offset = scroll_response.next_page_offset;
+            if offset.is_none() {
+                break;
+            }
+        }
+        Ok(RepositoryEmbeddings {
+            repo_id: repo_id.to_string(),
+            file_embeddings,
+        })
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<String>> {
+        let mut repositories: Vec<String> = self.aliases().await?.into_keys().collect();
+        //Indexes built before versioning are plain collections
+        repositories.extend(
+            self.collection_names()
+                .await?
+                .into_iter()
+                .filter(|name| parse_version(name).is_none()),
+        );
+        repositories.sort();
+        Ok(repositories)
+    }
+
+    async fn count_vectors(&self, repo_id: &str) -> Result<u64> {
+        let response = self
+            .client
+            .count(&CountPoints {
+                collection_name: repo_id.to_string(),
+                exact: Some(true),
+                ..Default::default()
+            })
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => return Err(self.read_error(repo_id, e).await),
+        };
+        Ok(response.result.map_or(0, |result| result.count))
+    }
+
+    async fn delete_repository(&self, repo_id: &str) -> Result<()> {
+        let mut found = false;
+        if self.aliases().await?.contains_key(repo_id) {
+            self.client
+                .delete_alias(repo_id)
+                .await
+                .map_err(Error::VectorDB)?;
+            found = true;
+        }
+        for name in self.collection_names().await? {
+            let versioned = parse_version(&name).is_some_and(|(id, _)| id == repo_id);
+            if versioned || name == repo_id {
+                self.client
+                    .delete_collection(&name)
+                    .await
+                    .map_err(Error::VectorDB)?;
+                found = true;
+            }
+        }
+        if !found {
+            return Err(Error::RepositoryNotFound(repo_id.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn list_versions(&self, repo_id: &str) -> Result<Vec<IndexVersion>> {
+        let live = self.aliases().await?.remove(repo_id);
+        let names = self.collection_names().await?;
+        let versions = index_versions(repo_id, &names, live.as_deref());
+        if versions.is_empty() {
+            return Err(Error::RepositoryNotFound(repo_id.to_string()));
+        }
+        Ok(versions)
+    }
+
+    async fn rollback(&self, repo_id: &str, version: Option<u64>) -> Result<IndexVersion> {
+        let versions = self.list_versions(repo_id).await?;
+        let target = rollback_target(repo_id, &versions, version)?;
+        let collection = version_collection(repo_id, target);
+        if self.building_set().contains(&collection) {
+            return Err(Error::InvalidRequest(format!(
+                "Version {target} of {repo_id} is still being built"
+            )));
+        }
+        self.point_alias(repo_id, &collection).await?;
+        tracing::info!(%repo_id, version = target, "Rolled back index");
+        Ok(IndexVersion {
+            version: target,
+            live: true,
+        })
+    }
+
+    async fn discard_unfinished(&self, repo_id: &str) -> Result<()> {
+        let unfinished: Vec<String> = self
+            .building_set()
+            .iter()
+            .filter(|name| parse_version(name).is_some_and(|(id, _)| id == repo_id))
+            .cloned()
+            .collect();
+        for collection in unfinished {
+            self.client
+                .delete_collection(&collection)
+                .await
+                .map_err(Error::VectorDB)?;
+            self.building_set().remove(&collection);
+        }
+        Ok(())
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.client.health_check().await.map_err(Error::VectorDB)?;
+        Ok(())
+    }
 }
 impl QdrantDB {
-    pub fn initialize() -> Result<QdrantDB> {
-        let mut config = QdrantClientConfig::from_url(
-            &std::env::var("QDRANT_URL").expect("QDRANT_URL environment variable not set"),
+    pub fn initialize(config: &Config) -> Result<QdrantDB> {
+        let mut client_config = QdrantClientConfig::from_url(&config.vector_store.url);
+        if let Some(api_key) = &config.vector_store.api_key {
+            client_config.set_api_key(api_key);
+        }
+        let client = QdrantClient::new(Some(client_config)).map_err(Error::VectorDB)?;
+        Ok(QdrantDB {
+            client,
+            hosts: Hosts::new(config)?,
+            max_file_count: config.fetch.max_file_count,
+            dimension: config.model.dimension,
+            keep_versions: config.vector_store.keep_versions,
+            building: Mutex::new(HashSet::new()),
+        })
+    }
+
+    async fn build_collection(
+        &self,
+        collection: &str,
+        file_embeddings: Vec<FileEmbeddings>,
+    ) -> Result<()> {
+        self.client
+            .create_collection(&CreateCollection {
+                collection_name: collection.to_string(),
+                vectors_config: Some(VectorsConfig {
+                    config: Some(vectors_config::Config::Params(VectorParams {
+                        size: self.dimension,
+                        distance: Distance::Cosine.into(),
+                        ..Default::default()
+                    })),
+                }),
+                ..Default::default()
+            })
+            .await
+            .map_err(Error::VectorDB)?;
+
+        for (field, field_type) in [
+            ("path_prefixes", FieldType::Keyword),
+            ("language", FieldType::Keyword),
+            ("length", FieldType::Integer),
+        ] {
+            self.client
+                .create_field_index(collection, field, field_type, None, None)
+                .await
+                .map_err(Error::VectorDB)?;
+        }
+
+        let points = points(file_embeddings);
+        let _timer = metrics::VECTOR_DB_DURATION
+            .with_label_values(&["upsert"])
+            .start_timer();
+        self.client
+            .upsert_points(collection, points, None)
+            .await
+            .map_err(Error::VectorDB)?;
+        Ok(())
+    }
+    //Repoints the alias in a single request so searches never see a missing or partial index
+    async fn point_alias(&self, repo_id: &str, collection: &str) -> Result<()> {
+        let mut actions: Vec<AliasOperations> = Vec::new();
+        if self.aliases().await?.contains_key(repo_id) {
+            actions.push(AliasOperations {
+                action: Some(Action::DeleteAlias(DeleteAlias {
+                    alias_name: repo_id.to_string(),
+                })),
+            });
+        } else if self
+            .collection_names()
+            .await?
+            .iter()
+            .any(|name| name == repo_id)
+        {
+            //An index from before versioning holds the name, it's replaced by the first new version
+            tracing::warn!(%repo_id, "Replacing unversioned collection with an alias");
+            self.client
+                .delete_collection(repo_id)
+                .await
+                .map_err(Error::VectorDB)?;
+        }
+        actions.push(AliasOperations {
+            action: Some(Action::CreateAlias(CreateAlias {
+                collection_name: collection.to_string(),
+                alias_name: repo_id.to_string(),
+            })),
+        });
+        self.client
+            .with_collections_client(|mut client| {
+                let request = ChangeAliases {
+                    actions: actions.clone(),
+                    timeout: None,
+                };
+                async move { client.update_aliases(request).await }
+            })
+            .await
+            .map_err(|e| Error::VectorDB(e.into()))?;
+        Ok(())
+    }
+
+    async fn prune_versions(&self, repo_id: &str) -> Result<()> {
+        let live = self.aliases().await?.remove(repo_id);
+        let names = self.collection_names().await?;
+        let stale = stale_versions(
+            repo_id,
+            &names,
+            live.as_deref(),
+            &self.building_set(),
+            self.keep_versions,
+        );
+        for collection in stale {
+            self.client
+                .delete_collection(&collection)
+                .await
+                .map_err(Error::VectorDB)?;
+        }
+        Ok(())
+    }
+
+    async fn collection_names(&self) -> Result<Vec<String>> {
+        let response = self
+            .client
+            .list_collections()
+            .await
+            .map_err(Error::VectorDB)?;
+        Ok(response
+            .collections
+            .into_iter()
+            .map(|collection| collection.name)
+            .collect())
+    }
+
+    //Alias name -> collection name
+    async fn aliases(&self) -> Result<HashMap<String, String>> {
+        let response = self.client.list_aliases().await.map_err(Error::VectorDB)?;
+        Ok(response
+            .aliases
+            .into_iter()
+            .map(|alias| (alias.alias_name, alias.collection_name))
+            .collect())
+    }
+
+    //Qdrant reports a missing collection like any other failure, it's told apart by looking it up
+    async fn read_error(&self, repo_id: &str, error: anyhow::Error) -> Error {
+        match self.exists(repo_id).await {
+            Ok(false) => Error::RepositoryNotFound(repo_id.to_string()),
+            _ => Error::VectorDB(error),
+        }
+    }
+
+    async fn exists(&self, repo_id: &str) -> Result<bool> {
+        Ok(self.aliases().await?.contains_key(repo_id)
+            || self
+                .collection_names()
+                .await?
+                .iter()
+                .any(|name| name == repo_id))
+    }
+
+    fn building_set(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.building.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn points(file_embeddings: Vec<FileEmbeddings>) -> Vec<PointStruct> {
+    file_embeddings
+        .into_par_iter()
+        .map(|file| {
+            let FileEmbeddings { file, embeddings } = file;
+            let prefixes = path_prefixes(&file.path).into_iter().map(Value::from);
+            let mut payload = HashMap::from([
+                (
+                    "path_prefixes",
+                    Value {
+                        kind: Some(Kind::ListValue(ListValue {
+                            values: prefixes.collect(),
+                        })),
+                    },
+                ),
+                ("path", Value::from(file.path)),
+                ("content", Value::from(file.content)),
+                ("length", Value::from(file.length as i64)),
+                ("line_count", Value::from(file.line_count as i64)),
+                ("content_hash", Value::from(file.content_hash)),
+                ("encoding", Value::from(file.encoding)),
+                ("lossy", Value::from(file.lossy)),
+            ]);
+            if let Some(language) = file.language {
+                payload.insert("language", Value::from(language));
+            }
+            if let Some(last_commit) = file.last_commit {
+                payload.insert("last_commit", Value::from(last_commit));
+            }
+            if let Some(indexed_commit) = file.indexed_commit {
+                payload.insert("indexed_commit", Value::from(indexed_commit));
+            }
+            let payload: Payload = payload.into();
+
+            PointStruct::new(Uuid::new_v4().to_string(), embeddings, payload)
+        })
+        .collect()
+}
+
+//Newest first
+fn collection_versions(repo_id: &str, names: &[String]) -> Vec<u64> {
+    let mut versions: Vec<u64> = names
+        .iter()
+        .filter_map(|name| parse_version(name))
+        .filter(|(id, _)| *id == repo_id)
+        .map(|(_, version)| version)
+        .collect();
+    versions.sort_unstable_by(|a, b| b.cmp(a));
+    versions
+}
+
+//An unversioned collection only counts while no alias has replaced it
+fn index_versions(repo_id: &str, names: &[String], live: Option<&str>) -> Vec<IndexVersion> {
+    let mut versions: Vec<IndexVersion> = collection_versions(repo_id, names)
+        .into_iter()
+        .map(|version| IndexVersion {
+            version,
+            live: live == Some(&version_collection(repo_id, version)),
+        })
+        .collect();
+    if live.is_none() && names.iter().any(|name| name == repo_id) {
+        versions.push(IndexVersion {
+            version: 0,
+            live: true,
+        });
+    }
+    versions
+}
+
+//The requested version, or the newest one older than the live version
+fn rollback_target(
+    repo_id: &str,
+    versions: &[IndexVersion],
+    requested: Option<u64>,
+) -> Result<u64> {
+    let target = match requested {
+        Some(version) => versions
+            .iter()
+            .find(|candidate| candidate.version == version),
+        None => {
+            let live_version = versions
+                .iter()
+                .find(|candidate| candidate.live)
+                .map_or(u64::MAX, |live| live.version);
+            versions
+                .iter()
+                .find(|candidate| candidate.version > 0 && candidate.version < live_version)
+        }
+    };
+    //Version 0 is an unversioned collection, it has no name to point the alias at
+    match target {
+        Some(target) if target.version > 0 => Ok(target.version),
+        _ => Err(Error::InvalidRequest(match requested {
+            Some(version) => format!("{repo_id} has no version {version}"),
+            None => format!("{repo_id} has no previous version"),
+        })),
+    }
+}
+
+//Keeps the newest `keep` versions besides the live one, collections still being built are left alone
+fn stale_versions(
+    repo_id: &str,
+    names: &[String],
+    live: Option<&str>,
+    building: &HashSet<String>,
+    keep: usize,
+) -> Vec<String> {
+    collection_versions(repo_id, names)
+        .into_iter()
+        .map(|version| version_collection(repo_id, version))
+        .filter(|name| Some(name.as_str()) != live && !building.contains(name))
+        .skip(keep)
+        .collect()
+}
+
+fn version_collection(repo_id: &str, version: u64) -> String {
+    format!("{repo_id}{VERSION_SEPARATOR}{version}")
+}
+
+//Splits "<repo_id>__v<version>" into its parts
+fn parse_version(name: &str) -> Option<(&str, u64)> {
+    let (repo_id, version) = name.rsplit_once(VERSION_SEPARATOR)?;
+    Some((repo_id, version.parse().ok()?))
+}
+
+fn payload_string(payload: &HashMap<String, Value>, key: &str) -> Option<String> {
+    match &payload.get(key)?.kind {
+        Some(Kind::StringValue(value)) => Some(value.clone()),
+        _ => None,
+    }
+}
+
+fn payload_file(payload: &HashMap<String, Value>, path: String, content: String) -> File {
+    let file = File::new(path, content, payload_string(payload, "last_commit"));
+    File {
+        encoding: payload_string(payload, "encoding").unwrap_or(file.encoding),
+        lossy: payload_bool(payload, "lossy").unwrap_or(file.lossy),
+        indexed_commit: payload_string(payload, "indexed_commit"),
+        ..file
+    }
+}
+
+fn payload_bool(payload: &HashMap<String, Value>, key: &str) -> Option<bool> {
+    match &payload.get(key)?.kind {
+        Some(Kind::BoolValue(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+fn payload_filter(filter: &SearchFilter) -> Option<Filter> {
+    let mut conditions: Vec<Condition> = Vec::new();
+    if let Some(prefix) = filter.normalized_path_prefix() {
+        conditions.push(Condition::matches("path_prefixes", prefix));
+    }
+    if !filter.languages.is_empty() {
+        conditions.push(Condition::matches("language", filter.languages.clone()));
+    }
+    if filter.min_size.is_some() || filter.max_size.is_some() {
+        conditions.push(Condition::range(
+            "length",
+            Range {
+                gte: filter.min_size.map(|size| size as f64),
+                lte: filter.max_size.map(|size| size as f64),
+                ..Default::default()
+            },
+        ));
+    }
+    (!conditions.is_empty()).then(|| Filter::must(conditions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPO: &str = "owner/repo-main";
+
+    fn names(versions: &[u64]) -> Vec<String> {
+        let mut names: Vec<String> = versions
+            .iter()
+            .map(|version| version_collection(REPO, *version))
+            .collect();
+        //Versions of another repository and unrelated collections are never picked up
+        names.push(version_collection("owner/other-main", 9));
+        names.push("owner/other-main".to_string());
+        names
+    }
+
+    fn listed(versions: &[IndexVersion]) -> Vec<(u64, bool)> {
+        versions
+            .iter()
+            .map(|version| (version.version, version.live))
+            .collect()
+    }
+
+    #[test]
+    fn versions_are_listed_newest_first() {
+        let names = names(&[100, 300, 200]);
+        assert_eq!(collection_versions(REPO, &names), vec![300, 200, 100]);
+
+        let live = version_collection(REPO, 200);
+        assert_eq!(
+            listed(&index_versions(REPO, &names, Some(&live))),
+            vec![(300, false), (200, true), (100, false)]
+        );
+    }
+
+    #[test]
+    fn unversioned_collection_is_listed_until_aliased() {
+        let mut names = names(&[100]);
+        names.push(REPO.to_string());
+        assert_eq!(
+            listed(&index_versions(REPO, &names, None)),
+            vec![(100, false), (0, true)]
+        );
+
+        let live = version_collection(REPO, 100);
+        assert_eq!(
+            listed(&index_versions(REPO, &names, Some(&live))),
+            vec![(100, true)]
+        );
+        //Nothing of this repository left
+        assert!(index_versions(REPO, &names[1..3], None).is_empty());
+    }
+
+    #[test]
+    fn rollback_picks_the_version_before_the_live_one() {
+        let names = names(&[100, 200, 300]);
+        let live = version_collection(REPO, 200);
+        let versions = index_versions(REPO, &names, Some(&live));
+
+        assert_eq!(rollback_target(REPO, &versions, None).unwrap(), 100);
+        assert_eq!(rollback_target(REPO, &versions, Some(300)).unwrap(), 300);
+    }
+
+    #[test]
+    fn rollback_without_an_earlier_version_fails() {
+        let names = names(&[100, 200]);
+        let live = version_collection(REPO, 100);
+        let versions = index_versions(REPO, &names, Some(&live));
+        assert!(matches!(
+            rollback_target(REPO, &versions, None),
+            Err(Error::InvalidRequest(message)) if message.contains("no previous version")
+        ));
+
+        //The unversioned collection can't be rolled back to
+        let mut names = names;
+        names.push(REPO.to_string());
+        let versions = index_versions(REPO, &names, None);
+        assert!(matches!(
+            rollback_target(REPO, &versions, Some(0)),
+            Err(Error::InvalidRequest(message)) if message.contains("no version 0")
+        ));
+    }
+
+    #[test]
+    fn rollback_to_a_pruned_version_fails() {
+        let live = version_collection(REPO, 400);
+        let building = HashSet::new();
+        let mut names = names(&[100, 200, 300, 400]);
+        let stale = stale_versions(REPO, &names, Some(&live), &building, 1);
+        names.retain(|name| !stale.contains(name));
+
+        let versions = index_versions(REPO, &names, Some(&live));
+        assert!(matches!(
+            rollback_target(REPO, &versions, Some(100)),
+            Err(Error::InvalidRequest(message)) if message.contains("no version 100")
+        ));
+        assert_eq!(rollback_target(REPO, &versions, None).unwrap(), 300);
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_versions_besides_live_and_building() {
+        let names = names(&[100, 200, 300, 400, 500]);
+        let live = version_collection(REPO, 200);
+        let building = HashSet::from([version_collection(REPO, 500)]);
+
+        assert_eq!(
+            stale_versions(REPO, &names, Some(&live), &building, 1),
+            vec![version_collection(REPO, 300), version_collection(REPO, 100)]
         );
-        config.set_api_key(
-            &std::env::var("QDRANT_API_KEY").expect("QDRANT_API_KEY environment variable not set"),
+        assert!(stale_versions(REPO, &names, Some(&live), &building, 3).is_empty());
+        assert_eq!(
+            stale_versions(REPO, &names, None, &HashSet::new(), 0).len(),
+            5
         );
-        let client = QdrantClient::new(Some(config))?;
-        Ok(QdrantDB { client })
     }
 }
@@ -0,0 +1,350 @@
+use super::{Embeddings, EmbeddingsModel, Onnx, CHUNKING};
+use crate::{
+    config::{CacheConfig, Config},
+    metrics,
+    prelude::*,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+//Values are an 8 byte recency tick followed by the vector as little-endian f32s
+const TICK_BYTES: usize = 8;
+
+pub struct EmbeddingCache {
+    db: sled::Db,
+    //Cache key -> tick + vector
+    vectors: sled::Tree,
+    //Tick -> cache key, oldest first, used for least recently used eviction
+    recency: sled::Tree,
+    max_bytes: u64,
+    bytes: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    eviction: Mutex<()>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+    pub entries: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+impl EmbeddingCache {
+    pub fn open(config: &CacheConfig) -> Result<EmbeddingCache> {
+        let db = sled::open(&config.path).map_err(cache_error)?;
+        let vectors = db.open_tree("vectors").map_err(cache_error)?;
+        let recency = db.open_tree("recency").map_err(cache_error)?;
+        let bytes = vectors
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum();
+        Ok(EmbeddingCache {
+            db,
+            vectors,
+            recency,
+            max_bytes: config.max_bytes,
+            bytes: AtomicU64::new(bytes),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            eviction: Mutex::new(()),
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Embeddings> {
+        match self.vectors.get(key) {
+            Ok(Some(value)) if value.len() > TICK_BYTES => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                metrics::CACHE_LOOKUPS.with_label_values(&["hit"]).inc();
+                if let Err(e) = self.touch(key, &value) {
+                    tracing::warn!(error = %e, "Unable to update embedding cache");
+                }
+                Some(
+                    value[TICK_BYTES..]
+                        .chunks_exact(4)
+                        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                        .collect(),
+                )
+            }
+            Ok(_) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                metrics::CACHE_LOOKUPS.with_label_values(&["miss"]).inc();
+                None
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Unable to read embedding cache");
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                metrics::CACHE_LOOKUPS.with_label_values(&["miss"]).inc();
+                None
+            }
+        }
+    }
+
+    //Failing to cache shouldn't fail the embedding, so errors are only logged
+    pub fn insert(&self, key: &[u8], embeddings: &Embeddings) {
+        if let Err(e) = self.try_insert(key, embeddings) {
+            tracing::warn!(error = %e, "Unable to write embedding cache");
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        CacheStats {
+            hits,
+            misses,
+            hit_rate: if hits + misses == 0 {
+                0.0
+            } else {
+                hits as f64 / (hits + misses) as f64
+            },
+            entries: self.vectors.len(),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            max_bytes: self.max_bytes,
+        }
+    }
+
+    fn try_insert(&self, key: &[u8], embeddings: &Embeddings) -> sled::Result<()> {
+        let tick = self.db.generate_id()?.to_be_bytes();
+        let mut value = Vec::with_capacity(TICK_BYTES + embeddings.len() * 4);
+        value.extend_from_slice(&tick);
+        for x in embeddings {
+            value.extend_from_slice(&x.to_le_bytes());
+        }
+        let size = (key.len() + value.len()) as u64;
+        let previous = self.vectors.insert(key, value)?;
+        //Counted before the entry can be evicted, so eviction never subtracts bytes not yet added
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        if let Some(previous) = previous {
+            self.bytes
+                .fetch_sub((key.len() + previous.len()) as u64, Ordering::Relaxed);
+            self.recency.remove(&previous[..TICK_BYTES])?;
+        }
+        self.recency.insert(tick, key)?;
+        if self.bytes.load(Ordering::Relaxed) > self.max_bytes {
+            self.evict()?;
+        }
+        Ok(())
+    }
+
+    //Only swaps in the new tick if the entry is unchanged, an entry evicted or replaced meanwhile stays so
+    fn touch(&self, key: &[u8], value: &[u8]) -> sled::Result<()> {
+        let tick = self.db.generate_id()?.to_be_bytes();
+        let mut touched = value.to_vec();
+        touched[..TICK_BYTES].copy_from_slice(&tick);
+        if self
+            .vectors
+            .compare_and_swap(key, Some(value), Some(touched))?
+            .is_err()
+        {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        batch.remove(&value[..TICK_BYTES]);
+        batch.insert(&tick, key);
+        self.recency.apply_batch(batch)
+    }
+
+    fn evict(&self) -> sled::Result<()> {
+        let _guard = self.eviction.lock().unwrap_or_else(|e| e.into_inner());
+        while self.bytes.load(Ordering::Relaxed) > self.max_bytes {
+            let (tick, key) = match self.recency.pop_min()? {
+                Some(entry) => entry,
+                None => break,
+            };
+            //Entries touched since this tick was recorded have a newer one and stay
+            if let Some(value) = self.vectors.get(&key)? {
+                if value[..TICK_BYTES] == tick[..]
+                    && self
+                        .vectors
+                        .compare_and_swap(&key, Some(&value), None::<&[u8]>)?
+                        .is_ok()
+                {
+                    self.bytes
+                        .fetch_sub((key.len() + value.len()) as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+//Wraps a model so identical inputs (e.g. files shared by forks and branches) are embedded once
+pub struct CachedModel<M> {
+    model: M,
+    cache: Option<EmbeddingCache>,
+    namespace: String,
+}
+
+impl<M: EmbeddingsModel> CachedModel<M> {
+    pub fn new(model: M, config: &Config) -> CachedModel<M> {
+        let cache = config
+            .cache
+            .enabled
+            .then(|| EmbeddingCache::open(&config.cache))
+            .and_then(|cache| match cache {
+                Ok(cache) => Some(cache),
+                //sled holds an exclusive lock, so the CLI can't share the server's cache
+                Err(e) => {
+                    tracing::warn!(error = %e, "Embedding cache disabled");
+                    None
+                }
+            });
+        CachedModel {
+            model,
+            cache,
+            namespace: format!("{}/{}/", config.model.id, CHUNKING),
+        }
+    }
+
+    //The wrapped model, for probes that must not be answered from the cache
+    pub fn uncached(&self) -> &M {
+        &self.model
+    }
+
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(EmbeddingCache::stats)
+    }
+
+    fn key(&self, text: &str) -> Vec<u8> {
+        let mut key = self.namespace.clone().into_bytes();
+        key.extend_from_slice(&Sha256::digest(text.as_bytes()));
+        key
+    }
+}
+
+impl CachedModel<Onnx> {
+    pub fn load(config: &Config) -> Result<CachedModel<Onnx>> {
+        config.model.check_files()?;
+        Ok(CachedModel::new(Onnx::new(&config.model)?, config))
+    }
+}
+
+impl<M: EmbeddingsModel> EmbeddingsModel for CachedModel<M> {
+    fn embed(&self, text: &str) -> Result<Embeddings> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.model.embed(text),
+        };
+        let key = self.key(text);
+        if let Some(embeddings) = cache.get(&key) {
+            return Ok(embeddings);
+        }
+        let embeddings = self.model.embed(text)?;
+        cache.insert(&key, &embeddings);
+        Ok(embeddings)
+    }
+
+    fn pool(&self) -> Option<&rayon::ThreadPool> {
+        self.model.pool()
+    }
+}
+
+fn cache_error(error: sled::Error) -> Error {
+    Error::Config(format!("Unable to open the embedding cache: {error}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Each entry is a 1 byte key, the tick and one f32
+    const ENTRY_BYTES: u64 = 1 + TICK_BYTES as u64 + 4;
+
+    fn cache(dir: &tempfile::TempDir, entries: u64) -> EmbeddingCache {
+        EmbeddingCache::open(&CacheConfig {
+            enabled: true,
+            path: dir.path().join("cache"),
+            max_bytes: entries * ENTRY_BYTES,
+        })
+        .unwrap()
+    }
+
+    fn stored_bytes(cache: &EmbeddingCache) -> u64 {
+        cache
+            .vectors
+            .iter()
+            .map(|entry| entry.unwrap())
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum()
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir, 3);
+        cache.insert(b"a", &vec![1.0]);
+        cache.insert(b"b", &vec![2.0]);
+        cache.insert(b"c", &vec![3.0]);
+        assert_eq!(cache.get(b"a"), Some(vec![1.0]));
+
+        cache.insert(b"d", &vec![4.0]);
+        cache.insert(b"e", &vec![5.0]);
+        assert_eq!(cache.get(b"b"), None);
+        assert_eq!(cache.get(b"c"), None);
+        assert_eq!(cache.get(b"a"), Some(vec![1.0]));
+        assert_eq!(cache.get(b"d"), Some(vec![4.0]));
+        assert_eq!(cache.get(b"e"), Some(vec![5.0]));
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.bytes, 3 * ENTRY_BYTES);
+        assert_eq!(stats.bytes, stored_bytes(&cache));
+        assert_eq!(cache.recency.len(), 3);
+    }
+
+    #[test]
+    fn replacing_an_entry_counts_its_bytes_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir, 3);
+        cache.insert(b"a", &vec![1.0]);
+        cache.insert(b"a", &vec![2.0]);
+        assert_eq!(cache.get(b"a"), Some(vec![2.0]));
+        assert_eq!(cache.stats().bytes, ENTRY_BYTES);
+        assert_eq!(cache.recency.len(), 1);
+    }
+
+    #[test]
+    fn touching_an_evicted_entry_leaves_it_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir, 1);
+        cache.insert(b"a", &vec![1.0]);
+        let stale = cache.vectors.get(b"a").unwrap().unwrap();
+        cache.insert(b"b", &vec![2.0]);
+
+        //A hit on "a" read before the eviction finishes after it
+        cache.touch(b"a", &stale).unwrap();
+        assert_eq!(cache.vectors.get(b"a").unwrap(), None);
+        assert_eq!(cache.stats().bytes, stored_bytes(&cache));
+        assert_eq!(cache.stats().bytes, ENTRY_BYTES);
+    }
+
+    #[test]
+    fn keeps_the_byte_total_under_concurrent_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir, 8);
+        std::thread::scope(|scope| {
+            for thread in 0..4u8 {
+                let cache = &cache;
+                scope.spawn(move || {
+                    for i in 0..200u8 {
+                        let key = [i % 16];
+                        cache.insert(&key, &vec![f32::from(thread)]);
+                        cache.get(&[i.wrapping_mul(7) % 16]);
+                    }
+                });
+            }
+        });
+        let stats = cache.stats();
+        assert_eq!(stats.bytes, stored_bytes(&cache));
+        assert!(stats.bytes <= 8 * ENTRY_BYTES);
+    }
+}
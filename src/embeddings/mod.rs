@@ -1,9 +1,20 @@
+mod cache;
 mod onnx;
 use crate::prelude::*;
 
+pub use cache::*;
 pub use onnx::*;
 pub type Embeddings = Vec<f32>;
+pub type Model = CachedModel<Onnx>;
+
+//Files are embedded whole, there is no chunking to configure yet
+pub const CHUNKING: &str = "whole-file";
 
 pub trait EmbeddingsModel {
     fn embed(&self, string: &str) -> Result<Embeddings>;
+
+    //Batches run on rayon's global pool unless the model brings its own
+    fn pool(&self) -> Option<&rayon::ThreadPool> {
+        None
+    }
 }
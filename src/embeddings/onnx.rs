@@ -1,10 +1,11 @@
-use crate::prelude::*;
+use crate::{config::ModelConfig, metrics, prelude::*};
 use ndarray::Axis;
 use ort::{
     tensor::{FromArray, InputTensor},
     Environment, ExecutionProvider, GraphOptimizationLevel, SessionBuilder,
 };
-use std::{path::Path, sync::Arc, thread::available_parallelism};
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::Arc;
 
 use super::{Embeddings, EmbeddingsModel};
 
@@ -12,10 +13,11 @@ use super::{Embeddings, EmbeddingsModel};
 pub struct Onnx {
     tokenizer: Arc<tokenizers::Tokenizer>,
     session: Arc<ort::Session>,
+    pool: Arc<ThreadPool>,
 }
 
 impl Onnx {
-    pub fn new(model_dir: &Path) -> Result<Self> {
+    pub fn new(config: &ModelConfig) -> Result<Self> {
         let environment = Arc::new(
             Environment::builder()
                 .with_name("Embeddings")
@@ -23,26 +25,37 @@ impl Onnx {
                 .build()?,
         );
 
-        let threads = available_parallelism().unwrap().get() as i16;
+        //Kept off rayon's global pool so embedding can't starve other parallel work
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(config.threads)
+            .thread_name(|index| format!("embed-{index}"))
+            .build()
+            .map_err(|e| Error::Model(e.to_string()))?;
 
         Ok(Self {
-            tokenizer: tokenizers::Tokenizer::from_file(model_dir.join("tokenizer.json"))
-                .unwrap()
+            tokenizer: tokenizers::Tokenizer::from_file(config.path.join("tokenizer.json"))
+                .map_err(|e| Error::Model(e.to_string()))?
                 .into(),
             session: SessionBuilder::new(&environment)?
                 .with_optimization_level(GraphOptimizationLevel::Level3)?
-                .with_intra_threads(threads)?
-                .with_model_from_file(model_dir.join("model_quantized.onnx"))?
+                .with_intra_threads(config.intra_threads.min(i16::MAX as usize) as i16)?
+                .with_model_from_file(config.path.join("model_quantized.onnx"))?
                 .into(),
+            pool: pool.into(),
         })
     }
 }
 
 impl EmbeddingsModel for Onnx {
     fn embed(&self, sequence: &str) -> Result<Embeddings> {
-        let tokenizer_output = self.tokenizer.encode(sequence, true).unwrap();
+        let tokenizer_output = self
+            .tokenizer
+            .encode(sequence, true)
+            .map_err(|e| Error::Model(e.to_string()))?;
 
         let input_ids = tokenizer_output.get_ids();
+        metrics::EMBEDDING_TOKENS.inc_by(input_ids.len() as u64);
+        metrics::CHUNKS_EMBEDDED.inc();
         let attention_mask = tokenizer_output.get_attention_mask();
         let token_type_ids = tokenizer_output.get_type_ids();
         let length = input_ids.len();
@@ -68,9 +81,15 @@ impl EmbeddingsModel for Onnx {
             InputTensor::from_array(token_type_ids_array.into_dyn()),
         ])?;
 
-        let output_tensor = outputs[0].try_extract().unwrap();
+        let output_tensor = outputs[0].try_extract::<f32>()?;
         let sequence_embedding = &*output_tensor.view();
-        let pooled = sequence_embedding.mean_axis(Axis(1)).unwrap();
-        Ok(pooled.to_owned().as_slice().unwrap().to_vec())
+        let pooled = sequence_embedding
+            .mean_axis(Axis(1))
+            .ok_or_else(|| Error::Model("Model returned an empty sequence output".into()))?;
+        Ok(pooled.iter().copied().collect())
+    }
+
+    fn pool(&self) -> Option<&ThreadPool> {
+        Some(&self.pool)
     }
 }
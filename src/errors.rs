@@ -0,0 +1,258 @@
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
+use serde::Serialize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Repository {0} was not found")]
+    RepositoryNotFound(String),
+    #[error("Branch {branch} was not found in repository {repository}")]
+    BranchNotFound { repository: String, branch: String },
+    #[error("{0} rejected the provided credentials")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Unauthenticated(String),
+    #[error("Access denied by {0}")]
+    Forbidden(String),
+    #[error("Rate limited by {host}")]
+    RateLimited {
+        host: String,
+        retry_after: Option<u64>,
+    },
+    #[error("{message}")]
+    TooManyRequests { message: String, retry_after: u64 },
+    #[error("{0}")]
+    LimitExceeded(String),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Unable to read repository archive: {0}")]
+    Archive(String),
+    #[error("Invalid snapshot: {0}")]
+    Snapshot(String),
+    #[error("Embeddings model error: {0}")]
+    Model(String),
+    #[error("Vector database error: {0}")]
+    VectorDB(anyhow::Error),
+    #[error("LLM request failed: {0}")]
+    Llm(String),
+    #[error("The server is shutting down")]
+    ShuttingDown,
+    #[error("Configuration error: {0}")]
+    Config(String),
+    #[error("Upstream request failed: {0}")]
+    Upstream(#[from] reqwest::Error),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::RepositoryNotFound(_) => "repository_not_found",
+            Error::BranchNotFound { .. } => "branch_not_found",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Unauthenticated(_) => "unauthenticated",
+            Error::Forbidden(_) => "forbidden",
+            Error::RateLimited { .. } => "rate_limited",
+            Error::TooManyRequests { .. } => "too_many_requests",
+            Error::LimitExceeded(_) => "limit_exceeded",
+            Error::QuotaExceeded(_) => "quota_exceeded",
+            Error::InvalidRequest(_) => "invalid_request",
+            Error::Archive(_) => "archive_error",
+            Error::Snapshot(_) => "invalid_snapshot",
+            Error::Model(_) => "model_error",
+            Error::VectorDB(_) => "vector_db_error",
+            Error::Llm(_) => "llm_error",
+            Error::ShuttingDown => "shutting_down",
+            Error::Config(_) => "config_error",
+            Error::Upstream(_) => "upstream_error",
+        }
+    }
+}
+
+impl ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Error::RepositoryNotFound(_) | Error::BranchNotFound { .. } => StatusCode::NOT_FOUND,
+            Error::Unauthorized(_) | Error::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) | Error::QuotaExceeded(_) => StatusCode::FORBIDDEN,
+            Error::RateLimited { .. } | Error::TooManyRequests { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::LimitExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Archive(_) | Error::Snapshot(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Model(_) | Error::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::VectorDB(_) | Error::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Llm(_) | Error::Upstream(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            Error::RateLimited {
+                retry_after: Some(seconds),
+                ..
+            }
+            | Error::TooManyRequests {
+                retry_after: seconds,
+                ..
+            } => {
+                response.insert_header((header::RETRY_AFTER, seconds.to_string()));
+            }
+            _ => {}
+        }
+        if let Error::Unauthenticated(_) = self {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+        })
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(error: zip::result::ZipError) -> Self {
+        Error::Archive(error.to_string())
+    }
+}
+
+impl From<ort::OrtError> for Error {
+    fn from(error: ort::OrtError) -> Self {
+        Error::Model(error.to_string())
+    }
+}
+
+impl From<ndarray::ShapeError> for Error {
+    fn from(error: ndarray::ShapeError) -> Self {
+        Error::Model(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    #[test]
+    fn maps_errors_to_status_codes() {
+        let cases = [
+            (
+                Error::RepositoryNotFound("a-b-main".into()),
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                Error::BranchNotFound {
+                    repository: "a/b".into(),
+                    branch: "dev".into(),
+                },
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                Error::Unauthorized("GitHub".into()),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                Error::Unauthenticated("missing".into()),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (Error::Forbidden("GitHub".into()), StatusCode::FORBIDDEN),
+            (
+                Error::QuotaExceeded("vectors".into()),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                Error::RateLimited {
+                    host: "GitHub".into(),
+                    retry_after: None,
+                },
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                Error::TooManyRequests {
+                    message: "slow down".into(),
+                    retry_after: 1,
+                },
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                Error::LimitExceeded("size".into()),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (Error::InvalidRequest("bad".into()), StatusCode::BAD_REQUEST),
+            (
+                Error::Archive("zip".into()),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                Error::Snapshot("magic".into()),
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                Error::Model("onnx".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                Error::Config("toml".into()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                Error::VectorDB(anyhow::anyhow!("unavailable")),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (Error::ShuttingDown, StatusCode::SERVICE_UNAVAILABLE),
+            (Error::Llm("timeout".into()), StatusCode::BAD_GATEWAY),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.status_code(), status, "{error:?}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn responds_with_a_json_body() {
+        let response = Error::RepositoryNotFound("a-b-main".into()).error_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "repository_not_found",
+                "message": "Repository a-b-main was not found",
+            })
+        );
+    }
+
+    #[test]
+    fn sets_retry_after_and_authenticate_headers() {
+        let response = Error::TooManyRequests {
+            message: "slow down".into(),
+            retry_after: 7,
+        }
+        .error_response();
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "7");
+
+        let response = Error::RateLimited {
+            host: "GitHub".into(),
+            retry_after: None,
+        }
+        .error_response();
+        assert!(response.headers().get(header::RETRY_AFTER).is_none());
+
+        let response = Error::Unauthenticated("API key is missing".into()).error_response();
+        assert_eq!(
+            response.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer"
+        );
+    }
+}
@@ -0,0 +1,520 @@
+use crate::{
+    config::Config,
+    db::{QdrantDB, RepositoryEmbeddingsDB, SearchFilter},
+    embeddings::{Embeddings, EmbeddingsModel},
+    github::{embed_dir, embed_repo, File, Repository, RepositoryEmbeddings},
+    hosts::Hosts,
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+//Collections created by an evaluation against the vector database are namespaced as this tenant
+const EVAL_TENANT: &str = "eval";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum EvalRepository {
+    //Relative to the dataset file
+    Dir(PathBuf),
+    Remote(Repository),
+}
+
+//Files are indexed whole, so the file is what's expected rather than a range in it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expected {
+    pub path: String,
+}
+
+//One line of the dataset
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EvalCase {
+    pub repo: EvalRepository,
+    pub query: String,
+    pub expected: Vec<Expected>,
+    #[serde(default)]
+    pub filter: SearchFilter,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Scores {
+    pub recall: f64,
+    pub mrr: f64,
+    pub ndcg: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CaseResult {
+    pub line: usize,
+    pub repo_id: String,
+    pub query: String,
+    pub retrieved: Vec<String>,
+    //Expected paths that weren't in the top k
+    pub missed: Vec<String>,
+    pub scores: Scores,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub k: usize,
+    pub backend: &'static str,
+    pub cases: Vec<CaseResult>,
+    pub mean: Scores,
+}
+
+pub fn load_dataset(path: &Path) -> Result<Vec<(usize, EvalCase)>> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| Error::InvalidRequest(format!("Unable to read {}: {e}", path.display())))?;
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut cases = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut case: EvalCase = serde_json::from_str(line)
+            .map_err(|e| Error::InvalidRequest(format!("{}:{}: {e}", path.display(), index + 1)))?;
+        if case.expected.is_empty() {
+            return Err(Error::InvalidRequest(format!(
+                "{}:{}: expected is empty",
+                path.display(),
+                index + 1
+            )));
+        }
+        if let EvalRepository::Dir(dir) = &case.repo {
+            case.repo = EvalRepository::Dir(base.join(dir));
+        }
+        cases.push((index + 1, case));
+    }
+    if cases.is_empty() {
+        return Err(Error::InvalidRequest(format!(
+            "{} has no cases",
+            path.display()
+        )));
+    }
+    Ok(cases)
+}
+
+//Where the indexed repositories are searched
+enum Index {
+    //Brute force cosine similarity over the embeddings, needs nothing but the model
+    Memory(HashMap<String, RepositoryEmbeddings>),
+    VectorStore {
+        db: Box<QdrantDB>,
+        repositories: HashMap<String, Repository>,
+    },
+}
+
+//Indexes every repository the dataset names once, then scores each query against it
+pub async fn run<M: EmbeddingsModel + Send + Sync>(
+    cases: Vec<(usize, EvalCase)>,
+    k: usize,
+    vector_store: bool,
+    config: &Config,
+    model: &M,
+) -> Result<Report> {
+    if k == 0 {
+        return Err(Error::InvalidRequest("k must be at least 1".into()));
+    }
+    let hosts = Hosts::new(config)?;
+    let mut index = if vector_store {
+        Index::VectorStore {
+            db: Box::new(QdrantDB::initialize(config)?),
+            repositories: HashMap::new(),
+        }
+    } else {
+        Index::Memory(HashMap::new())
+    };
+
+    let mut keys: Vec<String> = Vec::with_capacity(cases.len());
+    for (_, case) in &cases {
+        let key = repository_key(&case.repo);
+        if !index.contains(&key) {
+            index.add(&key, &case.repo, model, &hosts, config).await?;
+        }
+        keys.push(key);
+    }
+
+    let mut results = Vec::with_capacity(cases.len());
+    let outcome = score_cases(&index, &cases, &keys, k, model, &mut results).await;
+    if let Index::VectorStore { db, repositories } = &index {
+        for repository in repositories.values() {
+            if let Err(e) = db.delete_repository(&repository.to_string()).await {
+                tracing::warn!(error = %e, repo_id = %repository.to_string(), "Unable to delete the evaluation index");
+            }
+        }
+    }
+    outcome?;
+
+    let mean = mean(results.iter().map(|result| result.scores));
+    Ok(Report {
+        k,
+        backend: if vector_store { "qdrant" } else { "memory" },
+        cases: results,
+        mean,
+    })
+}
+
+async fn score_cases<M: EmbeddingsModel>(
+    index: &Index,
+    cases: &[(usize, EvalCase)],
+    keys: &[String],
+    k: usize,
+    model: &M,
+    results: &mut Vec<CaseResult>,
+) -> Result<()> {
+    for ((line, case), key) in cases.iter().zip(keys) {
+        let query_embeddings = model.embed(&case.query)?;
+        let (repo_id, files) = index.search(key, query_embeddings, k, &case.filter).await?;
+        let retrieved: Vec<String> = files.into_iter().map(|file| file.path).collect();
+        let expected: BTreeSet<&str> = case.expected.iter().map(|e| e.path.as_str()).collect();
+        let scores = score(&retrieved, &expected, k);
+        tracing::debug!(line, query = %case.query, recall = scores.recall, mrr = scores.mrr, ndcg = scores.ndcg, "Scored case");
+        results.push(CaseResult {
+            line: *line,
+            repo_id,
+            query: case.query.clone(),
+            missed: expected
+                .iter()
+                .filter(|path| !retrieved.iter().any(|retrieved| retrieved == *path))
+                .map(|path| path.to_string())
+                .collect(),
+            retrieved,
+            scores,
+        });
+    }
+    Ok(())
+}
+
+impl Index {
+    fn contains(&self, key: &str) -> bool {
+        match self {
+            Index::Memory(repositories) => repositories.contains_key(key),
+            Index::VectorStore { repositories, .. } => repositories.contains_key(key),
+        }
+    }
+
+    async fn add<M: EmbeddingsModel + Send + Sync>(
+        &mut self,
+        key: &str,
+        repo: &EvalRepository,
+        model: &M,
+        hosts: &Hosts,
+        config: &Config,
+    ) -> Result<()> {
+        let (repository, mut embeddings) = match repo {
+            EvalRepository::Dir(dir) => {
+                let repository = Repository::local(dir)?;
+                let embeddings = embed_dir(dir, repository.clone(), model, &config.fetch);
+                (repository, embeddings)
+            }
+            EvalRepository::Remote(repository) => {
+                let repository = hosts.resolve(repository.clone()).await?;
+                let embeddings =
+                    embed_repo(repository.clone(), model, hosts, &config.fetch).await?;
+                (repository, embeddings)
+            }
+        };
+        tracing::info!(repo_id = %embeddings.repo_id, files = embeddings.file_embeddings.len(), "Indexed evaluation repository");
+        match self {
+            Index::Memory(repositories) => {
+                repositories.insert(key.to_string(), embeddings);
+            }
+            Index::VectorStore { db, repositories } => {
+                //Kept apart from the real index of the same repository
+                let repository = Repository {
+                    tenant: Some(EVAL_TENANT.to_string()),
+                    ..repository
+                };
+                embeddings.repo_id = repository.to_string();
+                db.insert_repo_embeddings(embeddings).await?;
+                repositories.insert(key.to_string(), repository);
+            }
+        }
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        key: &str,
+        query_embeddings: Embeddings,
+        k: usize,
+        filter: &SearchFilter,
+    ) -> Result<(String, Vec<File>)> {
+        match self {
+            Index::Memory(repositories) => {
+                let embeddings = &repositories[key];
+                let globs = filter.globs()?;
+                let mut scored: Vec<(f32, &File)> = embeddings
+                    .file_embeddings
+                    .iter()
+                    .filter(|file| filter.matches(&file.file, &globs))
+                    .map(|file| (cosine(&query_embeddings, &file.embeddings), &file.file))
+                    .collect();
+                scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+                let files = scored
+                    .into_iter()
+                    .take(k)
+                    .map(|(_, file)| file.clone())
+                    .collect();
+                Ok((embeddings.repo_id.clone(), files))
+            }
+            Index::VectorStore { db, repositories } => {
+                let repository = repositories[key].clone();
+                let repo_id = repository.to_string();
+                let files = db
+                    .get_relevant_files(repository, query_embeddings, k as u64, filter)
+                    .await?;
+                Ok((repo_id, files))
+            }
+        }
+    }
+}
+
+fn repository_key(repo: &EvalRepository) -> String {
+    match repo {
+        EvalRepository::Dir(dir) => dir
+            .canonicalize()
+            .unwrap_or_else(|_| dir.clone())
+            .display()
+            .to_string(),
+        EvalRepository::Remote(repository) => repository.to_string(),
+    }
+}
+
+//The same distance the collections are created with
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+//Binary relevance, a retrieved path is relevant the first time it's one of the expected paths
+fn score(retrieved: &[String], expected: &BTreeSet<&str>, k: usize) -> Scores {
+    let mut found: BTreeSet<&str> = BTreeSet::new();
+    let relevant: Vec<bool> = retrieved
+        .iter()
+        .take(k)
+        .map(|path| expected.contains(path.as_str()) && found.insert(path.as_str()))
+        .collect();
+    let hits = relevant.iter().filter(|relevant| **relevant).count();
+    let mrr = relevant
+        .iter()
+        .position(|relevant| *relevant)
+        .map_or(0.0, |rank| 1.0 / (rank + 1) as f64);
+    let gain = |rank: usize| 1.0 / ((rank + 2) as f64).log2();
+    let dcg: f64 = relevant
+        .iter()
+        .enumerate()
+        .filter(|(_, relevant)| **relevant)
+        .map(|(rank, _)| gain(rank))
+        .sum();
+    let ideal: f64 = (0..expected.len().min(k)).map(gain).sum();
+    Scores {
+        recall: hits as f64 / expected.len() as f64,
+        mrr,
+        ndcg: if ideal == 0.0 { 0.0 } else { dcg / ideal },
+    }
+}
+
+fn mean(scores: impl Iterator<Item = Scores>) -> Scores {
+    let (count, total) = scores.fold((0, Scores::default()), |(count, total), scores| {
+        (
+            count + 1,
+            Scores {
+                recall: total.recall + scores.recall,
+                mrr: total.mrr + scores.mrr,
+                ndcg: total.ndcg + scores.ndcg,
+            },
+        )
+    });
+    if count == 0 {
+        return total;
+    }
+    Scores {
+        recall: total.recall / count as f64,
+        mrr: total.mrr / count as f64,
+        ndcg: total.ndcg / count as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::github::FileEmbeddings;
+
+    //Counts hashed words, enough to tell the fixture's files apart without the ONNX model
+    struct Words;
+
+    impl EmbeddingsModel for Words {
+        fn embed(&self, text: &str) -> Result<Embeddings> {
+            let mut embeddings = vec![0.0; 64];
+            for word in text.split(|c: char| !c.is_alphanumeric()) {
+                if !word.is_empty() {
+                    let hash = word.to_lowercase().bytes().fold(0usize, |hash, byte| {
+                        hash.wrapping_mul(31).wrapping_add(byte as usize)
+                    });
+                    embeddings[hash % 64] += 1.0;
+                }
+            }
+            Ok(embeddings)
+        }
+    }
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    fn assert_scores(scores: Scores, recall: f64, mrr: f64, ndcg: f64) {
+        for (name, actual, expected) in [
+            ("recall", scores.recall, recall),
+            ("mrr", scores.mrr, mrr),
+            ("ndcg", scores.ndcg, ndcg),
+        ] {
+            assert!(
+                (actual - expected).abs() < 1e-4,
+                "{name} is {actual}, expected {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn scores_rankings() {
+        let expected = BTreeSet::from(["a", "b"]);
+        //DCG 1/log2(2) + 1/log2(4) = 1.5 over an ideal 1 + 1/log2(3) = 1.6309
+        assert_scores(
+            score(&paths(&["a", "x", "b"]), &expected, 3),
+            1.0,
+            1.0,
+            0.9197,
+        );
+        //Only the top k count, DCG 1/log2(3) = 0.6309 over 1.6309
+        assert_scores(
+            score(&paths(&["x", "a", "b"]), &expected, 2),
+            0.5,
+            0.5,
+            0.3869,
+        );
+        assert_scores(score(&paths(&["x", "y"]), &expected, 2), 0.0, 0.0, 0.0);
+        assert_scores(score(&[], &expected, 2), 0.0, 0.0, 0.0);
+    }
+
+    #[test]
+    fn scores_more_expected_files_than_k() {
+        let expected = BTreeSet::from(["a", "b", "c"]);
+        //Recall can't reach 1, while the ideal DCG only counts the k ranks there are
+        assert_scores(score(&paths(&["a", "b"]), &expected, 2), 0.6667, 1.0, 1.0);
+        assert_scores(
+            score(&paths(&["x", "c"]), &expected, 2),
+            0.3333,
+            0.5,
+            0.3869,
+        );
+    }
+
+    #[test]
+    fn scores_repeated_paths_once() {
+        let expected = BTreeSet::from(["a", "b"]);
+        assert_scores(
+            score(&paths(&["a", "a", "b"]), &expected, 3),
+            1.0,
+            1.0,
+            0.9197,
+        );
+        assert_scores(score(&paths(&["a", "a"]), &expected, 2), 0.5, 1.0, 0.6131);
+    }
+
+    #[test]
+    fn averages_scores() {
+        let scores = [
+            Scores {
+                recall: 1.0,
+                mrr: 1.0,
+                ndcg: 1.0,
+            },
+            Scores {
+                recall: 0.0,
+                mrr: 0.5,
+                ndcg: 0.25,
+            },
+        ];
+        assert_scores(mean(scores.into_iter()), 0.5, 0.75, 0.625);
+        assert_scores(mean(std::iter::empty()), 0.0, 0.0, 0.0);
+    }
+
+    #[actix_web::test]
+    async fn keeps_index_order_between_tied_files() {
+        let file = |path: &str| FileEmbeddings {
+            file: File::new(path.to_string(), String::new(), None),
+            embeddings: vec![1.0, 0.0],
+        };
+        let index = Index::Memory(HashMap::from([(
+            "repo".to_string(),
+            RepositoryEmbeddings {
+                repo_id: "local/repo".into(),
+                file_embeddings: vec![file("b.rs"), file("a.rs"), file("c.rs")],
+            },
+        )]));
+        let (repo_id, files) = index
+            .search("repo", vec![2.0, 0.0], 2, &SearchFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(repo_id, "local/repo");
+        let paths: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["b.rs", "a.rs"]);
+    }
+
+    #[actix_web::test]
+    async fn evaluates_the_fixture_dataset_in_memory() {
+        let dataset = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/eval/dataset.jsonl");
+        let cases = load_dataset(&dataset).unwrap();
+        //k covers every file in the fixture repository, so every expected file is found
+        let report = run(cases, 8, false, &Config::default(), &Words)
+            .await
+            .unwrap();
+        assert_eq!(report.backend, "memory");
+        assert_eq!(report.cases.len(), 6);
+        for case in &report.cases {
+            assert!(
+                case.missed.is_empty(),
+                "line {} missed {:?}",
+                case.line,
+                case.missed
+            );
+            assert_eq!(case.scores.recall, 1.0);
+            assert!(case.scores.mrr > 0.0 && case.scores.ndcg > 0.0);
+        }
+        //The last case only searches Rust files
+        assert!(report.cases[5]
+            .retrieved
+            .iter()
+            .all(|path| path.ends_with(".rs")));
+        assert_eq!(report.mean.recall, 1.0);
+    }
+
+    #[test]
+    fn rejects_invalid_datasets() {
+        let dir = tempfile::tempdir().unwrap();
+        let dataset = dir.path().join("dataset.jsonl");
+        for line in [
+            r#"{"repo": "repo", "query": "q", "expected": []}"#,
+            r#"{"repo": "repo", "query": "q", "expected": [{"path": "a.rs", "lines": [1, 2]}]}"#,
+            "",
+        ] {
+            std::fs::write(&dataset, line).unwrap();
+            assert!(matches!(
+                load_dataset(&dataset),
+                Err(Error::InvalidRequest(_))
+            ));
+        }
+    }
+}
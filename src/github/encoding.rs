@@ -0,0 +1,175 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
+
+//Files with more undecodable sequences than this are treated as binary
+const MAX_LOSSY_ERROR_RATIO: f64 = 0.01;
+
+pub struct Decoded {
+    pub content: String,
+    pub encoding: &'static str,
+    pub lossy: bool,
+}
+
+//Decodes text in any encoding to UTF-8, returning None for content that looks binary
+pub fn decode(bytes: &[u8]) -> Option<Decoded> {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(bytes) {
+        let (content, had_errors) = encoding.decode_without_bom_handling(&bytes[bom_length..]);
+        return Some(Decoded {
+            content: content.into_owned(),
+            encoding: encoding.name(),
+            lossy: had_errors,
+        });
+    }
+    if let Ok(content) = std::str::from_utf8(bytes) {
+        return (!content.contains('\0')).then(|| Decoded {
+            content: content.to_string(),
+            encoding: UTF_8.name(),
+            lossy: false,
+        });
+    }
+    if bytes.contains(&0) {
+        return None;
+    }
+
+    //A stray bad byte in an otherwise UTF-8 file shouldn't turn every other character into mojibake
+    let (multibyte_chars, invalid_sequences) = utf8_stats(bytes);
+    if multibyte_chars > 0 && is_mostly_text(invalid_sequences, bytes.len()) {
+        return Some(Decoded {
+            content: String::from_utf8_lossy(bytes).into_owned(),
+            encoding: UTF_8.name(),
+            lossy: true,
+        });
+    }
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    let encoding = detector.guess(None, false);
+    let (content, had_errors) = encoding.decode_without_bom_handling(bytes);
+    if had_errors {
+        let errors = content
+            .chars()
+            .filter(|&c| c == char::REPLACEMENT_CHARACTER)
+            .count();
+        if !is_mostly_text(errors, bytes.len()) {
+            return None;
+        }
+    }
+    Some(Decoded {
+        content: content.into_owned(),
+        encoding: encoding.name(),
+        lossy: had_errors,
+    })
+}
+
+fn is_mostly_text(errors: usize, length: usize) -> bool {
+    (errors as f64) <= (length as f64) * MAX_LOSSY_ERROR_RATIO
+}
+
+//Counts valid multi-byte characters and invalid sequences in almost-UTF-8 input
+fn utf8_stats(mut bytes: &[u8]) -> (usize, usize) {
+    let count_multibyte = |valid: &[u8]| {
+        std::str::from_utf8(valid).map_or(0, |s| s.chars().filter(|c| !c.is_ascii()).count())
+    };
+    let mut multibyte_chars = 0;
+    let mut invalid_sequences = 0;
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                multibyte_chars += count_multibyte(valid.as_bytes());
+                return (multibyte_chars, invalid_sequences);
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                multibyte_chars += count_multibyte(valid);
+                invalid_sequences += 1;
+                match e.error_len() {
+                    Some(length) => bytes = &rest[length..],
+                    None => return (multibyte_chars, invalid_sequences),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(bytes: &[u8]) -> (String, &'static str, bool) {
+        let decoded = decode(bytes).expect("decoded as text");
+        (decoded.content, decoded.encoding, decoded.lossy)
+    }
+
+    #[test]
+    fn utf8_is_kept_as_is() {
+        assert_eq!(
+            decoded("fn main() { println!(\"héllo\"); }".as_bytes()),
+            ("fn main() { println!(\"héllo\"); }".into(), "UTF-8", false)
+        );
+        assert_eq!(decoded(b""), (String::new(), "UTF-8", false));
+    }
+
+    #[test]
+    fn byte_order_marks_pick_the_encoding_and_are_stripped() {
+        assert_eq!(
+            decoded(b"\xEF\xBB\xBFh\xC3\xA9llo"),
+            ("héllo".into(), "UTF-8", false)
+        );
+        assert_eq!(
+            decoded(b"\xFF\xFEh\x00\xE9\x00l\x00l\x00o\x00"),
+            ("héllo".into(), "UTF-16LE", false)
+        );
+        assert_eq!(
+            decoded(b"\xFE\xFF\x00h\x00\xE9\x00l\x00l\x00o"),
+            ("héllo".into(), "UTF-16BE", false)
+        );
+        //An odd trailing byte can't be a UTF-16 code unit
+        assert_eq!(
+            decoded(b"\xFF\xFEh\x00i\x00!"),
+            ("hi\u{FFFD}".into(), "UTF-16LE", true)
+        );
+    }
+
+    #[test]
+    fn latin1_is_detected() {
+        let bytes = b"# Caf\xE9 cr\xE8me\n\nD\xE9j\xE0 vu, \xE7a co\xFBte tr\xE8s cher \xE0 la fran\xE7aise.\n\
+            Les donn\xE9es sont stock\xE9es dans le r\xE9pertoire des pr\xE9f\xE9rences.\n";
+        let (content, encoding, lossy) = decoded(bytes);
+        assert_eq!(encoding, "windows-1252");
+        assert!(!lossy);
+        assert!(content.starts_with("# Café crème\n\nDéjà vu, ça coûte très cher"));
+        assert!(content.ends_with("répertoire des préférences.\n"));
+    }
+
+    #[test]
+    fn stray_bytes_in_utf8_are_replaced() {
+        let mut bytes = "// naïve café, déjà vu\n".repeat(10).into_bytes();
+        bytes.insert(40, 0xFF);
+        let (content, encoding, lossy) = decoded(&bytes);
+        assert_eq!((encoding, lossy), ("UTF-8", true));
+        assert_eq!(content.matches(char::REPLACEMENT_CHARACTER).count(), 1);
+        assert_eq!(content.matches("déjà").count(), 10);
+
+        //Cut off in the middle of a character
+        let mut bytes = "// naïve café\n".repeat(10).into_bytes();
+        bytes.push(0xC3);
+        let (content, encoding, lossy) = decoded(&bytes);
+        assert_eq!((encoding, lossy), ("UTF-8", true));
+        assert!(content.ends_with("café\n\u{FFFD}"));
+    }
+
+    #[test]
+    fn binary_is_rejected() {
+        assert!(decode(b"\x7FELF\x02\x01\x01\x00\x00\x00\x00\x00").is_none());
+        assert!(decode(b"text with a \0 in it").is_none());
+        assert!(decode(b"\x89PNG\r\n\x1A\n\x00\x00\x00\rIHDR\xFF\xD8").is_none());
+    }
+
+    #[test]
+    fn utf8_stats_count_characters_and_errors() {
+        assert_eq!(utf8_stats("ascii".as_bytes()), (0, 0));
+        assert_eq!(utf8_stats("héllo wörld".as_bytes()), (2, 0));
+        assert_eq!(utf8_stats(b"h\xC3\xA9\xFFll\xFE\xC3\xB6"), (2, 2));
+        assert_eq!(utf8_stats(b"h\xC3\xA9llo\xE2\x82"), (1, 1));
+    }
+}
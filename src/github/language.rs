@@ -0,0 +1,86 @@
+//Best effort language detection from the file name, falling back to the shebang line
+pub fn detect_language(path: &str, content: &str) -> Option<String> {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let language = match file_name {
+        "Dockerfile" | "Containerfile" => Some("dockerfile"),
+        "Makefile" | "GNUmakefile" => Some("makefile"),
+        "CMakeLists.txt" => Some("cmake"),
+        "Gemfile" | "Rakefile" => Some("ruby"),
+        _ => None,
+    }
+    .or_else(|| {
+        let (_, extension) = file_name.rsplit_once('.')?;
+        language_from_extension(&extension.to_ascii_lowercase())
+    })
+    .or_else(|| language_from_shebang(content))?;
+    Some(language.to_string())
+}
+
+fn language_from_extension(extension: &str) -> Option<&'static str> {
+    let language = match extension {
+        "rs" => "rust",
+        "py" | "pyi" => "python",
+        "js" | "mjs" | "cjs" | "jsx" => "javascript",
+        "ts" | "mts" | "cts" | "tsx" => "typescript",
+        "go" => "go",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "scala" => "scala",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => "cpp",
+        "cs" => "csharp",
+        "swift" => "swift",
+        "m" | "mm" => "objective-c",
+        "rb" => "ruby",
+        "php" => "php",
+        "sh" | "bash" | "zsh" => "shell",
+        "ps1" => "powershell",
+        "lua" => "lua",
+        "dart" => "dart",
+        "ex" | "exs" => "elixir",
+        "erl" | "hrl" => "erlang",
+        "hs" => "haskell",
+        "ml" | "mli" => "ocaml",
+        "clj" | "cljs" => "clojure",
+        "r" => "r",
+        "jl" => "julia",
+        "zig" => "zig",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" | "scss" | "sass" | "less" => "css",
+        "vue" => "vue",
+        "svelte" => "svelte",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "xml" => "xml",
+        "md" | "markdown" => "markdown",
+        "rst" => "restructuredtext",
+        "proto" => "protobuf",
+        "graphql" | "gql" => "graphql",
+        "tf" => "terraform",
+        "nix" => "nix",
+        _ => return None,
+    };
+    Some(language)
+}
+
+fn language_from_shebang(content: &str) -> Option<&'static str> {
+    let shebang = content.lines().next()?.strip_prefix("#!")?;
+    let mut words = shebang.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|word| !word.starts_with('-'))?;
+    }
+    let language = match interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.') {
+        "sh" | "bash" | "zsh" | "dash" | "ksh" => "shell",
+        "python" => "python",
+        "node" | "deno" => "javascript",
+        "ruby" => "ruby",
+        "perl" => "perl",
+        "php" => "php",
+        "lua" => "lua",
+        _ => return None,
+    };
+    Some(language)
+}
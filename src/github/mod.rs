@@ -1,16 +1,72 @@
+mod encoding;
+mod language;
 use crate::{
+    auth::TENANT_SEPARATOR,
+    config::FetchConfig,
     embeddings::{Embeddings, EmbeddingsModel},
+    hosts::{Host, Hosts},
+    metrics,
     prelude::*,
 };
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
-use std::io::Read;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    process::Command,
+};
+
+pub use encoding::{decode, Decoded};
+pub use language::detect_language;
 
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct File {
     pub path: String,
     pub content: String,
     pub length: usize,
+    pub language: Option<String>,
+    pub line_count: usize,
+    pub content_hash: String,
+    //Newest commit that changed the file, None when the host doesn't tell
+    pub last_commit: Option<String>,
+    //Commit the content was fetched at, the same for every file of an archive
+    pub indexed_commit: Option<String>,
+    //Encoding of the original bytes, the content itself is always UTF-8
+    pub encoding: String,
+    //Set when undecodable bytes were replaced with U+FFFD
+    pub lossy: bool,
+}
+
+impl File {
+    pub fn new(path: String, content: String, last_commit: Option<String>) -> File {
+        File {
+            length: content.len(),
+            language: detect_language(&path, &content),
+            line_count: content.lines().count(),
+            content_hash: format!("{:x}", Sha256::digest(content.as_bytes())),
+            last_commit,
+            indexed_commit: None,
+            encoding: "UTF-8".to_string(),
+            lossy: false,
+            path,
+            content,
+        }
+    }
+
+    //Returns None for binary files
+    pub fn from_bytes(path: String, bytes: &[u8], last_commit: Option<String>) -> Option<File> {
+        let Decoded {
+            content,
+            encoding,
+            lossy,
+        } = decode(bytes)?;
+        Some(File {
+            encoding: encoding.to_string(),
+            lossy,
+            ..File::new(path, content, last_commit)
+        })
+    }
 }
 
 impl ToString for File {
@@ -24,107 +80,381 @@ impl ToString for File {
 
 #[derive(Debug, Clone)]
 pub struct FileEmbeddings {
-    pub path: String,
+    pub file: File,
     pub embeddings: Embeddings,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct RepositoryEmbeddings {
     pub repo_id: String,
     pub file_embeddings: Vec<FileEmbeddings>,
 }
 
+impl RepositoryEmbeddings {
+    //Replaces the changed files and drops the removed ones, for incremental updates.
+    //Files already indexed are kept over new ones when the result would exceed max_files
+    pub fn with_changes(
+        self,
+        changed: Vec<FileEmbeddings>,
+        removed: &[String],
+        max_files: usize,
+    ) -> RepositoryEmbeddings {
+        let removed: HashSet<&str> = removed.iter().map(String::as_str).collect();
+        let mut changed: HashMap<String, FileEmbeddings> = changed
+            .into_iter()
+            .map(|file| (file.file.path.clone(), file))
+            .collect();
+        let mut file_embeddings: Vec<FileEmbeddings> = self
+            .file_embeddings
+            .into_iter()
+            .filter(|file| !removed.contains(file.file.path.as_str()))
+            .map(|file| changed.remove(&file.file.path).unwrap_or(file))
+            .collect();
+        let mut added: Vec<FileEmbeddings> = changed.into_values().collect();
+        added.sort_by(|a, b| a.file.path.cmp(&b.file.path));
+        let room = max_files.saturating_sub(file_embeddings.len());
+        if added.len() > room {
+            tracing::warn!(
+                dropped = added.len() - room,
+                max_files,
+                "Skipping added files over the file count limit"
+            );
+        }
+        file_embeddings.extend(added.into_iter().take(room));
+        RepositoryEmbeddings {
+            repo_id: self.repo_id,
+            file_embeddings,
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub struct RepositoryFilePaths {
     pub repo_id: String,
-    pub file_paths: Vec<String>
+    pub file_paths: Vec<String>,
+}
+
+//Kept out of Debug output, so a repository can be logged or recorded on a span with its credentials
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Repository {
+    #[serde(default)]
+    pub host: Host,
     pub owner: String,
     pub name: String,
+    //Left empty to use the repository's default branch
+    #[serde(default)]
     pub branch: String,
+    //Overrides the configured credentials for this request only
+    #[serde(default)]
+    pub token: Option<Secret>,
+    //Set from the API key, never from the request body
+    #[serde(skip)]
+    pub tenant: Option<String>,
+}
+
+impl Repository {
+    //The request's token, falling back to the one configured for the host
+    pub fn token_or<'a>(&'a self, configured: &'a Option<String>) -> Option<&'a str> {
+        self.token
+            .as_ref()
+            .map(Secret::expose)
+            .or(configured.as_deref())
+    }
+
+    //Local directories are indexed under a pseudo repository named after the directory
+    pub fn local(dir: &Path) -> Result<Repository> {
+        let dir = dir
+            .canonicalize()
+            .map_err(|_| Error::RepositoryNotFound(dir.display().to_string()))?;
+        let name = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "root".to_string());
+        Ok(Repository {
+            host: Host::GitHub,
+            owner: "local".to_string(),
+            name,
+            branch: "local".to_string(),
+            token: None,
+            tenant: None,
+        })
+    }
+
+    pub fn full_name(&self) -> String {
+        format!("{}/{}", &self.owner, &self.name)
+    }
 }
 
 impl ToString for Repository {
+    //GitHub repositories keep their original unprefixed collection names
     fn to_string(&self) -> String {
-        format!("{}-{}-{}", &self.owner, &self.name, &self.branch)
+        let id = match self.host {
+            Host::GitHub => format!("{}-{}-{}", &self.owner, &self.name, &self.branch),
+            Host::GitLab => format!("gitlab-{}-{}-{}", &self.owner, &self.name, &self.branch),
+            Host::Bitbucket => {
+                format!("bitbucket-{}-{}-{}", &self.owner, &self.name, &self.branch)
+            }
+            Host::Gitea => format!("gitea-{}-{}-{}", &self.owner, &self.name, &self.branch),
+        };
+        match &self.tenant {
+            Some(tenant) => format!("{tenant}{TENANT_SEPARATOR}{id}"),
+            None => id,
+        }
     }
 }
 
 pub async fn embed_repo<M: EmbeddingsModel + Send + Sync>(
     repository: Repository,
     model: &M,
+    hosts: &Hosts,
+    fetch_config: &FetchConfig,
 ) -> Result<RepositoryEmbeddings> {
+    let repository = hosts.resolve(repository).await?;
+    let time = std::time::Instant::now();
+    let files: Vec<File> = hosts.fetch_repo_files(&repository, fetch_config).await?;
+    tracing::info!(
+        files = files.len(),
+        elapsed_ms = time.elapsed().as_millis() as u64,
+        "Fetched files"
+    );
+    Ok(embed_files(repository, files, model))
+}
+
+//The repository names the collection, so the caller can put it under a tenant
+pub fn embed_dir<M: EmbeddingsModel + Send + Sync>(
+    dir: &Path,
+    repository: Repository,
+    model: &M,
+    fetch_config: &FetchConfig,
+) -> RepositoryEmbeddings {
     let time = std::time::Instant::now();
-    let files: Vec<File> = fetch_repo_files(repository.clone()).await?;
-    println!("Time to fetch files: {:?}", time.elapsed());
+    let files: Vec<File> = tracing::info_span!("filter", dir = %dir.display())
+        .in_scope(|| read_dir_files(dir, fetch_config));
+    tracing::info!(
+        files = files.len(),
+        elapsed_ms = time.elapsed().as_millis() as u64,
+        "Read files"
+    );
+    embed_files(repository, files, model)
+}
+
+pub fn embed_files<M: EmbeddingsModel + Send + Sync>(
+    repository: Repository,
+    files: Vec<File>,
+    model: &M,
+) -> RepositoryEmbeddings {
+    let span = tracing::info_span!("embed", files = files.len());
+    let _entered = span.enter();
     let time = std::time::Instant::now();
-    let file_embeddings: Vec<FileEmbeddings> = files
-        .into_par_iter()
-        .filter_map(|file| {
-            let embed_content = file.to_string();
-            let embeddings = model.embed(&embed_content).unwrap();
-            Some(FileEmbeddings {
-                path: file.path,
-                embeddings,
+    let timer = metrics::EMBEDDING_BATCH_DURATION.start_timer();
+    //Spans don't follow work onto rayon threads, so the batch span is passed explicitly
+    let embed = || {
+        files
+            .into_par_iter()
+            .filter_map(|file| {
+                let embed_content = tracing::debug_span!(parent: &span, "chunk", path = %file.path)
+                    .in_scope(|| file.to_string());
+                match model.embed(&embed_content) {
+                    Ok(embeddings) => Some(FileEmbeddings { file, embeddings }),
+                    Err(e) => {
+                        tracing::warn!(parent: &span, path = %file.path, error = %e, "Skipping file");
+                        None
+                    }
+                }
             })
-        })
-        .collect();
-    println!("Time to embed files: {:?}", time.elapsed());
-    Ok(RepositoryEmbeddings {
+            .collect::<Vec<FileEmbeddings>>()
+    };
+    let file_embeddings = match model.pool() {
+        Some(pool) => pool.install(embed),
+        None => embed(),
+    };
+    timer.observe_duration();
+    metrics::FILES_EMBEDDED.inc_by(file_embeddings.len() as u64);
+    tracing::info!(
+        embedded = file_embeddings.len(),
+        elapsed_ms = time.elapsed().as_millis() as u64,
+        "Embedded files"
+    );
+    RepositoryEmbeddings {
         repo_id: repository.to_string(),
         file_embeddings,
-    })
-}
-
-async fn fetch_repo_files(repository: Repository) -> Result<Vec<File>> {
-    let Repository {
-        owner,
-        name,
-        branch,
-    } = repository;
-    let url = format!("https://github.com/{owner}/{name}/archive/{branch}.zip");
-    let response = reqwest::get(url).await?.bytes().await?;
-    let reader = std::io::Cursor::new(response);
-    let mut archive = zip::ZipArchive::new(reader)?;
-    let files: Vec<File> = (0..archive.len())
-        .filter_map(|file| {
-            let mut file = archive.by_index(file).unwrap();
-            if file.is_file() {
-                let mut content = String::new();
-                let length = content.len();
-                //Fails for non UTF-8 files
-                match file.read_to_string(&mut content) {
-                    Ok(_) => Some(File {
-                        path: file.name().split_once("/").unwrap().1.to_string(),
-                        content: content,
-                        length,
-                    }),
-                    Err(_) => None,
-                }
-            } else {
-                None
-            }
+    }
+}
+
+//Walks the directory honouring .gitignore files, skipping binary files
+fn read_dir_files(dir: &Path, fetch_config: &FetchConfig) -> Vec<File> {
+    let last_commits = git_last_commits(dir);
+    let head = git_head(dir);
+    ignore::WalkBuilder::new(dir)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_some_and(|kind| kind.is_file()))
+        .filter(|entry| {
+            entry
+                .metadata()
+                .is_ok_and(|metadata| metadata.len() <= fetch_config.max_file_bytes)
+        })
+        .filter_map(|entry| {
+            let path = entry.path().strip_prefix(dir).ok()?;
+            let path = path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let bytes = std::fs::read(entry.path()).ok()?;
+            let last_commit = last_commits.get(&path).cloned();
+            let file = File::from_bytes(path, &bytes, last_commit)?;
+            Some(File {
+                indexed_commit: head.clone(),
+                ..file
+            })
         })
-        .collect();
-    Ok(files)
-}
-
-pub async fn fetch_file_content(repository: Repository, path: &str) -> Result<String> {
-    let Repository {
-        owner: repo_owner,
-        name: repo_name,
-        branch: repo_branch,
-    } = repository;
-    let url =
-        format!("https://raw.githubusercontent.com/{repo_owner}/{repo_name}/{repo_branch}/{path}");
-    let response = reqwest::get(url).await?;
-    if response.status() == reqwest::StatusCode::OK {
-        let content = response.text().await?;
-        Ok(content)
-    } else {
-        Err(anyhow::anyhow!("Unable to fetch file content"))
+        .take(fetch_config.max_file_count as usize)
+        .collect()
+}
+
+//The checked out commit, None when the directory isn't a git checkout
+fn git_head(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+//Maps each path to the newest commit touching it, empty when the directory isn't a git checkout
+fn git_last_commits(dir: &Path) -> HashMap<String, String> {
+    let output = Command::new("git")
+        .args([
+            "log",
+            "--format=format:%x00%H",
+            "--name-only",
+            "--no-renames",
+            "--relative",
+        ])
+        .current_dir(dir)
+        .output();
+    let output = match output {
+        Ok(output) if output.status.success() => output.stdout,
+        _ => return HashMap::new(),
+    };
+
+    let mut last_commits = HashMap::new();
+    let mut commit = "";
+    for line in std::str::from_utf8(&output).unwrap_or_default().lines() {
+        if let Some(hash) = line.strip_prefix('\0') {
+            commit = hash;
+        } else if !line.is_empty() && !commit.is_empty() {
+            last_commits
+                .entry(line.to_string())
+                .or_insert_with(|| commit.to_string());
+        }
+    }
+    last_commits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embedded(path: &str, content: &str) -> FileEmbeddings {
+        FileEmbeddings {
+            file: File::new(path.to_string(), content.to_string(), None),
+            embeddings: vec![0.0; 4],
+        }
+    }
+
+    #[test]
+    fn applies_changes_to_a_copy_of_the_index() {
+        let live = RepositoryEmbeddings {
+            repo_id: "owner-name-main".to_string(),
+            file_embeddings: vec![
+                embedded("src/main.rs", "fn main() {}"),
+                embedded("src/env.rs", "old"),
+                embedded("README.md", "# name"),
+            ],
+        };
+        let updated = live.with_changes(
+            vec![
+                embedded("src/main.rs", "fn main() { run() }"),
+                embedded("src/config.rs", "new"),
+            ],
+            &["src/env.rs".to_string()],
+            10,
+        );
+        let mut files: Vec<(&str, &str)> = updated
+            .file_embeddings
+            .iter()
+            .map(|file| (file.file.path.as_str(), file.file.content.as_str()))
+            .collect();
+        files.sort();
+        assert_eq!(updated.repo_id, "owner-name-main");
+        assert_eq!(
+            files,
+            [
+                ("README.md", "# name"),
+                ("src/config.rs", "new"),
+                ("src/main.rs", "fn main() { run() }"),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_indexed_files_over_added_ones_at_the_file_limit() {
+        let live = RepositoryEmbeddings {
+            repo_id: "owner-name-main".to_string(),
+            file_embeddings: vec![embedded("a.rs", "a"), embedded("b.rs", "b")],
+        };
+        let updated = live.with_changes(
+            vec![
+                embedded("d.rs", "new"),
+                embedded("c.rs", "new"),
+                embedded("b.rs", "changed"),
+            ],
+            &[],
+            3,
+        );
+        let files: Vec<(&str, &str)> = updated
+            .file_embeddings
+            .iter()
+            .map(|file| (file.file.path.as_str(), file.file.content.as_str()))
+            .collect();
+        assert_eq!(files, [("a.rs", "a"), ("b.rs", "changed"), ("c.rs", "new")]);
+    }
+
+    #[test]
+    fn keeps_tokens_out_of_debug_output() {
+        let repository: Repository =
+            serde_json::from_str(r#"{"owner": "owner", "name": "repo", "token": "ghp_secret"}"#)
+                .unwrap();
+        assert_eq!(repository.token_or(&None), Some("ghp_secret"));
+        assert!(!format!("{repository:?}").contains("ghp_secret"));
+
+        let configured = Some("configured".to_string());
+        let repository = Repository {
+            token: None,
+            ..repository
+        };
+        assert_eq!(repository.token_or(&configured), Some("configured"));
     }
 }
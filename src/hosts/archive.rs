@@ -0,0 +1,292 @@
+use crate::{config::FetchConfig, github::File, metrics, prelude::*};
+use reqwest::Response;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+
+//Entries smaller than this are too small to be a meaningful decompression bomb
+const RATIO_CHECK_MIN_BYTES: u64 = 1024 * 1024;
+
+//Streams the archive to an anonymous temp file so it's never held in memory
+pub(super) async fn download(
+    mut response: Response,
+    config: &FetchConfig,
+    host: &str,
+) -> Result<std::fs::File> {
+    if let Some(length) = response.content_length() {
+        if length > config.max_archive_bytes {
+            return Err(archive_too_large(config));
+        }
+    }
+    let mut archive = tempfile::tempfile().map_err(io_error)?;
+    let mut written: u64 = 0;
+    let downloaded = metrics::ARCHIVE_BYTES.with_label_values(&[host]);
+    while let Some(chunk) = response.chunk().await? {
+        written += chunk.len() as u64;
+        downloaded.inc_by(chunk.len() as u64);
+        if written > config.max_archive_bytes {
+            return Err(archive_too_large(config));
+        }
+        archive.write_all(&chunk).map_err(io_error)?;
+    }
+    archive.seek(SeekFrom::Start(0)).map_err(io_error)?;
+    Ok(archive)
+}
+
+pub(super) fn extract(archive: std::fs::File, config: &FetchConfig) -> Result<Vec<File>> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(archive))?;
+    if archive.len() as u64 > config.max_entries {
+        return Err(Error::LimitExceeded(format!(
+            "Archive has {} entries, the limit is {}",
+            archive.len(),
+            config.max_entries
+        )));
+    }
+
+    //Archives from GitHub and GitLab carry the commit they were built from as the zip comment
+    let commit = String::from_utf8_lossy(archive.comment())
+        .trim()
+        .to_string();
+    let commit =
+        (commit.len() == 40 && commit.chars().all(|c| c.is_ascii_hexdigit())).then_some(commit);
+
+    let mut files: Vec<File> = Vec::new();
+    let mut total_bytes: u64 = 0;
+    for index in 0..archive.len() {
+        if files.len() >= config.max_file_count as usize {
+            break;
+        }
+        let file = match archive.by_index(index) {
+            Ok(file) => file,
+            Err(e) => {
+                tracing::warn!(index, error = %e, "Skipping archive entry");
+                continue;
+            }
+        };
+        if !file.is_file() {
+            continue;
+        }
+        //Archive entries are prefixed with a single top-level directory
+        let path = match file.name().split_once('/') {
+            Some((_, path)) => path.to_string(),
+            None => continue,
+        };
+
+        //Declared sizes are only trusted to skip files early, what's read is counted as it's read
+        if file.size() > config.max_file_bytes {
+            tracing::debug!(%path, size = file.size(), "Skipping file over the per-file limit");
+            continue;
+        }
+        let compressed = file.compressed_size();
+        let remaining = config.max_total_bytes - total_bytes;
+        let bytes = match read_entry(file, &path, compressed, remaining, config)? {
+            Entry::Read(bytes) => bytes,
+            Entry::Skipped(read) => {
+                total_bytes += read;
+                continue;
+            }
+        };
+        total_bytes += bytes.len() as u64;
+        //The archive's commit isn't the one that last changed each file
+        if let Some(file) = File::from_bytes(path, &bytes, None) {
+            files.push(File {
+                indexed_commit: commit.clone(),
+                ..file
+            });
+        }
+    }
+    Ok(files)
+}
+
+enum Entry {
+    Read(Vec<u8>),
+    //Over the per-file limit or unreadable, with the bytes decompressed before giving up
+    Skipped(u64),
+}
+
+//Stops decompressing as soon as the entry is over the per-file limit, the archive's
+//remaining budget or the compression ratio, whatever its header declares
+fn read_entry<R: Read>(
+    entry: R,
+    path: &str,
+    compressed: u64,
+    remaining: u64,
+    config: &FetchConfig,
+) -> Result<Entry> {
+    let ratio_limit = compressed
+        .saturating_mul(config.max_compression_ratio)
+        .max(RATIO_CHECK_MIN_BYTES);
+    let limit = config.max_file_bytes.min(remaining).min(ratio_limit);
+    let mut bytes = Vec::new();
+    if let Err(e) = entry.take(limit + 1).read_to_end(&mut bytes) {
+        tracing::warn!(%path, error = %e, "Skipping unreadable file");
+        return Ok(Entry::Skipped(bytes.len() as u64));
+    }
+    let read = bytes.len() as u64;
+    if read > ratio_limit {
+        return Err(Error::LimitExceeded(format!(
+            "{path} decompresses more than {}x, the limit is {}x",
+            read / compressed.max(1),
+            config.max_compression_ratio
+        )));
+    }
+    if read > remaining {
+        return Err(Error::LimitExceeded(format!(
+            "Archive decompresses to more than {} bytes",
+            config.max_total_bytes
+        )));
+    }
+    if read > config.max_file_bytes {
+        tracing::debug!(%path, "Skipping file over the per-file limit");
+        return Ok(Entry::Skipped(read));
+    }
+    Ok(Entry::Read(bytes))
+}
+
+fn archive_too_large(config: &FetchConfig) -> Error {
+    Error::LimitExceeded(format!(
+        "Archive is larger than {} bytes",
+        config.max_archive_bytes
+    ))
+}
+
+fn io_error(error: std::io::Error) -> Error {
+    Error::Archive(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    const COMMIT: &str = "9c4b3a4f0e6e2d1c8b7a6f5e4d3c2b1a0f9e8d7c";
+
+    fn zip(entries: &[(&str, &[u8])], comment: &str) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.set_comment(comment);
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        for (name, content) in entries {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    //Rewrites the uncompressed size every header declares, as a crafted archive would
+    fn declare_size(archive: &mut [u8], size: u32) {
+        for offset in 0..archive.len().saturating_sub(4) {
+            let field = match &archive[offset..offset + 4] {
+                [0x50, 0x4b, 0x03, 0x04] => offset + 22,
+                [0x50, 0x4b, 0x01, 0x02] => offset + 24,
+                _ => continue,
+            };
+            archive[field..field + 4].copy_from_slice(&size.to_le_bytes());
+        }
+    }
+
+    fn extract_bytes(archive: &[u8], config: &FetchConfig) -> Result<Vec<File>> {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(archive).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        extract(file, config)
+    }
+
+    fn source(bytes: usize) -> Vec<u8> {
+        b"fn main() {}\n"
+            .iter()
+            .copied()
+            .cycle()
+            .take(bytes)
+            .collect()
+    }
+
+    fn small_limits() -> FetchConfig {
+        FetchConfig {
+            max_file_bytes: 64 * 1024,
+            max_total_bytes: 100 * 1024,
+            ..FetchConfig::default()
+        }
+    }
+
+    #[test]
+    fn extracts_files_with_the_archive_commit() {
+        let archive = zip(
+            &[
+                ("repo-main/src/main.rs", b"fn main() {}\n"),
+                ("repo-main/README.md", b"# Repo\n"),
+            ],
+            COMMIT,
+        );
+        let files = extract_bytes(&archive, &FetchConfig::default()).unwrap();
+        let paths: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["src/main.rs", "README.md"]);
+        assert!(files.iter().all(
+            |file| file.indexed_commit.as_deref() == Some(COMMIT) && file.last_commit.is_none()
+        ));
+
+        let files = extract_bytes(
+            &zip(&[("repo-main/a.rs", b"a")], "not a commit"),
+            &FetchConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(files[0].indexed_commit, None);
+    }
+
+    #[test]
+    fn rejects_archives_with_too_many_entries() {
+        let archive = zip(&[("repo/a.rs", b"a"), ("repo/b.rs", b"b")], "");
+        let config = FetchConfig {
+            max_entries: 1,
+            ..FetchConfig::default()
+        };
+        assert!(matches!(
+            extract_bytes(&archive, &config),
+            Err(Error::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn skips_files_over_the_per_file_limit() {
+        let large = source(65 * 1024);
+        let archive = zip(&[("repo/large.rs", &large), ("repo/small.rs", b"a")], "");
+        let files = extract_bytes(&archive, &small_limits()).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "small.rs");
+    }
+
+    #[test]
+    fn rejects_archives_over_the_total_limit() {
+        let file = source(60 * 1024);
+        let archive = zip(&[("repo/a.rs", &file), ("repo/b.rs", &file)], "");
+        let error = extract_bytes(&archive, &small_limits()).unwrap_err();
+        assert!(matches!(error, Error::LimitExceeded(message) if message.contains("102400 bytes")));
+    }
+
+    #[test]
+    fn rejects_decompression_bombs() {
+        let zeros = vec![0u8; 4 * 1024 * 1024];
+        let archive = zip(&[("repo/zeros.rs", &zeros)], "");
+        let config = FetchConfig {
+            max_file_bytes: 8 * 1024 * 1024,
+            ..FetchConfig::default()
+        };
+        let error = extract_bytes(&archive, &config).unwrap_err();
+        assert!(
+            matches!(error, Error::LimitExceeded(message) if message.contains("the limit is 100x"))
+        );
+    }
+
+    #[test]
+    fn counts_what_is_decompressed_rather_than_declared() {
+        let file = source(200 * 1024);
+        //A single lying entry is cut off at the per-file limit and skipped
+        let mut archive = zip(&[("repo/a.rs", &file)], "");
+        declare_size(&mut archive, 10);
+        assert!(extract_bytes(&archive, &small_limits()).unwrap().is_empty());
+
+        //What was read before giving up still counts towards the total
+        let mut archive = zip(&[("repo/a.rs", &file), ("repo/b.rs", &file)], "");
+        declare_size(&mut archive, 10);
+        let error = extract_bytes(&archive, &small_limits()).unwrap_err();
+        assert!(matches!(error, Error::LimitExceeded(message) if message.contains("102400 bytes")));
+    }
+}
@@ -0,0 +1,87 @@
+use super::{encode, encode_path, RepositoryHost};
+use crate::{config::BitbucketConfig, github::Repository, prelude::*};
+use async_trait::async_trait;
+use base64::Engine;
+use reqwest::header::{self, HeaderName};
+
+pub struct Bitbucket {
+    config: BitbucketConfig,
+}
+
+impl Bitbucket {
+    pub fn new(config: &BitbucketConfig) -> Bitbucket {
+        Bitbucket {
+            config: config.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl RepositoryHost for Bitbucket {
+    fn name(&self) -> &'static str {
+        "Bitbucket"
+    }
+
+    fn archive_url(&self, repository: &Repository, _authenticated: bool) -> String {
+        format!(
+            "{}/{}/{}/get/{}.zip",
+            self.config.web_url,
+            repository.owner,
+            repository.name,
+            encode(&repository.branch)
+        )
+    }
+
+    fn raw_file_url(&self, repository: &Repository, path: &str) -> String {
+        format!(
+            "{}/src/{}/{}",
+            self.repository_api_url(repository),
+            encode(&repository.branch),
+            encode_path(path)
+        )
+    }
+
+    fn blob_url(
+        &self,
+        repository: &Repository,
+        path: &str,
+        (start, end): (usize, usize),
+    ) -> String {
+        format!(
+            "{}/{}/{}/src/{}/{}#lines-{start}:{end}",
+            self.config.web_url,
+            repository.owner,
+            repository.name,
+            encode(&repository.branch),
+            encode_path(path)
+        )
+    }
+
+    fn repository_api_url(&self, repository: &Repository) -> String {
+        format!(
+            "{}/2.0/repositories/{}/{}",
+            self.config.api_url, repository.owner, repository.name
+        )
+    }
+
+    //"username:app_password" credentials use basic auth, anything else is an access token
+    async fn auth_header(&self, repository: &Repository) -> Result<Option<(HeaderName, String)>> {
+        let token = match repository.token_or(&self.config.token) {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let value = if token.contains(':') {
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(token)
+            )
+        } else {
+            format!("Bearer {token}")
+        };
+        Ok(Some((header::AUTHORIZATION, value)))
+    }
+
+    fn parse_default_branch(&self, body: serde_json::Value) -> Option<String> {
+        Some(body.get("mainbranch")?.get("name")?.as_str()?.to_string())
+    }
+}
@@ -0,0 +1,80 @@
+use super::{encode, encode_path, is_commit, RepositoryHost};
+use crate::{config::GiteaConfig, github::Repository, prelude::*};
+use async_trait::async_trait;
+use reqwest::header::{self, HeaderName};
+
+//Forgejo keeps Gitea's API, so both are served by this provider
+pub struct Gitea {
+    config: GiteaConfig,
+}
+
+impl Gitea {
+    pub fn new(config: &GiteaConfig) -> Gitea {
+        Gitea {
+            config: config.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl RepositoryHost for Gitea {
+    fn name(&self) -> &'static str {
+        "Gitea"
+    }
+
+    fn archive_url(&self, repository: &Repository, _authenticated: bool) -> String {
+        format!(
+            "{}/archive/{}.zip",
+            self.repository_api_url(repository),
+            encode(&repository.branch)
+        )
+    }
+
+    fn raw_file_url(&self, repository: &Repository, path: &str) -> String {
+        format!(
+            "{}/raw/{}?ref={}",
+            self.repository_api_url(repository),
+            encode_path(path),
+            encode(&repository.branch)
+        )
+    }
+
+    //Web URLs say whether the ref is a commit or a branch
+    fn blob_url(
+        &self,
+        repository: &Repository,
+        path: &str,
+        (start, end): (usize, usize),
+    ) -> String {
+        let kind = if is_commit(&repository.branch) {
+            "commit"
+        } else {
+            "branch"
+        };
+        format!(
+            "{}/{}/{}/src/{kind}/{}/{}#L{start}-L{end}",
+            self.config.base_url,
+            repository.owner,
+            repository.name,
+            encode(&repository.branch),
+            encode_path(path)
+        )
+    }
+
+    fn repository_api_url(&self, repository: &Repository) -> String {
+        format!(
+            "{}/api/v1/repos/{}/{}",
+            self.config.base_url, repository.owner, repository.name
+        )
+    }
+
+    async fn auth_header(&self, repository: &Repository) -> Result<Option<(HeaderName, String)>> {
+        Ok(repository
+            .token_or(&self.config.token)
+            .map(|token| (header::AUTHORIZATION, format!("token {token}"))))
+    }
+
+    fn parse_default_branch(&self, body: serde_json::Value) -> Option<String> {
+        Some(body.get("default_branch")?.as_str()?.to_string())
+    }
+}
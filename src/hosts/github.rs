@@ -0,0 +1,200 @@
+use super::{check_status, encode, encode_path, rate_limit_wait, unix_time, RepositoryHost};
+use crate::{
+    config::{GitHubAppConfig, GitHubConfig},
+    github::Repository,
+    prelude::*,
+};
+use async_trait::async_trait;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::header::{self, HeaderName};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+//Installation tokens are valid for an hour, refresh them a little early
+const INSTALLATION_TOKEN_TTL: Duration = Duration::from_secs(50 * 60);
+
+pub struct GitHub {
+    client: reqwest::Client,
+    config: GitHubConfig,
+    app_key: Option<EncodingKey>,
+    installation_token: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Serialize)]
+struct AppClaims {
+    iat: u64,
+    exp: u64,
+    iss: String,
+}
+
+#[derive(Deserialize)]
+struct InstallationToken {
+    token: String,
+}
+
+impl GitHub {
+    pub fn new(client: reqwest::Client, config: &GitHubConfig) -> Result<GitHub> {
+        let app_key =
+            match &config.app {
+                Some(app) => {
+                    let pem = std::fs::read(&app.private_key_path).map_err(|e| {
+                        Error::Config(format!(
+                            "Unable to read {}: {}",
+                            app.private_key_path.display(),
+                            e
+                        ))
+                    })?;
+                    Some(EncodingKey::from_rsa_pem(&pem).map_err(|e| {
+                        Error::Config(format!("Invalid GitHub App private key: {e}"))
+                    })?)
+                }
+                None => None,
+            };
+        Ok(GitHub {
+            client,
+            config: config.clone(),
+            app_key,
+            installation_token: Mutex::new(None),
+        })
+    }
+
+    //A per-request token wins over the configured token, which wins over a GitHub App installation
+    async fn token(&self, repository: &Repository) -> Result<Option<String>> {
+        if let Some(token) = repository.token_or(&self.config.token) {
+            return Ok(Some(token.to_string()));
+        }
+        match (&self.config.app, &self.app_key) {
+            (Some(app), Some(key)) => Ok(Some(self.installation_token(app, key).await?)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn installation_token(&self, app: &GitHubAppConfig, key: &EncodingKey) -> Result<String> {
+        if let Some((token, issued_at)) = &*self.cached_installation_token() {
+            if issued_at.elapsed() < INSTALLATION_TOKEN_TTL {
+                return Ok(token.clone());
+            }
+        }
+
+        let now = unix_time();
+        let claims = AppClaims {
+            //Backdated to tolerate clock drift, as recommended by GitHub
+            iat: now.saturating_sub(60),
+            exp: now + 9 * 60,
+            iss: app.app_id.to_string(),
+        };
+        let jwt = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, key)
+            .map_err(|e| Error::Config(format!("Unable to sign GitHub App token: {e}")))?;
+        let url = format!(
+            "{}/app/installations/{}/access_tokens",
+            self.config.api_url, app.installation_id
+        );
+        let response = self
+            .client
+            .post(url)
+            .bearer_auth(jwt)
+            .header(header::ACCEPT, "application/vnd.github+json")
+            .send()
+            .await?;
+        if let Some(retry_after) = rate_limit_wait(&response) {
+            return Err(Error::RateLimited {
+                host: self.name().to_string(),
+                retry_after: Some(retry_after),
+            });
+        }
+        let InstallationToken { token } = check_status(self, response)?.json().await?;
+        *self.cached_installation_token() = Some((token.clone(), Instant::now()));
+        Ok(token)
+    }
+
+    fn cached_installation_token(&self) -> std::sync::MutexGuard<'_, Option<(String, Instant)>> {
+        self.installation_token
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[async_trait]
+impl RepositoryHost for GitHub {
+    fn name(&self) -> &'static str {
+        "GitHub"
+    }
+
+    //Archive links on github.com ignore credentials, the API zipball endpoint honours them
+    fn archive_url(&self, repository: &Repository, authenticated: bool) -> String {
+        let (owner, name, branch) = segments(repository);
+        if authenticated {
+            format!(
+                "{}/repos/{owner}/{name}/zipball/{branch}",
+                self.config.api_url
+            )
+        } else {
+            format!(
+                "{}/{owner}/{name}/archive/{branch}.zip",
+                self.config.web_url
+            )
+        }
+    }
+
+    fn raw_file_url(&self, repository: &Repository, path: &str) -> String {
+        let (owner, name, branch) = segments(repository);
+        format!(
+            "{}/{owner}/{name}/{branch}/{}",
+            self.config.raw_url,
+            encode_path(path)
+        )
+    }
+
+    fn blob_url(
+        &self,
+        repository: &Repository,
+        path: &str,
+        (start, end): (usize, usize),
+    ) -> String {
+        let (owner, name, branch) = segments(repository);
+        format!(
+            "{}/{owner}/{name}/blob/{branch}/{}#L{start}-L{end}",
+            self.config.web_url,
+            encode_path(path)
+        )
+    }
+
+    fn repository_api_url(&self, repository: &Repository) -> String {
+        let (owner, name, _) = segments(repository);
+        format!("{}/repos/{owner}/{name}", self.config.api_url)
+    }
+
+    async fn auth_header(&self, repository: &Repository) -> Result<Option<(HeaderName, String)>> {
+        Ok(self
+            .token(repository)
+            .await?
+            .map(|token| (header::AUTHORIZATION, format!("Bearer {token}"))))
+    }
+
+    //Owners and names are single path segments on GitHub, anything else would reach another endpoint
+    fn check_repository(&self, repository: &Repository) -> Result<()> {
+        for (kind, value) in [("owner", &repository.owner), ("name", &repository.name)] {
+            if value.is_empty() || value.contains('/') || value.contains("..") {
+                return Err(Error::InvalidRequest(format!(
+                    "Invalid GitHub repository {kind} {value:?}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_default_branch(&self, body: serde_json::Value) -> Option<String> {
+        Some(body.get("default_branch")?.as_str()?.to_string())
+    }
+}
+
+fn segments(repository: &Repository) -> (String, String, String) {
+    (
+        encode(&repository.owner),
+        encode(&repository.name),
+        encode(&repository.branch),
+    )
+}
@@ -0,0 +1,79 @@
+use super::{encode, encode_path, RepositoryHost};
+use crate::{config::GitLabConfig, github::Repository, prelude::*};
+use async_trait::async_trait;
+use reqwest::header::HeaderName;
+
+pub struct GitLab {
+    config: GitLabConfig,
+}
+
+impl GitLab {
+    pub fn new(config: &GitLabConfig) -> GitLab {
+        GitLab {
+            config: config.clone(),
+        }
+    }
+
+    //Projects are addressed by their URL-encoded "namespace/name" path, which allows nested groups
+    fn project_url(&self, repository: &Repository) -> String {
+        format!(
+            "{}/api/v4/projects/{}",
+            self.config.base_url,
+            encode(&format!("{}/{}", repository.owner, repository.name))
+        )
+    }
+}
+
+#[async_trait]
+impl RepositoryHost for GitLab {
+    fn name(&self) -> &'static str {
+        "GitLab"
+    }
+
+    fn archive_url(&self, repository: &Repository, _authenticated: bool) -> String {
+        format!(
+            "{}/repository/archive.zip?sha={}",
+            self.project_url(repository),
+            encode(&repository.branch)
+        )
+    }
+
+    fn raw_file_url(&self, repository: &Repository, path: &str) -> String {
+        format!(
+            "{}/repository/files/{}/raw?ref={}",
+            self.project_url(repository),
+            encode(path),
+            encode(&repository.branch)
+        )
+    }
+
+    fn blob_url(
+        &self,
+        repository: &Repository,
+        path: &str,
+        (start, end): (usize, usize),
+    ) -> String {
+        format!(
+            "{}/{}/{}/-/blob/{}/{}#L{start}-{end}",
+            self.config.base_url,
+            repository.owner,
+            repository.name,
+            encode(&repository.branch),
+            encode_path(path)
+        )
+    }
+
+    fn repository_api_url(&self, repository: &Repository) -> String {
+        self.project_url(repository)
+    }
+
+    async fn auth_header(&self, repository: &Repository) -> Result<Option<(HeaderName, String)>> {
+        Ok(repository
+            .token_or(&self.config.token)
+            .map(|token| (HeaderName::from_static("private-token"), token.to_string())))
+    }
+
+    fn parse_default_branch(&self, body: serde_json::Value) -> Option<String> {
+        Some(body.get("default_branch")?.as_str()?.to_string())
+    }
+}
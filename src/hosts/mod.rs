@@ -0,0 +1,750 @@
+mod archive;
+mod bitbucket;
+mod gitea;
+mod github;
+mod gitlab;
+use crate::config::{Config, FetchConfig};
+use crate::github::{File, Repository};
+use crate::metrics;
+use crate::prelude::*;
+use async_trait::async_trait;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{header::HeaderName, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub use bitbucket::*;
+pub use gitea::*;
+pub use github::*;
+pub use gitlab::*;
+
+//Used when a host signals a rate limit without saying when it resets
+const DEFAULT_RETRY_AFTER: u64 = 60;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Host {
+    #[default]
+    #[value(name = "github")]
+    GitHub,
+    #[value(name = "gitlab")]
+    GitLab,
+    #[value(name = "bitbucket")]
+    Bitbucket,
+    #[value(name = "gitea")]
+    Gitea,
+}
+
+#[async_trait]
+pub trait RepositoryHost: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn archive_url(&self, repository: &Repository, authenticated: bool) -> String;
+
+    fn raw_file_url(&self, repository: &Repository, path: &str) -> String;
+
+    //Web page showing the lines of a file, at the ref in repository.branch
+    fn blob_url(&self, repository: &Repository, path: &str, lines: (usize, usize)) -> String;
+
+    //API endpoint describing the repository, used for existence checks and the default branch
+    fn repository_api_url(&self, repository: &Repository) -> String;
+
+    async fn auth_header(&self, repository: &Repository) -> Result<Option<(HeaderName, String)>>;
+
+    //Rejects names the host's URLs can't hold before any request is made
+    fn check_repository(&self, _repository: &Repository) -> Result<()> {
+        Ok(())
+    }
+
+    fn parse_default_branch(&self, body: serde_json::Value) -> Option<String>;
+}
+
+pub struct Hosts {
+    client: reqwest::Client,
+    github: GitHub,
+    gitlab: GitLab,
+    bitbucket: Bitbucket,
+    gitea: Gitea,
+    max_retries: u32,
+    max_retry_wait_secs: u64,
+}
+
+impl Hosts {
+    pub fn new(config: &Config) -> Result<Hosts> {
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("onn/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Hosts {
+            github: GitHub::new(client.clone(), &config.github)?,
+            gitlab: GitLab::new(&config.gitlab),
+            bitbucket: Bitbucket::new(&config.bitbucket),
+            gitea: Gitea::new(&config.gitea),
+            client,
+            max_retries: config.fetch.max_retries,
+            max_retry_wait_secs: config.fetch.max_retry_wait_secs,
+        })
+    }
+
+    fn provider(&self, host: Host) -> &dyn RepositoryHost {
+        match host {
+            Host::GitHub => &self.github,
+            Host::GitLab => &self.gitlab,
+            Host::Bitbucket => &self.bitbucket,
+            Host::Gitea => &self.gitea,
+        }
+    }
+
+    //Fills in the default branch when the request didn't name one
+    pub async fn resolve(&self, mut repository: Repository) -> Result<Repository> {
+        if !repository.branch.is_empty() {
+            return Ok(repository);
+        }
+        let provider = self.provider(repository.host);
+        let url = provider.repository_api_url(&repository);
+        let response = self.get(provider, &url, &repository).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(Error::RepositoryNotFound(repository.full_name()));
+        }
+        let body = check_status(provider, response)?.json().await?;
+        repository.branch = provider.parse_default_branch(body).ok_or_else(|| {
+            Error::RepositoryNotFound(format!("{} (no default branch)", repository.full_name()))
+        })?;
+        Ok(repository)
+    }
+
+    #[tracing::instrument(
+        name = "fetch",
+        skip_all,
+        fields(repo_id = %repository.to_string(), host = ?repository.host)
+    )]
+    pub async fn fetch_repo_files(
+        &self,
+        repository: &Repository,
+        fetch_config: &FetchConfig,
+    ) -> Result<Vec<File>> {
+        let provider = self.provider(repository.host);
+        let timer = metrics::ARCHIVE_FETCH_DURATION
+            .with_label_values(&[provider.name()])
+            .start_timer();
+        let authenticated = provider.auth_header(repository).await?.is_some();
+        let url = provider.archive_url(repository, authenticated);
+        let response = self.get(provider, &url, repository).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(self.missing_repo_or_branch(provider, repository).await);
+        }
+        let response = check_status(provider, response)?;
+        let archive = archive::download(response, fetch_config, provider.name()).await?;
+        let fetch_config = fetch_config.clone();
+        let span = tracing::info_span!("filter");
+        let files = actix_web::rt::task::spawn_blocking(move || {
+            span.in_scope(|| archive::extract(archive, &fetch_config))
+        })
+        .await
+        .map_err(|e| Error::Archive(e.to_string()))??;
+        timer.observe_duration();
+        Ok(files)
+    }
+
+    //Pinned to the commit when it's known, so the link keeps pointing at the lines that were read
+    pub fn permalink(
+        &self,
+        repository: &Repository,
+        commit: Option<&str>,
+        path: &str,
+        lines: (usize, usize),
+    ) -> String {
+        let at_ref = Repository {
+            branch: commit.unwrap_or(&repository.branch).to_string(),
+            ..repository.clone()
+        };
+        self.provider(repository.host)
+            .blob_url(&at_ref, path, lines)
+    }
+
+    pub async fn fetch_file_content(&self, repository: &Repository, path: &str) -> Result<String> {
+        let provider = self.provider(repository.host);
+        let url = provider.raw_file_url(repository, path);
+        let response = self.get(provider, &url, repository).await?;
+        let content = check_status(provider, response)?.text().await?;
+        Ok(content)
+    }
+
+    //Fetches individual files at a commit, skipping ones that are gone or over the per-file limit
+    pub async fn fetch_files(
+        &self,
+        repository: &Repository,
+        commit: &str,
+        paths: &[String],
+        fetch_config: &FetchConfig,
+    ) -> Result<Vec<File>> {
+        let provider = self.provider(repository.host);
+        let at_commit = Repository {
+            branch: commit.to_string(),
+            ..repository.clone()
+        };
+        let mut files: Vec<File> = Vec::new();
+        for path in paths {
+            let url = provider.raw_file_url(&at_commit, path);
+            let response = self.get(provider, &url, &at_commit).await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                tracing::debug!(%path, "Skipping file missing at commit");
+                continue;
+            }
+            let bytes = check_status(provider, response)?.bytes().await?;
+            if bytes.len() as u64 > fetch_config.max_file_bytes {
+                tracing::debug!(%path, size = bytes.len(), "Skipping file over the per-file limit");
+                continue;
+            }
+            if let Some(file) = File::from_bytes(path.clone(), &bytes, None) {
+                files.push(File {
+                    indexed_commit: Some(commit.to_string()),
+                    ..file
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    async fn missing_repo_or_branch(
+        &self,
+        provider: &dyn RepositoryHost,
+        repository: &Repository,
+    ) -> Error {
+        let url = provider.repository_api_url(repository);
+        match self.get(provider, &url, repository).await {
+            Ok(response) if response.status().is_success() => Error::BranchNotFound {
+                repository: repository.full_name(),
+                branch: repository.branch.clone(),
+            },
+            Ok(response) if response.status() == StatusCode::NOT_FOUND => {
+                Error::RepositoryNotFound(repository.full_name())
+            }
+            Ok(response) => match check_status(provider, response) {
+                Err(e) => e,
+                Ok(_) => Error::RepositoryNotFound(repository.full_name()),
+            },
+            Err(e) => e,
+        }
+    }
+
+    //Waits out short rate limits, anything longer is reported to the caller with its retry time
+    async fn get(
+        &self,
+        provider: &dyn RepositoryHost,
+        url: &str,
+        repository: &Repository,
+    ) -> Result<Response> {
+        provider.check_repository(repository)?;
+        let auth_header = provider.auth_header(repository).await?;
+        let mut attempt = 0;
+        loop {
+            let mut request = self.client.get(url);
+            if let Some((name, value)) = &auth_header {
+                request = request.header(name.clone(), value.as_str());
+            }
+            let response = request.send().await?;
+            match rate_limit_wait(&response) {
+                Some(wait) if attempt < self.max_retries && wait <= self.max_retry_wait_secs => {
+                    attempt += 1;
+                    actix_web::rt::time::sleep(Duration::from_secs(wait)).await;
+                }
+                Some(wait) => {
+                    return Err(Error::RateLimited {
+                        host: provider.name().to_string(),
+                        retry_after: Some(wait),
+                    })
+                }
+                None => return Ok(response),
+            }
+        }
+    }
+}
+
+//Covers 429s as well as GitHub's 403 with x-ratelimit-remaining: 0, and GitLab's RateLimit-* headers
+fn rate_limit_wait(response: &Response) -> Option<u64> {
+    let headers = response.headers();
+    let header_value = |names: &[&str]| -> Option<u64> {
+        names
+            .iter()
+            .find_map(|name| headers.get(*name)?.to_str().ok()?.parse().ok())
+    };
+    let status = response.status();
+    let exhausted = header_value(&["x-ratelimit-remaining", "ratelimit-remaining"]) == Some(0);
+    let retry_after = header_value(&["retry-after"]);
+
+    if status != StatusCode::TOO_MANY_REQUESTS
+        && !(status == StatusCode::FORBIDDEN && (exhausted || retry_after.is_some()))
+    {
+        return None;
+    }
+    Some(
+        retry_after
+            .or_else(|| {
+                header_value(&["x-ratelimit-reset", "ratelimit-reset"])
+                    .map(|reset| reset.saturating_sub(unix_time()))
+            })
+            .unwrap_or(DEFAULT_RETRY_AFTER),
+    )
+}
+
+fn check_status(provider: &dyn RepositoryHost, response: Response) -> Result<Response> {
+    match response.status() {
+        StatusCode::UNAUTHORIZED => Err(Error::Unauthorized(provider.name().to_string())),
+        StatusCode::FORBIDDEN => Err(Error::Forbidden(provider.name().to_string())),
+        _ => Ok(response.error_for_status()?),
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+fn is_commit(git_ref: &str) -> bool {
+    git_ref.len() == 40 && git_ref.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/').map(encode).collect::<Vec<_>>().join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        BitbucketConfig, GitHubAppConfig, GitHubConfig, GitLabConfig, GiteaConfig,
+    };
+    use crate::github::Secret;
+    use actix_web::{http::header::HeaderMap, web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::{
+        io::{Cursor, Write},
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+
+    //Path and query, status, headers and body
+    type Response = (String, u16, Vec<(&'static str, String)>, Vec<u8>);
+
+    //Answers requests from a table keyed by path and query, recording every request it gets.
+    //Paths are compared as sent, so reserved characters appear percent-encoded
+    struct Mock {
+        responses: Vec<Response>,
+        requests: Mutex<Vec<(String, HeaderMap)>>,
+    }
+
+    impl Mock {
+        fn new() -> Mock {
+            Mock {
+                responses: Vec::new(),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn respond(mut self, uri: &str, status: u16, body: impl Into<Vec<u8>>) -> Mock {
+            self.responses
+                .push((uri.to_string(), status, Vec::new(), body.into()));
+            self
+        }
+
+        fn respond_with_header(
+            mut self,
+            uri: &str,
+            status: u16,
+            header: (&'static str, &str),
+        ) -> Mock {
+            self.responses.push((
+                uri.to_string(),
+                status,
+                vec![(header.0, header.1.to_string())],
+                Vec::new(),
+            ));
+            self
+        }
+
+        //Serves until the test's runtime stops, returns the base URL
+        fn start(self) -> (String, Arc<Mock>) {
+            let mock = Arc::new(self);
+            let data = web::Data::from(mock.clone());
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(data.clone())
+                    .default_service(web::to(answer))
+            })
+            .workers(1)
+            .disable_signals()
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+            let address = server.addrs()[0];
+            actix_web::rt::spawn(server.run());
+            (format!("http://{address}"), mock)
+        }
+
+        fn requests(&self) -> Vec<(String, HeaderMap)> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    //Responses are used in order, so a URI listed twice answers differently the second time
+    async fn answer(req: HttpRequest, mock: web::Data<Mock>) -> HttpResponse {
+        let uri = req.uri().to_string();
+        let mut requests = mock.requests.lock().unwrap();
+        let seen = requests.iter().filter(|(seen, _)| *seen == uri).count();
+        requests.push((uri.clone(), req.headers().clone()));
+        match mock
+            .responses
+            .iter()
+            .filter(|(expected, ..)| *expected == uri)
+            .nth(seen)
+        {
+            Some((_, status, headers, body)) => {
+                let mut response =
+                    HttpResponse::build(actix_web::http::StatusCode::from_u16(*status).unwrap());
+                for (name, value) in headers {
+                    response.insert_header((*name, value.as_str()));
+                }
+                response.body(body.clone())
+            }
+            None => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    fn archive(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn repository(host: Host, branch: &str) -> Repository {
+        Repository {
+            host,
+            owner: "group".into(),
+            name: "repo".into(),
+            branch: branch.into(),
+            token: None,
+            tenant: None,
+        }
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    #[actix_web::test]
+    async fn gitlab_resolves_and_downloads_with_its_token_header() {
+        let (url, mock) = Mock::new()
+            .respond(
+                "/api/v4/projects/group%2Frepo",
+                200,
+                r#"{"default_branch": "main"}"#,
+            )
+            .respond(
+                "/api/v4/projects/group%2Frepo/repository/archive.zip?sha=main",
+                200,
+                archive(&[("repo-main/src/lib.rs", "pub fn lib() {}")]),
+            )
+            .start();
+        let hosts = Hosts::new(&Config {
+            gitlab: GitLabConfig {
+                base_url: url,
+                token: Some("glpat-token".into()),
+            },
+            ..Config::default()
+        })
+        .unwrap();
+
+        let repository = hosts.resolve(repository(Host::GitLab, "")).await.unwrap();
+        assert_eq!(repository.branch, "main");
+        let files = hosts
+            .fetch_repo_files(&repository, &FetchConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "src/lib.rs");
+        for (_, headers) in mock.requests() {
+            assert_eq!(header(&headers, "private-token"), Some("glpat-token"));
+        }
+    }
+
+    #[actix_web::test]
+    async fn gitea_tells_missing_branches_from_missing_repositories() {
+        let (url, mock) = Mock::new()
+            .respond("/api/v1/repos/group/repo", 200, "{}")
+            .start();
+        let hosts = Hosts::new(&Config {
+            gitea: GiteaConfig {
+                base_url: url,
+                token: Some("gitea-token".into()),
+            },
+            ..Config::default()
+        })
+        .unwrap();
+
+        let error = hosts
+            .fetch_repo_files(&repository(Host::Gitea, "gone"), &FetchConfig::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::BranchNotFound { branch, .. } if branch == "gone"));
+        let requests = mock.requests();
+        assert_eq!(requests[0].0, "/api/v1/repos/group/repo/archive/gone.zip");
+        assert_eq!(
+            header(&requests[0].1, "authorization"),
+            Some("token gitea-token")
+        );
+
+        let other = Repository {
+            name: "other".into(),
+            ..repository(Host::Gitea, "main")
+        };
+        assert!(matches!(
+            hosts
+                .fetch_repo_files(&other, &FetchConfig::default())
+                .await,
+            Err(Error::RepositoryNotFound(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn bitbucket_uses_basic_auth_for_app_passwords() {
+        let (url, mock) = Mock::new()
+            .respond("/2.0/repositories/group/repo", 401, "")
+            .respond(
+                "/2.0/repositories/group/repo/src/main/README%2Emd",
+                200,
+                "# Repo",
+            )
+            .start();
+        let hosts = Hosts::new(&Config {
+            bitbucket: BitbucketConfig {
+                api_url: url.clone(),
+                web_url: url,
+                token: Some("user:app-password".into()),
+            },
+            ..Config::default()
+        })
+        .unwrap();
+
+        assert!(matches!(
+            hosts.resolve(repository(Host::Bitbucket, "")).await,
+            Err(Error::Unauthorized(host)) if host == "Bitbucket"
+        ));
+        let content = hosts
+            .fetch_file_content(&repository(Host::Bitbucket, "main"), "README.md")
+            .await
+            .unwrap();
+        assert_eq!(content, "# Repo");
+        //base64 of "user:app-password"
+        assert_eq!(
+            header(&mock.requests()[1].1, "authorization"),
+            Some("Basic dXNlcjphcHAtcGFzc3dvcmQ=")
+        );
+    }
+
+    #[actix_web::test]
+    async fn waits_out_short_rate_limits_only() {
+        let (url, _) = Mock::new()
+            .respond_with_header(
+                "/api/v1/repos/group/repo/raw/a%2Ers?ref=main",
+                429,
+                ("retry-after", "0"),
+            )
+            .respond("/api/v1/repos/group/repo/raw/a%2Ers?ref=main", 200, "a")
+            .respond_with_header(
+                "/api/v1/repos/group/repo/raw/b%2Ers?ref=main",
+                429,
+                ("retry-after", "3600"),
+            )
+            .start();
+        let hosts = Hosts::new(&Config {
+            gitea: GiteaConfig {
+                base_url: url,
+                token: None,
+            },
+            ..Config::default()
+        })
+        .unwrap();
+        let repository = repository(Host::Gitea, "main");
+
+        assert_eq!(
+            hosts.fetch_file_content(&repository, "a.rs").await.unwrap(),
+            "a"
+        );
+        assert!(matches!(
+            hosts.fetch_file_content(&repository, "b.rs").await,
+            Err(Error::RateLimited {
+                retry_after: Some(3600),
+                ..
+            })
+        ));
+    }
+
+    fn github(url: &str, token: Option<&str>, app: Option<GitHubAppConfig>) -> Hosts {
+        Hosts::new(&Config {
+            github: GitHubConfig {
+                token: token.map(str::to_string),
+                app,
+                api_url: url.to_string(),
+                web_url: url.to_string(),
+                raw_url: url.to_string(),
+                webhook_secret: None,
+            },
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn github_prefers_the_request_token_over_the_configured_one() {
+        let (url, mock) = Mock::new()
+            .respond("/repos/group/repo", 200, r#"{"default_branch": "main"}"#)
+            .respond("/repos/group/repo", 200, r#"{"default_branch": "main"}"#)
+            .start();
+        let hosts = github(&url, Some("server-token"), None);
+
+        let with_token = Repository {
+            token: Some(serde_json::from_str::<Secret>(r#""request-token""#).unwrap()),
+            ..repository(Host::GitHub, "")
+        };
+        hosts.resolve(with_token).await.unwrap();
+        hosts.resolve(repository(Host::GitHub, "")).await.unwrap();
+        let requests = mock.requests();
+        assert_eq!(
+            header(&requests[0].1, "authorization"),
+            Some("Bearer request-token")
+        );
+        assert_eq!(
+            header(&requests[1].1, "authorization"),
+            Some("Bearer server-token")
+        );
+    }
+
+    #[actix_web::test]
+    async fn github_exchanges_and_caches_app_installation_tokens() {
+        let (url, mock) = Mock::new()
+            .respond(
+                "/app/installations/7/access_tokens",
+                201,
+                r#"{"token": "ghs_installation"}"#,
+            )
+            .respond("/repos/group/repo", 200, r#"{"default_branch": "main"}"#)
+            .respond("/group/repo/main/README%2Emd", 200, "# Repo")
+            .start();
+        let app = GitHubAppConfig {
+            app_id: 42,
+            installation_id: 7,
+            private_key_path: Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("fixtures/github/app-private-key.pem"),
+        };
+        let hosts = github(&url, None, Some(app));
+
+        let repository = hosts.resolve(repository(Host::GitHub, "")).await.unwrap();
+        let content = hosts
+            .fetch_file_content(&repository, "README.md")
+            .await
+            .unwrap();
+        assert_eq!(content, "# Repo");
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 3, "the installation token is reused");
+        assert_eq!(requests[0].0, "/app/installations/7/access_tokens");
+        let jwt = header(&requests[0].1, "authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap();
+        let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256);
+        validation.insecure_disable_signature_validation();
+        let claims = jsonwebtoken::decode::<serde_json::Value>(
+            jwt,
+            &jsonwebtoken::DecodingKey::from_secret(&[]),
+            &validation,
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims["iss"], "42");
+        for (_, headers) in &requests[1..] {
+            assert_eq!(
+                header(headers, "authorization"),
+                Some("Bearer ghs_installation")
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn github_maps_error_statuses() {
+        let (url, _) = Mock::new()
+            .respond("/repos/group/unauthorized", 401, "")
+            .respond("/repos/group/forbidden", 403, "")
+            .respond_with_header("/repos/group/limited", 403, ("x-ratelimit-remaining", "0"))
+            .respond_with_header("/repos/group/throttled", 429, ("retry-after", "3600"))
+            .start();
+        let hosts = github(&url, None, None);
+        let resolve = |name: &str| {
+            hosts.resolve(Repository {
+                name: name.into(),
+                ..repository(Host::GitHub, "")
+            })
+        };
+
+        assert!(matches!(
+            resolve("unauthorized").await,
+            Err(Error::Unauthorized(host)) if host == "GitHub"
+        ));
+        assert!(matches!(
+            resolve("forbidden").await,
+            Err(Error::Forbidden(host)) if host == "GitHub"
+        ));
+        assert!(matches!(
+            resolve("missing").await,
+            Err(Error::RepositoryNotFound(_))
+        ));
+        assert!(matches!(
+            resolve("limited").await,
+            Err(Error::RateLimited {
+                retry_after: Some(DEFAULT_RETRY_AFTER),
+                ..
+            })
+        ));
+        assert!(matches!(
+            resolve("throttled").await,
+            Err(Error::RateLimited {
+                retry_after: Some(3600),
+                ..
+            })
+        ));
+    }
+
+    #[actix_web::test]
+    async fn github_encodes_segments_and_rejects_other_paths() {
+        let (url, mock) = Mock::new()
+            .respond("/group/repo/feature%2Fx/src/a%2Ers", 200, "a")
+            .start();
+        let hosts = github(&url, None, None);
+
+        let content = hosts
+            .fetch_file_content(&repository(Host::GitHub, "feature/x"), "src/a.rs")
+            .await
+            .unwrap();
+        assert_eq!(content, "a");
+
+        for (owner, name) in [("group/x", "repo"), ("group", "../repo"), ("group", "a..b")] {
+            let repository = Repository {
+                owner: owner.into(),
+                name: name.into(),
+                ..repository(Host::GitHub, "main")
+            };
+            assert!(matches!(
+                hosts.fetch_file_content(&repository, "a.rs").await,
+                Err(Error::InvalidRequest(_))
+            ));
+        }
+        assert_eq!(mock.requests().len(), 1);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{db::RepositoryEmbeddingsDB, prelude::*};
+use actix_web::dev::ServerHandle;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+struct Job {
+    repo_id: String,
+    //Set once the job has started creating its collection
+    writing: bool,
+}
+
+//Tracks in-flight indexing jobs so shutdown can wait for them instead of cutting them off
+#[derive(Default)]
+pub struct Jobs {
+    active: Mutex<HashMap<Uuid, Job>>,
+    draining: AtomicBool,
+}
+
+pub struct JobGuard {
+    jobs: Arc<Jobs>,
+    id: Uuid,
+}
+
+impl Jobs {
+    pub fn start(self: &Arc<Self>, repo_id: &str) -> Result<JobGuard> {
+        let mut active = self.lock();
+        //Checked under the lock so a job can't slip in after draining started
+        if self.is_draining() {
+            return Err(Error::ShuttingDown);
+        }
+        let id = Uuid::new_v4();
+        active.insert(
+            id,
+            Job {
+                repo_id: repo_id.to_string(),
+                writing: false,
+            },
+        );
+        Ok(JobGuard {
+            jobs: self.clone(),
+            id,
+        })
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn active(&self) -> usize {
+        self.lock().len()
+    }
+
+    //Stops accepting jobs and waits for the running ones, returning the repositories still being written
+    pub async fn drain(&self, timeout: Duration) -> Vec<String> {
+        {
+            let _active = self.lock();
+            self.draining.store(true, Ordering::SeqCst);
+        }
+        let deadline = Instant::now() + timeout;
+        while self.active() > 0 && Instant::now() < deadline {
+            actix_web::rt::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        self.lock()
+            .values()
+            .filter(|job| job.writing)
+            .map(|job| job.repo_id.clone())
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, Job>> {
+        self.active.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl JobGuard {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn writing(&self) {
+        if let Some(job) = self.jobs.lock().get_mut(&self.id) {
+            job.writing = true;
+        }
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        self.jobs.lock().remove(&self.id);
+    }
+}
+
+//Replaces actix's own signal handling so jobs are drained before the workers stop
+pub async fn shutdown_on_signal<D: RepositoryEmbeddingsDB>(
+    server: ServerHandle,
+    jobs: Arc<Jobs>,
+    db: Arc<D>,
+    timeout: Duration,
+) {
+    wait_for_signal().await;
+    tracing::info!(
+        active = jobs.active(),
+        "Shutting down, draining indexing jobs"
+    );
+    for repo_id in jobs.drain(timeout).await {
+        //Unfinished versions were never served, they'd only take up space
+        match db.discard_unfinished(&repo_id).await {
+            Ok(()) => tracing::warn!(%repo_id, "Discarded interrupted index build"),
+            Err(e) => {
+                tracing::error!(%repo_id, error = %e, "Unable to discard interrupted index build")
+            }
+        }
+    }
+    server.stop(true).await;
+}
+
+async fn wait_for_signal() {
+    let interrupt = Box::pin(async {
+        if let Err(e) = actix_web::rt::signal::ctrl_c().await {
+            tracing::error!(error = %e, "Unable to listen for Ctrl-C");
+            futures::future::pending::<()>().await;
+        }
+    });
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                let terminate = Box::pin(async move {
+                    terminate.recv().await;
+                });
+                futures::future::select(interrupt, terminate).await;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Unable to listen for SIGTERM");
+                interrupt.await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    interrupt.await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_are_tracked_until_their_guard_drops() {
+        let jobs = Arc::new(Jobs::default());
+        let first = jobs.start("owner-repo-main").unwrap();
+        let second = jobs.start("owner-repo-main").unwrap();
+        assert_ne!(first.id(), second.id());
+        assert_eq!(jobs.active(), 2);
+
+        first.writing();
+        assert!(jobs.lock()[&first.id()].writing);
+        assert!(!jobs.lock()[&second.id()].writing);
+
+        drop(first);
+        assert_eq!(jobs.active(), 1);
+        drop(second);
+        assert_eq!(jobs.active(), 0);
+    }
+
+    #[actix_web::test]
+    async fn draining_refuses_new_jobs() {
+        let jobs = Arc::new(Jobs::default());
+        assert!(!jobs.is_draining());
+        assert!(jobs.drain(Duration::from_secs(5)).await.is_empty());
+        assert!(jobs.is_draining());
+        assert!(matches!(
+            jobs.start("owner-repo-main"),
+            Err(Error::ShuttingDown)
+        ));
+        assert_eq!(jobs.active(), 0);
+    }
+
+    #[actix_web::test]
+    async fn draining_waits_for_running_jobs() {
+        let jobs = Arc::new(Jobs::default());
+        let job = jobs.start("owner-repo-main").unwrap();
+        job.writing();
+        actix_web::rt::spawn(async move {
+            actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+            drop(job);
+        });
+
+        let started = Instant::now();
+        assert!(jobs.drain(Duration::from_secs(5)).await.is_empty());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(jobs.active(), 0);
+    }
+
+    #[actix_web::test]
+    async fn draining_reports_jobs_still_writing_at_the_timeout() {
+        let jobs = Arc::new(Jobs::default());
+        let writing = jobs.start("owner-writing-main").unwrap();
+        writing.writing();
+        //Jobs that haven't created a collection yet leave nothing to clean up
+        let _fetching = jobs.start("owner-fetching-main").unwrap();
+
+        let unfinished = jobs.drain(Duration::from_millis(10)).await;
+        assert_eq!(unfinished, vec!["owner-writing-main".to_string()]);
+        assert_eq!(jobs.active(), 2);
+    }
+}
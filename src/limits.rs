@@ -0,0 +1,274 @@
+use crate::{config::RateLimitConfig, prelude::*};
+use actix_web::dev::ServiceRequest;
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+//Idle buckets are dropped past this many entries so per-IP state can't grow without bound
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets<K> {
+    //Tokens per second
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Eq + Hash> Buckets<K> {
+    fn new(per_minute: u32, burst: u32) -> Option<Buckets<K>> {
+        (per_minute > 0).then(|| Buckets {
+            rate: per_minute as f64 / 60.0,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    //Takes a token, or returns how many seconds until one is available
+    fn take(&self, key: K) -> std::result::Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            let (rate, burst) = (self.rate, self.burst);
+            //A bucket that would have refilled completely holds no state worth keeping
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = (bucket.tokens
+            + now.duration_since(bucket.updated).as_secs_f64() * self.rate)
+            .min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil() as u64)
+        }
+    }
+}
+
+pub struct Concurrency {
+    name: &'static str,
+    max: usize,
+    active: Arc<AtomicUsize>,
+    retry_after_secs: u64,
+}
+
+//Releases the slot when dropped
+pub struct Permit {
+    active: Arc<AtomicUsize>,
+}
+
+impl Concurrency {
+    fn new(name: &'static str, max: usize, retry_after_secs: u64) -> Concurrency {
+        Concurrency {
+            name,
+            max,
+            active: Arc::new(AtomicUsize::new(0)),
+            retry_after_secs,
+        }
+    }
+
+    //Rejects instead of queueing, a client retrying later is cheaper than a request holding a worker
+    pub fn acquire(&self) -> Result<Permit> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (self.max == 0 || active < self.max).then_some(active + 1)
+            })
+            .map_err(|_| Error::TooManyRequests {
+                message: format!("Too many {} are running, try again later", self.name),
+                retry_after: self.retry_after_secs,
+            })?;
+        Ok(Permit {
+            active: self.active.clone(),
+        })
+    }
+}
+
+impl Concurrency {
+    //For background work, which has no client to send away
+    pub async fn wait(&self) -> Permit {
+        loop {
+            if let Ok(permit) = self.acquire() {
+                return permit;
+            }
+            actix_web::rt::time::sleep(WAIT_POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct RateLimits {
+    keys: Option<Buckets<[u8; 32]>>,
+    ips: Option<Buckets<IpAddr>>,
+    pub embeddings: Concurrency,
+    pub queries: Concurrency,
+}
+
+impl RateLimits {
+    pub fn new(config: &RateLimitConfig) -> RateLimits {
+        RateLimits {
+            keys: Buckets::new(config.per_key_per_minute, config.per_key_burst),
+            ips: Buckets::new(config.per_ip_per_minute, config.per_ip_burst),
+            embeddings: Concurrency::new(
+                "indexing jobs",
+                config.max_concurrent_embeddings,
+                config.busy_retry_after_secs,
+            ),
+            queries: Concurrency::new(
+                "queries",
+                config.max_concurrent_queries,
+                config.busy_retry_after_secs,
+            ),
+        }
+    }
+
+    //Checked before authentication so keys can't be guessed at full speed
+    pub fn check_ip(&self, ip: Option<IpAddr>) -> Result<()> {
+        match (&self.ips, ip) {
+            (Some(buckets), Some(ip)) => {
+                buckets
+                    .take(ip)
+                    .map_err(|retry_after| Error::TooManyRequests {
+                        message: format!("Rate limit exceeded for {ip}"),
+                        retry_after,
+                    })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_key(&self, key: Option<[u8; 32]>) -> Result<()> {
+        match (&self.keys, key) {
+            (Some(buckets), Some(key)) => {
+                buckets
+                    .take(key)
+                    .map_err(|retry_after| Error::TooManyRequests {
+                        message: "Rate limit exceeded for this API key".into(),
+                        retry_after,
+                    })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+//Forwarded headers are only honoured behind a trusted proxy, clients could spoof them otherwise
+pub fn client_ip(req: &ServiceRequest, trust_forwarded_for: bool) -> Option<IpAddr> {
+    if trust_forwarded_for {
+        let info = req.connection_info();
+        let address = info.realip_remote_addr()?;
+        address.parse::<IpAddr>().ok().or_else(|| {
+            address
+                .parse::<SocketAddr>()
+                .ok()
+                .map(|address| address.ip())
+        })
+    } else {
+        req.peer_addr().map(|address| address.ip())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_allow_a_burst_then_ask_to_wait() {
+        let buckets = Buckets::new(6, 3).unwrap();
+        for _ in 0..3 {
+            assert_eq!(buckets.take("key"), Ok(()));
+        }
+        //6 a minute refills a token every 10 seconds
+        assert_eq!(buckets.take("key"), Err(10));
+        //Other keys have their own bucket
+        assert_eq!(buckets.take("other"), Ok(()));
+    }
+
+    #[test]
+    fn buckets_refill_over_time_up_to_the_burst() {
+        let buckets = Buckets::new(60, 2).unwrap();
+        assert_eq!(buckets.take("key"), Ok(()));
+        assert_eq!(buckets.take("key"), Ok(()));
+        assert!(buckets.take("key").is_err());
+        let an_hour_ago = Instant::now()
+            .checked_sub(Duration::from_secs(3600))
+            .unwrap();
+        buckets
+            .buckets
+            .lock()
+            .unwrap()
+            .get_mut("key")
+            .unwrap()
+            .updated = an_hour_ago;
+        assert_eq!(buckets.take("key"), Ok(()));
+        assert_eq!(buckets.take("key"), Ok(()));
+        assert!(buckets.take("key").is_err());
+    }
+
+    #[test]
+    fn zero_rates_disable_limits() {
+        assert!(Buckets::<&str>::new(0, 10).is_none());
+        let limits = RateLimits::new(&RateLimitConfig {
+            per_ip_per_minute: 0,
+            per_key_per_minute: 1,
+            per_key_burst: 0,
+            ..RateLimitConfig::default()
+        });
+        let ip = Some(IpAddr::from([127, 0, 0, 1]));
+        for _ in 0..100 {
+            assert!(limits.check_ip(ip).is_ok());
+        }
+        //A burst of 0 still lets one request through
+        assert!(limits.check_key(Some([0; 32])).is_ok());
+        assert!(matches!(
+            limits.check_key(Some([0; 32])),
+            Err(Error::TooManyRequests {
+                retry_after: 60,
+                ..
+            })
+        ));
+        assert!(limits.check_key(None).is_ok());
+    }
+
+    #[test]
+    fn concurrency_releases_slots_on_drop() {
+        let concurrency = Concurrency::new("jobs", 2, 30);
+        let first = concurrency.acquire().unwrap();
+        let _second = concurrency.acquire().unwrap();
+        assert!(matches!(
+            concurrency.acquire(),
+            Err(Error::TooManyRequests {
+                retry_after: 30,
+                ..
+            })
+        ));
+        drop(first);
+        assert!(concurrency.acquire().is_ok());
+
+        let unlimited = Concurrency::new("jobs", 0, 30);
+        let _permits: Vec<Permit> = (0..100).map(|_| unlimited.acquire().unwrap()).collect();
+    }
+}
@@ -0,0 +1,290 @@
+use super::{
+    check_response, ChatBackend, ChatMessage, ChatRequest, ChatResponse, FunctionCall, Role,
+    ToolCall, ToolKind, Usage,
+};
+use crate::{config::LlmConfig, prelude::*};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const DEFAULT_BASE_URL: &str = "https://api.anthropic.com";
+const API_VERSION: &str = "2023-06-01";
+//The messages API requires a limit, unlike chat completions
+const DEFAULT_MAX_TOKENS: u32 = 1024;
+
+pub struct Anthropic {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+}
+
+#[derive(Serialize)]
+struct Message {
+    role: &'static str,
+    content: Vec<Value>,
+}
+
+#[derive(Serialize)]
+struct Tool<'a> {
+    name: &'a str,
+    description: &'a str,
+    input_schema: Value,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+    content: Vec<ContentBlock>,
+    usage: MessagesUsage,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct MessagesUsage {
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+impl Anthropic {
+    pub fn new(client: reqwest::Client, config: &LlmConfig) -> Anthropic {
+        Anthropic {
+            client,
+            base_url: config
+                .base_url
+                .as_deref()
+                .unwrap_or(DEFAULT_BASE_URL)
+                .trim_end_matches('/')
+                .to_string(),
+            api_key: config.api_key.clone(),
+        }
+    }
+}
+
+fn messages(messages: &[ChatMessage]) -> (Option<String>, Vec<Message>) {
+    let mut system: Vec<&str> = Vec::new();
+    let mut converted: Vec<Message> = Vec::new();
+    for message in messages {
+        let content = message.content.as_deref().unwrap_or_default();
+        let (role, blocks) = match message.role {
+            Role::System => {
+                system.push(content);
+                continue;
+            }
+            Role::User => ("user", vec![json!({ "type": "text", "text": content })]),
+            Role::Assistant => {
+                let mut blocks = Vec::new();
+                if !content.is_empty() {
+                    blocks.push(json!({ "type": "text", "text": content }));
+                }
+                for call in &message.tool_calls {
+                    let input: Value = serde_json::from_str(&call.function.arguments)
+                        .unwrap_or_else(|_| json!({}));
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": call.id,
+                        "name": call.function.name,
+                        "input": input,
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            Role::Tool => (
+                "user",
+                vec![json!({
+                    "type": "tool_result",
+                    "tool_use_id": message.tool_call_id,
+                    "content": content,
+                })],
+            ),
+        };
+        //Roles have to alternate, so consecutive messages of one role are merged, which also
+        //puts the results of parallel tool calls into the single message that has to follow them
+        match converted.last_mut() {
+            Some(last) if last.role == role => last.content.extend(blocks),
+            _ => converted.push(Message {
+                role,
+                content: blocks,
+            }),
+        }
+    }
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+    (system, converted)
+}
+
+fn messages_request(request: &ChatRequest) -> MessagesRequest<'_> {
+    let (system, messages) = messages(&request.messages);
+    MessagesRequest {
+        model: &request.model,
+        max_tokens: request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+        system,
+        messages,
+        tools: request
+            .tools
+            .iter()
+            .map(|function| Tool {
+                name: &function.name,
+                description: &function.description,
+                input_schema: function.parameters.clone(),
+            })
+            .collect(),
+        tool_choice: (!request.tools.is_empty() && !request.allow_tool_calls)
+            .then(|| json!({ "type": "none" })),
+        temperature: request.temperature,
+    }
+}
+
+fn chat_response(response: MessagesResponse) -> ChatResponse {
+    let MessagesResponse { content, usage } = response;
+    let mut text: Vec<String> = Vec::new();
+    let mut tool_calls: Vec<ToolCall> = Vec::new();
+    for block in content {
+        match block {
+            ContentBlock::Text { text: part } => text.push(part),
+            ContentBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                id,
+                kind: ToolKind::Function,
+                function: FunctionCall {
+                    name,
+                    arguments: input.to_string(),
+                },
+            }),
+            ContentBlock::Other => {}
+        }
+    }
+    ChatResponse {
+        message: ChatMessage {
+            role: Role::Assistant,
+            content: (!text.is_empty()).then(|| text.join("")),
+            tool_calls,
+            tool_call_id: None,
+        },
+        usage: Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+        },
+    }
+}
+
+#[async_trait]
+impl ChatBackend for Anthropic {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        let api_key = self.api_key.as_deref().ok_or_else(|| {
+            Error::Config("llm.api_key is not set (config file or ANTHROPIC_API_KEY)".into())
+        })?;
+        let body = messages_request(request);
+        let response = self
+            .client
+            .post(format!("{}/v1/messages", self.base_url))
+            .header("x-api-key", api_key)
+            .header("anthropic-version", API_VERSION)
+            .json(&body)
+            .send()
+            .await?;
+        let response: MessagesResponse =
+            check_response(self.name(), response).await?.json().await?;
+        Ok(chat_response(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LlmConfig;
+    use crate::llm::{ChatOptions, FunctionDefinition};
+
+    const TOOL_USE: &str = include_str!("../../fixtures/llm/anthropic-tool-use.json");
+    const TOOL_RESULTS: &str = include_str!("../../fixtures/llm/anthropic-tool-results.json");
+
+    #[test]
+    fn recorded_tool_use_round_trips() {
+        let response = chat_response(serde_json::from_str(TOOL_USE).unwrap());
+        let message = response.message;
+        assert_eq!(
+            message.content.as_deref(),
+            Some("<thinking>I need to find where the server starts.</thinking>")
+        );
+        let calls: Vec<(&str, &str)> = message
+            .tool_calls
+            .iter()
+            .map(|call| (call.id.as_str(), call.function.name.as_str()))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("toolu_01A09q90qw90lq917835lq9", "search_codebase"),
+                ("toolu_01BkQ7vEh6iWeM7ZvYqNr1Ds", "read_file")
+            ]
+        );
+        let arguments: Value =
+            serde_json::from_str(&message.tool_calls[1].function.arguments).unwrap();
+        assert_eq!(
+            arguments,
+            json!({ "path": "src/main.rs", "start_line": 1, "end_line": 40 })
+        );
+        assert_eq!(response.usage.total(), 1120);
+
+        //Sent back with the results, which have to follow in a single user message
+        let config = LlmConfig {
+            model: "claude-3-opus-20240229".into(),
+            ..LlmConfig::default()
+        };
+        let mut request = ChatRequest::new(&config, &ChatOptions::default());
+        request.tools = vec![FunctionDefinition {
+            name: "read_file".into(),
+            description: "Reads a file of the repository".into(),
+            parameters: json!({
+                "type": "object",
+                "properties": { "path": { "type": "string" } },
+                "required": ["path"]
+            }),
+        }];
+        let results = ["src/main.rs\nsrc/routes/mod.rs", "[1] fn main() {}"];
+        request.messages = vec![
+            ChatMessage::system("You answer questions about a repository."),
+            ChatMessage::user("Where is the server started?"),
+        ];
+        let answers: Vec<ChatMessage> = message
+            .tool_calls
+            .iter()
+            .zip(results)
+            .map(|(call, result)| ChatMessage::tool(&call.id, result))
+            .collect();
+        request.messages.push(message);
+        request.messages.extend(answers);
+
+        let body = serde_json::to_value(messages_request(&request)).unwrap();
+        assert_eq!(body, serde_json::from_str::<Value>(TOOL_RESULTS).unwrap());
+    }
+}
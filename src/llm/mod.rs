@@ -0,0 +1,210 @@
+mod anthropic;
+mod openai;
+mod scripted;
+use crate::{
+    config::{LlmConfig, LlmProvider},
+    prelude::*,
+};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+pub use anthropic::*;
+pub use openai::*;
+pub use scripted::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    //JSON encoded, as produced by the model, so it may not parse
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolKind {
+    #[default]
+    Function,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    //Correlates the call with its result, the tool message answering it carries the same id
+    pub id: String,
+    #[serde(rename = "type", default)]
+    pub kind: ToolKind,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: Role,
+    #[serde(default)]
+    pub content: Option<String>,
+    //An assistant turn can call several tools at once
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn system(content: impl Into<String>) -> ChatMessage {
+        ChatMessage::text(Role::System, content)
+    }
+
+    pub fn user(content: impl Into<String>) -> ChatMessage {
+        ChatMessage::text(Role::User, content)
+    }
+
+    pub fn tool(tool_call_id: &str, content: impl Into<String>) -> ChatMessage {
+        ChatMessage {
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..ChatMessage::text(Role::Tool, content)
+        }
+    }
+
+    fn text(role: Role, content: impl Into<String>) -> ChatMessage {
+        ChatMessage {
+            role,
+            content: Some(content.into()),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: String,
+    //JSON schema of the arguments object
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    pub fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+//Per-request overrides of the configured model settings
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ChatOptions {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+impl ChatOptions {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(temperature) = self.temperature {
+            if !(0.0..=2.0).contains(&temperature) {
+                return Err(Error::InvalidRequest(
+                    "temperature must be between 0 and 2".into(),
+                ));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err(Error::InvalidRequest(
+                "max_tokens must be greater than 0".into(),
+            ));
+        }
+        if self.model.as_deref() == Some("") {
+            return Err(Error::InvalidRequest("model must not be empty".into()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub tools: Vec<FunctionDefinition>,
+    //Cleared to make the model answer in text, tools stay listed since the history refers to them
+    pub allow_tool_calls: bool,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+impl ChatRequest {
+    pub fn new(config: &LlmConfig, options: &ChatOptions) -> ChatRequest {
+        ChatRequest {
+            model: options
+                .model
+                .clone()
+                .unwrap_or_else(|| config.model.clone()),
+            messages: Vec::new(),
+            tools: Vec::new(),
+            allow_tool_calls: true,
+            temperature: options.temperature.or(config.temperature),
+            max_tokens: options.max_tokens.or(config.max_tokens),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatResponse {
+    pub message: ChatMessage,
+    pub usage: Usage,
+}
+
+#[async_trait]
+pub trait ChatBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse>;
+}
+
+pub fn backend(config: &LlmConfig) -> Result<Arc<dyn ChatBackend>> {
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("onn/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build()?;
+    Ok(match config.provider {
+        LlmProvider::OpenAi => Arc::new(OpenAi::new(client, config)),
+        LlmProvider::Anthropic => Arc::new(Anthropic::new(client, config)),
+        LlmProvider::Scripted => Arc::new(Scripted::load(config)?),
+    })
+}
+
+//Whether requests can be expected to be accepted, used by the readiness probe
+pub fn check(config: &LlmConfig) -> std::result::Result<(), &'static str> {
+    match (config.provider, &config.api_key, &config.base_url) {
+        (LlmProvider::Anthropic, None, _) => Err("llm.api_key is not set"),
+        //Local OpenAI-compatible servers usually don't need a key
+        (LlmProvider::OpenAi, None, None) => Err("llm.api_key is not set"),
+        _ => Ok(()),
+    }
+}
+
+//Turns non-success responses into errors carrying the body, which is where APIs explain themselves
+async fn check_response(backend: &str, response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(Error::Llm(format!("{backend} returned {status}: {body}")))
+}
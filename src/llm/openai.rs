@@ -0,0 +1,289 @@
+use super::{
+    check_response, ChatBackend, ChatMessage, ChatRequest, ChatResponse, FunctionCall,
+    FunctionDefinition, Role, ToolCall, ToolKind, Usage,
+};
+use crate::{
+    config::{LlmConfig, ToolFormat},
+    prelude::*,
+};
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+//Speaks the chat completions API, which llama.cpp server, vLLM and Ollama implement as well
+pub struct OpenAi {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+    tool_format: ToolFormat,
+}
+
+#[derive(Serialize)]
+struct CompletionRequest<'a, M, T> {
+    model: &'a str,
+    messages: M,
+    #[serde(flatten)]
+    tools: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Tools<'a> {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Tool<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct Tool<'a> {
+    #[serde(rename = "type")]
+    kind: ToolKind,
+    function: &'a FunctionDefinition,
+}
+
+#[derive(Serialize)]
+struct Functions<'a> {
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    functions: &'a [FunctionDefinition],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<&'static str>,
+}
+
+//A message as the functions API expects it
+#[derive(Serialize)]
+struct FunctionMessage<'a> {
+    role: &'static str,
+    content: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function_call: Option<&'a FunctionCall>,
+}
+
+#[derive(Deserialize)]
+struct FunctionReply {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    function_call: Option<FunctionCall>,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse<M> {
+    choices: Vec<Choice<M>>,
+    //Some local servers leave usage out
+    #[serde(default)]
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+struct Choice<M> {
+    message: M,
+}
+
+impl OpenAi {
+    pub fn new(client: reqwest::Client, config: &LlmConfig) -> OpenAi {
+        OpenAi {
+            client,
+            base_url: config
+                .base_url
+                .as_deref()
+                .unwrap_or(DEFAULT_BASE_URL)
+                .trim_end_matches('/')
+                .to_string(),
+            api_key: config.api_key.clone(),
+            tool_format: config.tool_format,
+        }
+    }
+
+    async fn complete<B: Serialize, M: DeserializeOwned>(&self, body: &B) -> Result<(M, Usage)> {
+        let mut http_request = self
+            .client
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(body);
+        if let Some(api_key) = &self.api_key {
+            http_request = http_request.bearer_auth(api_key);
+        }
+        let response = check_response(self.name(), http_request.send().await?).await?;
+        let CompletionResponse { choices, usage } = response.json().await?;
+        let message = choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message)
+            .ok_or_else(|| Error::Llm("The response has no choices".into()))?;
+        Ok((message, usage))
+    }
+
+    async fn chat_with_tools(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        let (message, usage) = self.complete(&tools_request(request)).await?;
+        Ok(ChatResponse { message, usage })
+    }
+
+    async fn chat_with_functions(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        let body = CompletionRequest {
+            model: &request.model,
+            messages: function_messages(&request.messages),
+            tools: Functions {
+                functions: &request.tools,
+                function_call: tool_choice(request),
+            },
+            temperature: request.temperature,
+            max_tokens: request.max_tokens,
+        };
+        let (reply, usage): (FunctionReply, _) = self.complete(&body).await?;
+        //The functions API has no call ids, they only have to be unique within the conversation
+        let tool_calls = reply
+            .function_call
+            .map(|function| ToolCall {
+                id: format!("call_{}", request.messages.len()),
+                kind: ToolKind::Function,
+                function,
+            })
+            .into_iter()
+            .collect();
+        Ok(ChatResponse {
+            message: ChatMessage {
+                role: Role::Assistant,
+                content: reply.content,
+                tool_calls,
+                tool_call_id: None,
+            },
+            usage,
+        })
+    }
+}
+
+fn tools_request(request: &ChatRequest) -> CompletionRequest<'_, &[ChatMessage], Tools<'_>> {
+    CompletionRequest {
+        model: &request.model,
+        messages: &request.messages,
+        tools: Tools {
+            tools: request
+                .tools
+                .iter()
+                .map(|function| Tool {
+                    kind: ToolKind::Function,
+                    function,
+                })
+                .collect(),
+            tool_choice: tool_choice(request),
+        },
+        temperature: request.temperature,
+        max_tokens: request.max_tokens,
+    }
+}
+
+fn tool_choice(request: &ChatRequest) -> Option<&'static str> {
+    match (request.tools.is_empty(), request.allow_tool_calls) {
+        (true, _) => None,
+        (false, true) => Some("auto"),
+        (false, false) => Some("none"),
+    }
+}
+
+//Tool results are answered by function name instead of call id
+fn function_messages(messages: &[ChatMessage]) -> Vec<FunctionMessage<'_>> {
+    let names: HashMap<&str, &str> = messages
+        .iter()
+        .flat_map(|message| &message.tool_calls)
+        .map(|call| (call.id.as_str(), call.function.name.as_str()))
+        .collect();
+    messages
+        .iter()
+        .map(|message| FunctionMessage {
+            role: match message.role {
+                Role::System => "system",
+                Role::User => "user",
+                Role::Assistant => "assistant",
+                Role::Tool => "function",
+            },
+            content: message.content.as_deref(),
+            name: message
+                .tool_call_id
+                .as_deref()
+                .and_then(|id| names.get(id).copied()),
+            function_call: message.tool_calls.first().map(|call| &call.function),
+        })
+        .collect()
+}
+
+#[async_trait]
+impl ChatBackend for OpenAi {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        match self.tool_format {
+            ToolFormat::Tools => self.chat_with_tools(request).await,
+            ToolFormat::Functions => self.chat_with_functions(request).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    const TOOL_CALLS: &str = include_str!("../../fixtures/llm/openai-tool-calls.json");
+    const TOOL_RESULTS: &str = include_str!("../../fixtures/llm/openai-tool-results.json");
+
+    #[test]
+    fn recorded_tool_calls_round_trip() {
+        let CompletionResponse { choices, usage } =
+            serde_json::from_str::<CompletionResponse<ChatMessage>>(TOOL_CALLS).unwrap();
+        let message = choices.into_iter().next().unwrap().message;
+        assert_eq!(message.content, None);
+        let calls: Vec<&str> = message
+            .tool_calls
+            .iter()
+            .map(|call| call.function.name.as_str())
+            .collect();
+        assert_eq!(calls, vec!["search_codebase", "search_path", "read_file"]);
+        assert_eq!(usage.total(), 891);
+
+        //Sent back with one result per call, in the order they were made
+        let config = LlmConfig {
+            model: "gpt-4-1106-preview".into(),
+            temperature: Some(0.0),
+            ..LlmConfig::default()
+        };
+        let mut request = ChatRequest::new(&config, &Default::default());
+        request.tools = vec![FunctionDefinition {
+            name: "read_file".into(),
+            description: "Reads a file of the repository".into(),
+            parameters: json!({
+                "type": "object",
+                "properties": { "path": { "type": "string" } },
+                "required": ["path"]
+            }),
+        }];
+        let results = [
+            "src/main.rs\nsrc/routes/mod.rs",
+            "src/main.rs",
+            "[1] fn main() {}",
+        ];
+        request.messages = vec![
+            ChatMessage::system("You answer questions about a repository."),
+            ChatMessage::user("Where is the server started?"),
+        ];
+        let answers: Vec<ChatMessage> = message
+            .tool_calls
+            .iter()
+            .zip(results)
+            .map(|(call, result)| ChatMessage::tool(&call.id, result))
+            .collect();
+        request.messages.push(message);
+        request.messages.extend(answers);
+
+        let body = serde_json::to_value(tools_request(&request)).unwrap();
+        assert_eq!(body, serde_json::from_str::<Value>(TOOL_RESULTS).unwrap());
+    }
+}
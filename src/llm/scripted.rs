@@ -0,0 +1,49 @@
+use super::{ChatBackend, ChatMessage, ChatRequest, ChatResponse, Usage};
+use crate::{config::LlmConfig, prelude::*};
+use async_trait::async_trait;
+use std::{collections::VecDeque, sync::Mutex};
+
+//Answers with the given replies in order, whatever is asked, so the agent can run without a model
+pub struct Scripted {
+    replies: Mutex<VecDeque<ChatMessage>>,
+}
+
+impl Scripted {
+    pub fn new(replies: Vec<ChatMessage>) -> Scripted {
+        Scripted {
+            replies: Mutex::new(replies.into()),
+        }
+    }
+
+    //The script is a JSON array of assistant messages in the chat completions format
+    pub fn load(config: &LlmConfig) -> Result<Scripted> {
+        let path = config.script_path.as_ref().ok_or_else(|| {
+            Error::Config("llm.script_path is required by the scripted provider".into())
+        })?;
+        let script = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read {}: {e}", path.display())))?;
+        let replies: Vec<ChatMessage> = serde_json::from_str(&script)
+            .map_err(|e| Error::Config(format!("Invalid script {}: {e}", path.display())))?;
+        Ok(Scripted::new(replies))
+    }
+}
+
+#[async_trait]
+impl ChatBackend for Scripted {
+    fn name(&self) -> &'static str {
+        "scripted"
+    }
+
+    async fn chat(&self, _request: &ChatRequest) -> Result<ChatResponse> {
+        let message = self
+            .replies
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop_front()
+            .ok_or_else(|| Error::Llm("The script has no replies left".into()))?;
+        Ok(ChatResponse {
+            message,
+            usage: Usage::default(),
+        })
+    }
+}
@@ -1,28 +1,164 @@
+mod auth;
+mod cli;
+mod config;
 mod db;
 mod embeddings;
+mod errors;
+mod eval;
 mod github;
+mod hosts;
+mod jobs;
+mod limits;
+mod llm;
+mod metrics;
 mod prelude;
 mod routes;
+mod snapshot;
+mod telemetry;
 mod utils;
-use std::{path::Path, sync::Arc};
+mod webhooks;
+use std::{sync::Arc, time::Duration};
 
-use actix_web::{web, App, HttpResponse, HttpServer};
+use actix_web::{
+    dev::Service,
+    http::header::{HeaderName, HeaderValue},
+    web, App, HttpMessage, HttpResponse, HttpServer,
+};
+use clap::Parser;
+use futures::future::{ok, Either};
+use tracing::Instrument;
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
-    let model: Arc<embeddings::Onnx> = Arc::new(embeddings::Onnx::new(Path::new("model")).unwrap());
-    let db: Arc<db::QdrantDB> = Arc::new(db::QdrantDB::initialize().unwrap());
+    let cli = cli::Cli::parse();
+    let config: Arc<config::Config> = Arc::new(config::Config::load()?);
+    let _telemetry = telemetry::init(&config.logging)?;
 
-    HttpServer::new(move || {
+    match cli.command.unwrap_or(cli::Command::Serve) {
+        cli::Command::Serve => serve(config).await,
+        command => Ok(cli::run(command, cli.json, &config).await?),
+    }
+}
+
+async fn serve(config: Arc<config::Config>) -> anyhow::Result<()> {
+    let model: Arc<embeddings::Model> = Arc::new(embeddings::Model::load(&config)?);
+    let db: Arc<db::QdrantDB> = Arc::new(db::QdrantDB::initialize(&config)?);
+    let hosts: Arc<hosts::Hosts> = Arc::new(hosts::Hosts::new(&config)?);
+    let jobs: Arc<jobs::Jobs> = Arc::new(jobs::Jobs::default());
+    let tenants: Arc<auth::Tenants> = Arc::new(auth::Tenants::new(&config.auth));
+    let limits: Arc<limits::RateLimits> = Arc::new(limits::RateLimits::new(&config.rate_limit));
+    let agent: Arc<utils::conversation::Agent> =
+        Arc::new(utils::conversation::Agent::new(&config)?);
+    let trust_forwarded_for = config.rate_limit.trust_forwarded_for;
+    let queue: Arc<webhooks::Queue> = Arc::new(webhooks::Queue::start(
+        db.clone(),
+        model.clone(),
+        hosts.clone(),
+        config.clone(),
+        limits.clone(),
+        tenants.clone(),
+    ));
+    let address = (config.server.host.clone(), config.server.port);
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    metrics::init();
+
+    let app_jobs = jobs.clone();
+    let app_db = db.clone();
+    let server = HttpServer::new(move || {
+        let app_tenants = tenants.clone();
+        let app_limits = limits.clone();
         App::new()
+            .wrap_fn(move |req, srv| {
+                if auth::is_public(req.path()) {
+                    return Either::Left(srv.call(req));
+                }
+                let ip = limits::client_ip(&req, trust_forwarded_for);
+                let admitted = app_limits.check_ip(ip).and_then(|_| {
+                    let (tenant, key) = app_tenants.authenticate(req.path(), req.headers())?;
+                    app_limits.check_key(key)?;
+                    Ok(tenant)
+                });
+                match admitted {
+                    Ok(tenant) => {
+                        req.extensions_mut().insert(tenant);
+                        Either::Left(srv.call(req))
+                    }
+                    Err(e) => Either::Right(ok(req.error_response(e))),
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let start = std::time::Instant::now();
+                let method = req.method().to_string();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    let route = response
+                        .request()
+                        .match_pattern()
+                        .unwrap_or_else(|| "unmatched".to_string());
+                    metrics::observe_request(
+                        &route,
+                        &method,
+                        response.status().as_u16(),
+                        start.elapsed(),
+                    );
+                    Ok(response)
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let request_id = telemetry::request_id(req.headers());
+                let span = tracing::info_span!(
+                    "http_request",
+                    request_id = %request_id,
+                    method = %req.method(),
+                    path = %req.path()
+                );
+                let response = span.in_scope(|| srv.call(req));
+                async move {
+                    let mut response = response.await?;
+                    tracing::info!(status = response.status().as_u16(), "Request completed");
+                    if let Ok(value) = HeaderValue::from_str(&request_id) {
+                        response
+                            .headers_mut()
+                            .insert(HeaderName::from_static(telemetry::REQUEST_ID_HEADER), value);
+                    }
+                    Ok(response)
+                }
+                .instrument(span)
+            })
             .route("/", web::get().to(|| HttpResponse::Ok()))
+            .route("/metrics", web::get().to(metrics::export))
+            .service(routes::healthz)
+            .service(routes::readyz)
             .service(routes::embeddings)
+            .service(routes::search)
             .service(routes::query)
+            .service(routes::export_snapshot)
+            .service(routes::import_snapshot(&config.server))
+            .service(routes::versions)
+            .service(routes::rollback)
+            .service(routes::cache_stats)
+            .service(routes::github_webhook())
             .app_data(web::Data::new(model.clone()))
-            .app_data(web::Data::new(db.clone()))
+            .app_data(web::Data::new(app_db.clone()))
+            .app_data(web::Data::new(app_jobs.clone()))
+            .app_data(web::Data::new(limits.clone()))
+            .app_data(web::Data::new(queue.clone()))
+            .app_data(web::Data::new(hosts.clone()))
+            .app_data(web::Data::new(agent.clone()))
+            .app_data(web::Data::new(config.clone()))
     })
-    .bind(("0.0.0.0", 3001))?
-    .run()
-    .await
+    .bind(address)?
+    .disable_signals()
+    .run();
+
+    actix_web::rt::spawn(jobs::shutdown_on_signal(
+        server.handle(),
+        jobs,
+        db,
+        shutdown_timeout,
+    ));
+    server.await?;
+    Ok(())
 }
@@ -0,0 +1,224 @@
+use actix_web::HttpResponse;
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec,
+    TextEncoder,
+};
+use std::time::Duration;
+
+//Everything is registered with the default registry, which /metrics exports as is
+
+pub static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "onn_http_requests_total",
+        "HTTP requests by route, method and status",
+        &["route", "method", "status"]
+    )
+    .expect("valid metric")
+});
+
+pub static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "onn_http_request_duration_seconds",
+        "HTTP request latency by route and method",
+        &["route", "method"],
+        exponential_buckets(0.005, 2.0, 14).expect("valid buckets")
+    )
+    .expect("valid metric")
+});
+
+pub static ARCHIVE_FETCH_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "onn_archive_fetch_duration_seconds",
+        "Time to download and extract a repository archive",
+        &["host"],
+        exponential_buckets(0.1, 2.0, 12).expect("valid buckets")
+    )
+    .expect("valid metric")
+});
+
+pub static ARCHIVE_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "onn_archive_bytes_total",
+        "Compressed archive bytes downloaded",
+        &["host"]
+    )
+    .expect("valid metric")
+});
+
+pub static FILES_EMBEDDED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("onn_files_embedded_total", "Files embedded while indexing")
+        .expect("valid metric")
+});
+
+//Files are embedded whole today, so this tracks the model inputs rather than files
+pub static CHUNKS_EMBEDDED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "onn_chunks_embedded_total",
+        "Texts run through the embeddings model, cache misses only"
+    )
+    .expect("valid metric")
+});
+
+pub static EMBEDDING_TOKENS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "onn_embedding_tokens_total",
+        "Tokens run through the embeddings model"
+    )
+    .expect("valid metric")
+});
+
+pub static EMBEDDING_BATCH_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "onn_embedding_batch_duration_seconds",
+        "Time to embed every file of a repository",
+        exponential_buckets(0.1, 2.0, 14).expect("valid buckets")
+    )
+    .expect("valid metric")
+});
+
+pub static VECTOR_DB_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "onn_vector_db_duration_seconds",
+        "Vector database latency by operation",
+        &["operation"],
+        exponential_buckets(0.001, 2.0, 16).expect("valid buckets")
+    )
+    .expect("valid metric")
+});
+
+pub static LLM_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "onn_llm_requests_total",
+        "LLM calls by outcome",
+        &["model", "outcome"]
+    )
+    .expect("valid metric")
+});
+
+pub static LLM_TOKENS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "onn_llm_tokens_total",
+        "LLM tokens used by kind (prompt or completion)",
+        &["model", "kind"]
+    )
+    .expect("valid metric")
+});
+
+pub static CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "onn_embedding_cache_lookups_total",
+        "Embedding cache lookups by result (hit or miss)",
+        &["result"]
+    )
+    .expect("valid metric")
+});
+
+//Registers every metric up front so unlabelled ones are exported before their first use
+pub fn init() {
+    Lazy::force(&HTTP_REQUESTS);
+    Lazy::force(&HTTP_REQUEST_DURATION);
+    Lazy::force(&ARCHIVE_FETCH_DURATION);
+    Lazy::force(&ARCHIVE_BYTES);
+    Lazy::force(&FILES_EMBEDDED);
+    Lazy::force(&CHUNKS_EMBEDDED);
+    Lazy::force(&EMBEDDING_TOKENS);
+    Lazy::force(&EMBEDDING_BATCH_DURATION);
+    Lazy::force(&VECTOR_DB_DURATION);
+    Lazy::force(&LLM_REQUESTS);
+    Lazy::force(&LLM_TOKENS);
+    Lazy::force(&CACHE_LOOKUPS);
+}
+
+pub fn observe_request(route: &str, method: &str, status: u16, elapsed: Duration) {
+    HTTP_REQUESTS
+        .with_label_values(&[route, method, &status.to_string()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route, method])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub async fn export() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render())
+}
+
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!(error = %e, "Unable to encode metrics");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header, test, web, App};
+
+    //Metrics are global, so labels only this test uses keep it apart from the others
+    #[actix_web::test]
+    async fn metrics_are_exported_with_their_labels() {
+        init();
+        observe_request("/test/{id}", "GET", 404, Duration::from_millis(20));
+        ARCHIVE_FETCH_DURATION
+            .with_label_values(&["test-host"])
+            .observe(1.5);
+        ARCHIVE_BYTES.with_label_values(&["test-host"]).inc_by(2048);
+        VECTOR_DB_DURATION
+            .with_label_values(&["test-operation"])
+            .observe(0.01);
+        LLM_REQUESTS
+            .with_label_values(&["test-model", "success"])
+            .inc();
+        LLM_TOKENS
+            .with_label_values(&["test-model", "prompt"])
+            .inc_by(100);
+        CACHE_LOOKUPS.with_label_values(&["test-result"]).inc();
+
+        let app = test::init_service(App::new().route("/metrics", web::get().to(export))).await;
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(response.status().is_success());
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain; version=0.0.4"
+        );
+        let body = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+
+        for (name, kind) in [
+            ("onn_http_requests_total", "counter"),
+            ("onn_http_request_duration_seconds", "histogram"),
+            ("onn_archive_fetch_duration_seconds", "histogram"),
+            ("onn_archive_bytes_total", "counter"),
+            ("onn_files_embedded_total", "counter"),
+            ("onn_chunks_embedded_total", "counter"),
+            ("onn_embedding_tokens_total", "counter"),
+            ("onn_embedding_batch_duration_seconds", "histogram"),
+            ("onn_vector_db_duration_seconds", "histogram"),
+            ("onn_llm_requests_total", "counter"),
+            ("onn_llm_tokens_total", "counter"),
+            ("onn_embedding_cache_lookups_total", "counter"),
+        ] {
+            assert!(
+                body.contains(&format!("# TYPE {name} {kind}\n")),
+                "{name} isn't exported as a {kind}"
+            );
+        }
+        for sample in [
+            r#"onn_http_requests_total{method="GET",route="/test/{id}",status="404"} 1"#,
+            r#"onn_http_request_duration_seconds_count{method="GET",route="/test/{id}"} 1"#,
+            r#"onn_archive_fetch_duration_seconds_count{host="test-host"} 1"#,
+            r#"onn_archive_bytes_total{host="test-host"} 2048"#,
+            r#"onn_vector_db_duration_seconds_count{operation="test-operation"} 1"#,
+            r#"onn_llm_requests_total{model="test-model",outcome="success"} 1"#,
+            r#"onn_llm_tokens_total{kind="prompt",model="test-model"} 100"#,
+            r#"onn_embedding_cache_lookups_total{result="test-result"} 1"#,
+        ] {
+            assert!(body.contains(sample), "{sample} is missing");
+        }
+    }
+}
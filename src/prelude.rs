@@ -1,3 +1,3 @@
-pub type Result<T> = anyhow::Result<T>;
+pub use crate::errors::Error;
 
-pub const MAX_FILE_COUNT: u32 = 1000;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, Error>;
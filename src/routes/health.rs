@@ -0,0 +1,161 @@
+use crate::{
+    config::Config,
+    db::{QdrantDB, RepositoryEmbeddingsDB},
+    embeddings::{Embeddings, EmbeddingsModel, Model},
+    jobs::Jobs,
+    llm,
+    prelude::Error,
+};
+use actix_web::{get, web, HttpResponse, Responder};
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Check {
+    fn from_result<T>(result: Result<T, impl ToString>) -> Check {
+        match result {
+            Ok(_) => Check {
+                ok: true,
+                detail: None,
+            },
+            Err(e) => Check {
+                ok: false,
+                detail: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    shutting_down: bool,
+    model: Check,
+    vector_store: Check,
+    //Reported only, the server can index and search without an LLM
+    llm: Check,
+}
+
+fn readiness(
+    inference: Result<Embeddings, String>,
+    vector_store: Result<(), Error>,
+    config: &Config,
+    shutting_down: bool,
+) -> Readiness {
+    let inference = inference.and_then(|embeddings| {
+        if embeddings.len() as u64 == config.model.dimension {
+            Ok(())
+        } else {
+            Err(format!(
+                "Model returned {} dimensions, expected {}",
+                embeddings.len(),
+                config.model.dimension
+            ))
+        }
+    });
+    let model = Check::from_result(inference);
+    let vector_store = Check::from_result(vector_store);
+    Readiness {
+        ready: !shutting_down && model.ok && vector_store.ok,
+        shutting_down,
+        model,
+        vector_store,
+        llm: Check::from_result(llm::check(&config.llm)),
+    }
+}
+
+#[get("/healthz")]
+async fn healthz() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+#[get("/readyz")]
+async fn readyz(
+    db: web::Data<Arc<QdrantDB>>,
+    model: web::Data<Arc<Model>>,
+    config: web::Data<Arc<Config>>,
+    jobs: web::Data<Arc<Jobs>>,
+) -> impl Responder {
+    let probe_model = model.get_ref().clone();
+    //Runs a real inference, bypassing the cache
+    let inference = web::block(move || probe_model.uncached().embed("readiness probe"))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|embeddings| embeddings.map_err(|e| e.to_string()));
+    let readiness = readiness(
+        inference,
+        db.health_check().await,
+        &config,
+        jobs.is_draining(),
+    );
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.model.dimension = 4;
+        config
+    }
+
+    #[test]
+    fn ready_when_every_dependency_is_up() {
+        let report = readiness(Ok(vec![0.0; 4]), Ok(()), &config(), false);
+        assert!(report.ready);
+        assert!(report.model.ok && report.vector_store.ok);
+    }
+
+    #[test]
+    fn not_ready_when_a_dependency_is_down() {
+        let down = || Err(Error::VectorDB(anyhow::anyhow!("connection refused")));
+        let report = readiness(Ok(vec![0.0; 4]), down(), &config(), false);
+        assert!(!report.ready);
+        assert!(report.model.ok);
+        assert_eq!(
+            report.vector_store.detail.as_deref(),
+            Some("Vector database error: connection refused")
+        );
+
+        let report = readiness(Err("model not loaded".into()), Ok(()), &config(), false);
+        assert!(!report.ready);
+        assert_eq!(report.model.detail.as_deref(), Some("model not loaded"));
+
+        let report = readiness(Ok(vec![0.0; 3]), Ok(()), &config(), false);
+        assert!(!report.ready);
+        assert_eq!(
+            report.model.detail.as_deref(),
+            Some("Model returned 3 dimensions, expected 4")
+        );
+    }
+
+    #[test]
+    fn not_ready_while_shutting_down() {
+        let report = readiness(Ok(vec![0.0; 4]), Ok(()), &config(), true);
+        assert!(!report.ready);
+        assert!(report.shutting_down);
+    }
+
+    #[test]
+    fn llm_is_reported_without_affecting_readiness() {
+        let mut config = config();
+        config.llm.api_key = None;
+        config.llm.base_url = None;
+        let report = readiness(Ok(vec![0.0; 4]), Ok(()), &config, false);
+        assert!(report.ready);
+        assert!(!report.llm.ok);
+        assert_eq!(report.llm.detail.as_deref(), Some("llm.api_key is not set"));
+    }
+}
@@ -1,40 +1,300 @@
-use crate::embeddings::EmbeddingsModel;
-use crate::utils::conversation::Query;
-use crate::{db::RepositoryEmbeddingsDB, github::Repository};
+use crate::config::{Config, ServerConfig};
+use crate::prelude::*;
+use crate::utils::conversation::{Agent, Conversation, Query};
+use crate::{
+    db::{RepositoryEmbeddingsDB, SearchFilter},
+    embeddings::EmbeddingsModel,
+    github::Repository,
+    snapshot::Snapshot,
+};
 use actix_web::{
-    post,
-    web::{self, Json},
-    HttpResponse, Responder,
+    get, guard, post,
+    web::{self, Bytes, Json},
+    HttpResponse, Resource, Responder,
 };
 use reqwest::StatusCode;
+use serde::Deserialize;
 use std::sync::Arc;
+use tracing::Instrument;
+
+use crate::{
+    auth::Tenant, db::QdrantDB, embeddings::Model, github::embed_repo, hosts::Hosts, jobs::Jobs,
+    limits::RateLimits,
+};
 
-use crate::{db::QdrantDB, embeddings::Onnx, github::embed_repo};
+mod health;
+mod webhooks;
+pub use health::*;
+pub use webhooks::*;
 
 #[post("/embeddings")]
+#[allow(clippy::too_many_arguments)]
 async fn embeddings(
     data: Json<Repository>,
     db: web::Data<Arc<QdrantDB>>,
-    model: web::Data<Arc<Onnx>>,
-) -> impl Responder {
-    let embeddings = embed_repo(data.into_inner(), model.get_ref().as_ref())
-        .await
-        .unwrap();
-
-    match db.get_ref().insert_repo_embeddings(embeddings).await {
-        Ok(_) => HttpResponse::new(StatusCode::CREATED),
-        Err(e) => {
-            dbg!(e);
-            return HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR);
-        }
+    model: web::Data<Arc<Model>>,
+    hosts: web::Data<Arc<Hosts>>,
+    config: web::Data<Arc<Config>>,
+    jobs: web::Data<Arc<Jobs>>,
+    limits: web::Data<Arc<RateLimits>>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> Result<impl Responder> {
+    let _permit = limits.embeddings.acquire()?;
+    let repository = hosts
+        .resolve(Repository {
+            tenant: tenant.name.clone(),
+            ..data.into_inner()
+        })
+        .await?;
+    let repo_id = repository.to_string();
+    tenant
+        .check_repository_quota(db.get_ref().as_ref(), &repo_id)
+        .await?;
+    let job = jobs.start(&repo_id)?;
+    let span = tracing::info_span!("index", job_id = %job.id(), repo_id = %repo_id);
+    async {
+        let embeddings = embed_repo(
+            repository,
+            model.get_ref().as_ref(),
+            hosts.get_ref().as_ref(),
+            &config.fetch,
+        )
+        .await?;
+
+        tenant
+            .check_vector_quota(
+                db.get_ref().as_ref(),
+                &repo_id,
+                embeddings.file_embeddings.len() as u64,
+            )
+            .await?;
+        job.writing();
+        db.get_ref().insert_repo_embeddings(embeddings).await
+    }
+    .instrument(span)
+    .await?;
+    Ok(HttpResponse::new(StatusCode::CREATED))
+}
+
+#[derive(Deserialize)]
+pub struct SearchRequest {
+    repository: Repository,
+    query: String,
+    limit: Option<u64>,
+    #[serde(default)]
+    filter: SearchFilter,
+}
+
+#[post("/search")]
+async fn search(
+    data: Json<SearchRequest>,
+    db: web::Data<Arc<QdrantDB>>,
+    model: web::Data<Arc<Model>>,
+    hosts: web::Data<Arc<Hosts>>,
+    config: web::Data<Arc<Config>>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> Result<impl Responder> {
+    let SearchRequest {
+        repository,
+        query: text,
+        limit,
+        filter,
+    } = data.into_inner();
+    let repository = hosts
+        .resolve(Repository {
+            tenant: tenant.name.clone(),
+            ..repository
+        })
+        .await?;
+    let query_embeddings = model.embed(&text)?;
+    let files = db
+        .get_relevant_files(
+            repository,
+            query_embeddings,
+            limit.unwrap_or(config.retrieval.limit),
+            &filter,
+        )
+        .await?;
+    Ok(HttpResponse::Ok().json(files))
+}
+
+#[get("/embeddings/cache")]
+async fn cache_stats(model: web::Data<Arc<Model>>) -> impl Responder {
+    match model.cache_stats() {
+        Some(stats) => HttpResponse::Ok().json(stats),
+        None => HttpResponse::NotFound().finish(),
     }
 }
 
 #[post("/query")]
+#[allow(clippy::too_many_arguments)]
 async fn query(
     data: Json<Query>,
     db: web::Data<Arc<QdrantDB>>,
-    model: web::Data<Arc<Onnx>>,
-) -> impl Responder {
-    todo!()
+    model: web::Data<Arc<Model>>,
+    hosts: web::Data<Arc<Hosts>>,
+    config: web::Data<Arc<Config>>,
+    agent: web::Data<Arc<Agent>>,
+    limits: web::Data<Arc<RateLimits>>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> Result<impl Responder> {
+    tenant.check_llm_budget()?;
+    let _permit = limits.queries.acquire()?;
+    let mut query = data.into_inner();
+    query.repository = hosts
+        .resolve(Repository {
+            tenant: tenant.name.clone(),
+            ..query.repository
+        })
+        .await?;
+    let answer = Conversation::new(
+        query,
+        &agent,
+        db.get_ref().clone(),
+        model.get_ref().clone(),
+        hosts.get_ref().clone(),
+        config.get_ref().clone(),
+        tenant.into_inner(),
+    )?
+    .generate_answer()
+    .await?;
+    Ok(HttpResponse::Ok().json(answer))
+}
+
+#[get("/repos/{id}/snapshot")]
+async fn export_snapshot(
+    path: web::Path<String>,
+    db: web::Data<Arc<QdrantDB>>,
+    config: web::Data<Arc<Config>>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> Result<impl Responder> {
+    let repo_id = path.into_inner();
+    let collection = tenant.collection(&repo_id);
+    //The header carries the id the tenant knows, so the file can be imported by another tenant
+    let snapshot = Snapshot::export(db.get_ref().as_ref(), &collection, &config.model)
+        .await?
+        .with_repo_id(&repo_id);
+    let body = web::block(move || {
+        let mut body: Vec<u8> = Vec::new();
+        snapshot.write(&mut body).map(|_| body)
+    })
+    .await
+    .map_err(|e| Error::Snapshot(e.to_string()))??;
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{repo_id}.onnsnap\""),
+        ))
+        .body(body))
+}
+
+//Snapshots are far larger than any other body, so only this resource accepts their size
+pub fn import_snapshot(config: &ServerConfig) -> Resource {
+    web::resource("/repos/{id}/snapshot")
+        .guard(guard::Put())
+        .app_data(web::PayloadConfig::new(config.max_snapshot_bytes))
+        .to(import_snapshot_handler)
+}
+
+async fn import_snapshot_handler(
+    path: web::Path<String>,
+    body: Bytes,
+    db: web::Data<Arc<QdrantDB>>,
+    config: web::Data<Arc<Config>>,
+    jobs: web::Data<Arc<Jobs>>,
+    limits: web::Data<Arc<RateLimits>>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> Result<impl Responder> {
+    let _permit = limits.embeddings.acquire()?;
+    let repo_id = path.into_inner();
+    let collection = tenant.collection(&repo_id);
+    tenant
+        .check_repository_quota(db.get_ref().as_ref(), &collection)
+        .await?;
+    let job = jobs.start(&collection)?;
+    let server = config.server.clone();
+    let snapshot = web::block(move || Snapshot::read(body.as_ref(), &server))
+        .await
+        .map_err(|e| Error::Snapshot(e.to_string()))??;
+    tenant
+        .check_vector_quota(
+            db.get_ref().as_ref(),
+            &collection,
+            snapshot.header.file_count,
+        )
+        .await?;
+    job.writing();
+    let mut header = snapshot
+        .with_repo_id(&collection)
+        .import(db.get_ref().as_ref(), &config.model)
+        .await?;
+    header.repo_id = repo_id;
+    Ok(HttpResponse::Created().json(header))
+}
+
+#[get("/repos/{id}/versions")]
+async fn versions(
+    path: web::Path<String>,
+    db: web::Data<Arc<QdrantDB>>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> Result<impl Responder> {
+    let versions = db
+        .list_versions(&tenant.collection(&path.into_inner()))
+        .await?;
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+#[derive(Deserialize, Default)]
+pub struct RollbackRequest {
+    version: Option<u64>,
+}
+
+#[post("/repos/{id}/rollback")]
+async fn rollback(
+    path: web::Path<String>,
+    data: Option<Json<RollbackRequest>>,
+    db: web::Data<Arc<QdrantDB>>,
+    tenant: web::ReqData<Arc<Tenant>>,
+) -> Result<impl Responder> {
+    let RollbackRequest { version } = data.map(Json::into_inner).unwrap_or_default();
+    let live = db
+        .rollback(&tenant.collection(&path.into_inner()), version)
+        .await?;
+    Ok(HttpResponse::Ok().json(live))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header, test, App};
+
+    #[actix_web::test]
+    async fn applies_the_snapshot_limit_to_imports_only() {
+        let config = ServerConfig {
+            max_snapshot_bytes: 1024 * 1024,
+            ..Default::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .service(import_snapshot(&config))
+                .service(github_webhook()),
+        )
+        .await;
+
+        //Over the 256 KiB default, so only the snapshot limit lets it through to the handler
+        let request = test::TestRequest::put()
+            .uri("/repos/a-b-main/snapshot")
+            .set_payload(vec![0u8; 512 * 1024])
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_ne!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let request = test::TestRequest::put()
+            .uri("/repos/a-b-main/snapshot")
+            .insert_header((header::CONTENT_LENGTH, config.max_snapshot_bytes + 1))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
 }
@@ -0,0 +1,144 @@
+use crate::{
+    config::Config,
+    db::{QdrantDB, RepositoryEmbeddingsDB},
+    jobs::Jobs,
+    prelude::*,
+    webhooks::{self, Changes, PushEvent, Queue, Reindex},
+};
+use actix_web::{guard, web, web::Bytes, HttpRequest, HttpResponse, Resource, Responder};
+use serde::Serialize;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+struct Delivery {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    collections: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<Changes>,
+}
+
+impl Delivery {
+    fn new(status: &'static str) -> Delivery {
+        Delivery {
+            status,
+            reason: None,
+            collections: Vec::new(),
+            changes: None,
+        }
+    }
+
+    fn ignored(reason: &'static str) -> HttpResponse {
+        HttpResponse::Ok().json(Delivery {
+            reason: Some(reason),
+            ..Delivery::new("ignored")
+        })
+    }
+}
+
+//GitHub caps deliveries at 25 MB, and the body is read before its signature can be checked
+pub const MAX_WEBHOOK_BYTES: usize = 25 * 1024 * 1024;
+
+//Authenticated by the payload signature rather than an API key
+pub fn github_webhook() -> Resource {
+    web::resource("/webhooks/github")
+        .guard(guard::Post())
+        .app_data(web::PayloadConfig::new(MAX_WEBHOOK_BYTES))
+        .to(github_webhook_handler)
+}
+
+async fn github_webhook_handler(
+    request: HttpRequest,
+    body: Bytes,
+    db: web::Data<Arc<QdrantDB>>,
+    config: web::Data<Arc<Config>>,
+    jobs: web::Data<Arc<Jobs>>,
+    queue: web::Data<Arc<Queue>>,
+) -> Result<impl Responder> {
+    let secret = config
+        .github
+        .webhook_secret
+        .as_deref()
+        .ok_or_else(|| Error::Unauthenticated("GitHub webhooks are not configured".into()))?;
+    let header = |name: &str| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    webhooks::verify_signature(secret, &body, header(webhooks::SIGNATURE_HEADER))?;
+
+    match header(webhooks::EVENT_HEADER) {
+        Some("ping") => return Ok(HttpResponse::Ok().json(Delivery::new("pong"))),
+        Some("push") => {}
+        _ => return Ok(Delivery::ignored("only push events are handled")),
+    }
+    let delivery = match header(webhooks::DELIVERY_HEADER) {
+        Some(delivery) => delivery,
+        None => return Err(Error::InvalidRequest("Delivery id is missing".into())),
+    };
+    if !queue.deliveries.insert(delivery) {
+        return Ok(Delivery::ignored("already delivered"));
+    }
+    let response = accept_push(&body, db.get_ref(), jobs.get_ref(), queue.get_ref()).await;
+    if response.is_err() {
+        queue.deliveries.forget(delivery);
+    }
+    response
+}
+
+async fn accept_push(
+    body: &[u8],
+    db: &Arc<QdrantDB>,
+    jobs: &Arc<Jobs>,
+    queue: &Queue,
+) -> Result<HttpResponse> {
+    let event: PushEvent = serde_json::from_slice(body)
+        .map_err(|e| Error::InvalidRequest(format!("Invalid push payload: {e}")))?;
+    let repository = match event.repository() {
+        Some(repository) => repository,
+        None => return Ok(Delivery::ignored("not a branch update")),
+    };
+    let collections = webhooks::tracking_collections(db.list_repositories().await?, &repository);
+    if collections.is_empty() {
+        return Ok(Delivery::ignored("branch is not indexed"));
+    }
+
+    let changes = event.changes();
+    let jobs = collections
+        .iter()
+        .map(|collection| jobs.start(collection))
+        .collect::<Result<Vec<_>>>()?;
+    let accepted = Delivery {
+        collections: collections.clone(),
+        changes: Some(changes.clone()),
+        ..Delivery::new("queued")
+    };
+    queue.enqueue(Reindex {
+        repository,
+        commit: event.after,
+        collections,
+        changes,
+        jobs,
+    })?;
+    Ok(HttpResponse::Accepted().json(accepted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header, http::StatusCode, test, App};
+
+    #[actix_web::test]
+    async fn rejects_deliveries_over_the_webhook_limit() {
+        let app = test::init_service(App::new().service(github_webhook())).await;
+        let request = test::TestRequest::post()
+            .uri("/webhooks/github")
+            .insert_header((header::CONTENT_LENGTH, MAX_WEBHOOK_BYTES + 1))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::{
+    config::{ModelConfig, ServerConfig},
+    db::RepositoryEmbeddingsDB,
+    embeddings::{Embeddings, CHUNKING},
+    github::{File, FileEmbeddings, RepositoryEmbeddings},
+    prelude::*,
+};
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+//Layout: magic, format version (u16 LE), header length (u32 LE), JSON header,
+//then the files as zstd-compressed bincode records
+const MAGIC: &[u8; 8] = b"ONNSNAP\0";
+const FORMAT_VERSION: u16 = 1;
+const MAX_HEADER_BYTES: u32 = 64 * 1024;
+//Bounds allocations when reading untrusted uploads
+const MAX_RECORD_BYTES: u64 = 64 * 1024 * 1024;
+const COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+    pub version: u16,
+    pub repo_id: String,
+    pub model: String,
+    pub dimension: u64,
+    pub chunking: String,
+    //Set when every file was indexed from the same commit
+    pub commit: Option<String>,
+    pub file_count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    path: String,
+    content: String,
+    last_commit: Option<String>,
+    encoding: String,
+    lossy: bool,
+    embeddings: Embeddings,
+}
+
+pub struct Snapshot {
+    pub header: SnapshotHeader,
+    pub embeddings: RepositoryEmbeddings,
+}
+
+impl Snapshot {
+    pub async fn export<D: RepositoryEmbeddingsDB>(
+        db: &D,
+        repo_id: &str,
+        model: &ModelConfig,
+    ) -> Result<Snapshot> {
+        let embeddings = db.get_repo_embeddings(repo_id).await?;
+
+        let mut commits = embeddings
+            .file_embeddings
+            .iter()
+            .map(|file| file.file.indexed_commit.as_ref());
+        let first = commits.next().flatten();
+        let commit = commits
+            .all(|commit| commit == first)
+            .then(|| first.cloned())
+            .flatten();
+
+        Ok(Snapshot {
+            header: SnapshotHeader {
+                version: FORMAT_VERSION,
+                repo_id: repo_id.to_string(),
+                model: model.id.clone(),
+                dimension: model.dimension,
+                chunking: CHUNKING.to_string(),
+                commit,
+                file_count: embeddings.file_embeddings.len() as u64,
+            },
+            embeddings,
+        })
+    }
+
+    //Restores the snapshot as a new version of the index with the same id
+    pub async fn import<D: RepositoryEmbeddingsDB>(
+        self,
+        db: &D,
+        model: &ModelConfig,
+    ) -> Result<SnapshotHeader> {
+        self.check_compatible(model)?;
+        db.insert_repo_embeddings(self.embeddings).await?;
+        Ok(self.header)
+    }
+
+    //Imports under a different id, e.g. the one in the request path
+    pub fn with_repo_id(mut self, repo_id: &str) -> Snapshot {
+        self.header.repo_id = repo_id.to_string();
+        self.embeddings.repo_id = repo_id.to_string();
+        self
+    }
+
+    fn check_compatible(&self, model: &ModelConfig) -> Result<()> {
+        let header = &self.header;
+        if header.model != model.id || header.dimension != model.dimension {
+            return Err(Error::Snapshot(format!(
+                "Built with {} ({} dimensions), this server uses {} ({} dimensions)",
+                header.model, header.dimension, model.id, model.dimension
+            )));
+        }
+        if header.chunking != CHUNKING {
+            return Err(Error::Snapshot(format!(
+                "Unsupported chunking {}",
+                header.chunking
+            )));
+        }
+        if let Some(file) = self
+            .embeddings
+            .file_embeddings
+            .iter()
+            .find(|file| file.embeddings.len() as u64 != header.dimension)
+        {
+            return Err(Error::Snapshot(format!(
+                "{} has {} dimensions, the header declares {}",
+                file.file.path,
+                file.embeddings.len(),
+                header.dimension
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn write<W: Write>(self, mut writer: W) -> Result<()> {
+        let header = serde_json::to_vec(&self.header).map_err(snapshot_error)?;
+        writer.write_all(MAGIC).map_err(snapshot_error)?;
+        writer
+            .write_all(&FORMAT_VERSION.to_le_bytes())
+            .map_err(snapshot_error)?;
+        writer
+            .write_all(&(header.len() as u32).to_le_bytes())
+            .map_err(snapshot_error)?;
+        writer.write_all(&header).map_err(snapshot_error)?;
+
+        let mut encoder = zstd::Encoder::new(writer, COMPRESSION_LEVEL).map_err(snapshot_error)?;
+        for FileEmbeddings { file, embeddings } in self.embeddings.file_embeddings {
+            let record = SnapshotFile {
+                path: file.path,
+                content: file.content,
+                last_commit: file.last_commit,
+                encoding: file.encoding,
+                lossy: file.lossy,
+                embeddings,
+            };
+            record_options()
+                .serialize_into(&mut encoder, &record)
+                .map_err(snapshot_error)?;
+        }
+        let mut writer = encoder.finish().map_err(snapshot_error)?;
+        writer.flush().map_err(snapshot_error)?;
+        Ok(())
+    }
+
+    pub fn read<R: Read>(mut reader: R, config: &ServerConfig) -> Result<Snapshot> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).map_err(snapshot_error)?;
+        if &magic != MAGIC {
+            return Err(Error::Snapshot("Not a snapshot file".into()));
+        }
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version).map_err(snapshot_error)?;
+        let version = u16::from_le_bytes(version);
+        if version != FORMAT_VERSION {
+            return Err(Error::Snapshot(format!(
+                "Unsupported format version {version}, expected {FORMAT_VERSION}"
+            )));
+        }
+        let mut length = [0u8; 4];
+        reader.read_exact(&mut length).map_err(snapshot_error)?;
+        let length = u32::from_le_bytes(length);
+        if length > MAX_HEADER_BYTES {
+            return Err(Error::Snapshot(format!(
+                "Header of {length} bytes is too large"
+            )));
+        }
+        let mut header = vec![0u8; length as usize];
+        reader.read_exact(&mut header).map_err(snapshot_error)?;
+        let header: SnapshotHeader = serde_json::from_slice(&header).map_err(snapshot_error)?;
+
+        if header.file_count > config.max_snapshot_files {
+            return Err(Error::LimitExceeded(format!(
+                "Snapshot has {} files, the limit is {}",
+                header.file_count, config.max_snapshot_files
+            )));
+        }
+
+        //One byte past the limit is read so that reaching it can be told apart from ending there
+        let mut records = zstd::Decoder::new(reader)
+            .map_err(snapshot_error)?
+            .take(config.max_snapshot_decompressed_bytes + 1);
+        let mut file_embeddings: Vec<FileEmbeddings> = Vec::new();
+        for _ in 0..header.file_count {
+            let record: SnapshotFile = match record_options().deserialize_from(&mut records) {
+                Ok(record) => record,
+                Err(_) if records.limit() == 0 => return Err(decompressed_too_large(config)),
+                Err(e) => return Err(snapshot_error(e)),
+            };
+            let file = File::new(record.path, record.content, record.last_commit);
+            file_embeddings.push(FileEmbeddings {
+                file: File {
+                    encoding: record.encoding,
+                    lossy: record.lossy,
+                    indexed_commit: header.commit.clone(),
+                    ..file
+                },
+                embeddings: record.embeddings,
+            });
+        }
+        if records.limit() == 0 {
+            return Err(decompressed_too_large(config));
+        }
+        //Records past the declared count mean the header can't be trusted
+        if records.read(&mut [0u8; 1]).map_err(snapshot_error)? != 0 {
+            return Err(Error::Snapshot(format!(
+                "Snapshot has more records than the {} its header declares",
+                header.file_count
+            )));
+        }
+        Ok(Snapshot {
+            embeddings: RepositoryEmbeddings {
+                repo_id: header.repo_id.clone(),
+                file_embeddings,
+            },
+            header,
+        })
+    }
+}
+
+fn record_options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_RECORD_BYTES)
+}
+
+fn decompressed_too_large(config: &ServerConfig) -> Error {
+    Error::LimitExceeded(format!(
+        "Snapshot decompresses to more than {} bytes",
+        config.max_snapshot_decompressed_bytes
+    ))
+}
+
+fn snapshot_error(error: impl std::fmt::Display) -> Error {
+    Error::Snapshot(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMIT: &str = "9c4b3a4f0e6e2d1c8b7a6f5e4d3c2b1a0f9e8d7c";
+
+    fn snapshot(files: &[(&str, &str)]) -> Snapshot {
+        let model = ModelConfig::default();
+        let file_embeddings: Vec<FileEmbeddings> = files
+            .iter()
+            .map(|(path, content)| FileEmbeddings {
+                file: File::new(path.to_string(), content.to_string(), None),
+                embeddings: vec![0.5; model.dimension as usize],
+            })
+            .collect();
+        Snapshot {
+            header: SnapshotHeader {
+                version: FORMAT_VERSION,
+                repo_id: "owner/repo".into(),
+                model: model.id,
+                dimension: model.dimension,
+                chunking: CHUNKING.into(),
+                commit: Some(COMMIT.into()),
+                file_count: file_embeddings.len() as u64,
+            },
+            embeddings: RepositoryEmbeddings {
+                repo_id: "owner/repo".into(),
+                file_embeddings,
+            },
+        }
+    }
+
+    fn bytes(snapshot: Snapshot) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trips() {
+        let bytes = bytes(snapshot(&[
+            ("src/main.rs", "fn main() {}"),
+            ("README.md", "# Repo"),
+        ]));
+        let snapshot = Snapshot::read(bytes.as_slice(), &ServerConfig::default()).unwrap();
+        assert_eq!(snapshot.header.file_count, 2);
+        assert_eq!(snapshot.embeddings.repo_id, "owner/repo");
+        let files: Vec<(&str, &str)> = snapshot
+            .embeddings
+            .file_embeddings
+            .iter()
+            .map(|file| (file.file.path.as_str(), file.file.content.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            [("src/main.rs", "fn main() {}"), ("README.md", "# Repo")]
+        );
+        assert!(snapshot.embeddings.file_embeddings.iter().all(|file| {
+            file.file.indexed_commit.as_deref() == Some(COMMIT) && file.embeddings.len() == 384
+        }));
+        assert!(snapshot.check_compatible(&ModelConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_files_and_versions() {
+        let config = ServerConfig::default();
+        assert!(matches!(
+            Snapshot::read(b"PK\x03\x04 not a snapshot".as_slice(), &config),
+            Err(Error::Snapshot(message)) if message == "Not a snapshot file"
+        ));
+        let mut bytes = bytes(snapshot(&[("a.rs", "a")]));
+        bytes[MAGIC.len()] = 2;
+        assert!(matches!(
+            Snapshot::read(bytes.as_slice(), &config),
+            Err(Error::Snapshot(message)) if message.starts_with("Unsupported format version 2")
+        ));
+    }
+
+    #[test]
+    fn rejects_snapshots_of_another_model() {
+        let snapshot = Snapshot::read(
+            bytes(snapshot(&[("a.rs", "a")])).as_slice(),
+            &ServerConfig::default(),
+        )
+        .unwrap();
+        let model = ModelConfig {
+            dimension: 768,
+            ..ModelConfig::default()
+        };
+        assert!(matches!(
+            snapshot.check_compatible(&model),
+            Err(Error::Snapshot(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_record_counts_that_differ_from_the_header() {
+        let config = ServerConfig::default();
+        let mut extra = snapshot(&[("a.rs", "a"), ("b.rs", "b")]);
+        extra.header.file_count = 1;
+        assert!(matches!(
+            Snapshot::read(bytes(extra).as_slice(), &config),
+            Err(Error::Snapshot(message)) if message.starts_with("Snapshot has more records")
+        ));
+
+        let mut missing = snapshot(&[("a.rs", "a")]);
+        missing.header.file_count = 2;
+        assert!(matches!(
+            Snapshot::read(bytes(missing).as_slice(), &config),
+            Err(Error::Snapshot(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_snapshots_over_the_limits() {
+        let bytes = bytes(snapshot(&[("a.rs", &"a".repeat(10_000)), ("b.rs", "b")]));
+        let config = ServerConfig {
+            max_snapshot_files: 1,
+            ..ServerConfig::default()
+        };
+        assert!(matches!(
+            Snapshot::read(bytes.as_slice(), &config),
+            Err(Error::LimitExceeded(message)) if message.contains("the limit is 1")
+        ));
+        let config = ServerConfig {
+            max_snapshot_decompressed_bytes: 5_000,
+            ..ServerConfig::default()
+        };
+        assert!(matches!(
+            Snapshot::read(bytes.as_slice(), &config),
+            Err(Error::LimitExceeded(message)) if message.contains("5000 bytes")
+        ));
+    }
+}
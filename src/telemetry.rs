@@ -0,0 +1,193 @@
+use crate::{
+    config::{LogFormat, LoggingConfig},
+    prelude::*,
+};
+use actix_web::http::header::HeaderMap;
+use opentelemetry::{sdk::trace, sdk::Resource, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Subscriber;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+//Flushes buffered spans to the collector when dropped
+pub struct Telemetry {
+    otlp: bool,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if self.otlp {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
+
+pub fn init(config: &LoggingConfig) -> Result<Telemetry> {
+    let (subscriber, telemetry) = subscriber(config)?;
+    subscriber
+        .try_init()
+        .map_err(|e| Error::Config(format!("Unable to set up logging: {e}")))?;
+    Ok(telemetry)
+}
+
+//Logs go to stderr so command output on stdout stays machine-readable
+fn subscriber(config: &LoggingConfig) -> Result<(impl Subscriber + Send + Sync, Telemetry)> {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.level))
+        .map_err(|e| Error::Config(format!("Invalid log filter: {e}")))?;
+    let logs = match config.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .with_writer(std::io::stderr)
+            .boxed(),
+    };
+    let otlp = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", config.service_name.clone()),
+                ])))
+                .install_batch(opentelemetry::runtime::Tokio)
+                .map_err(|e| Error::Config(format!("Unable to set up OTLP export: {e}")))?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+    let exporting = otlp.is_some();
+    let subscriber = tracing_subscriber::registry()
+        .with(logs)
+        .with(otlp)
+        .with(filter);
+    Ok((subscriber, Telemetry { otlp: exporting }))
+}
+
+//Reuses the caller's id when it looks sane so traces can be joined across services
+pub fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128 && id.chars().all(|c| c.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+    use opentelemetry_proto::tonic::{
+        collector::trace::v1::{
+            trace_service_server::{TraceService, TraceServiceServer},
+            ExportTraceServiceRequest, ExportTraceServiceResponse,
+        },
+        common::v1::any_value::Value,
+        trace::v1::Span,
+    };
+    use std::{
+        sync::{mpsc, Mutex},
+        time::Duration,
+    };
+
+    //Stands in for an OTLP collector, passing on every export it receives
+    struct Collector(Mutex<mpsc::Sender<ExportTraceServiceRequest>>);
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(
+            &self,
+            request: tonic::Request<ExportTraceServiceRequest>,
+        ) -> std::result::Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status>
+        {
+            let _ = self.0.lock().unwrap().send(request.into_inner());
+            Ok(tonic::Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    fn attribute<'a>(span: &'a Span, key: &str) -> Option<&'a Value> {
+        span.attributes
+            .iter()
+            .find(|attribute| attribute.key == key)?
+            .value
+            .as_ref()?
+            .value
+            .as_ref()
+    }
+
+    #[test]
+    fn exports_request_spans_to_the_collector() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let listener = runtime
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        runtime.spawn(
+            tonic::transport::Server::builder()
+                .add_service(TraceServiceServer::new(Collector(Mutex::new(sender))))
+                .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+        );
+
+        //The batch exporter runs on the runtime the pipeline is installed from
+        let _entered = runtime.enter();
+        let (subscriber, telemetry) = subscriber(&LoggingConfig {
+            level: "info".into(),
+            otlp_endpoint: Some(format!("http://{address}")),
+            ..LoggingConfig::default()
+        })
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static(REQUEST_ID_HEADER),
+            HeaderValue::from_static("req-7"),
+        );
+        let request_id = request_id(&headers);
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("http_request", request_id = %request_id).in_scope(|| {
+                tracing::info_span!("search").in_scope(|| tracing::info!("Searched"));
+            });
+        });
+        //Dropping flushes the batch
+        drop(telemetry);
+
+        let mut spans: Vec<Span> = Vec::new();
+        while spans.len() < 2 {
+            let request = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+            spans.extend(
+                request
+                    .resource_spans
+                    .into_iter()
+                    .flat_map(|resource| resource.scope_spans)
+                    .flat_map(|scope| scope.spans),
+            );
+        }
+        let request = spans
+            .iter()
+            .find(|span| span.name == "http_request")
+            .unwrap();
+        let search = spans.iter().find(|span| span.name == "search").unwrap();
+        assert_eq!(
+            attribute(request, "request_id"),
+            Some(&Value::StringValue("req-7".into()))
+        );
+        assert_eq!(request.trace_id.len(), 16);
+        assert!(request.trace_id.iter().any(|byte| *byte != 0));
+        assert_eq!(search.trace_id, request.trace_id);
+        assert_eq!(search.parent_span_id, request.span_id);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::github::{File, Repository};
+use crate::hosts::Hosts;
+
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+
+//A file, or lines of one, the agent read, referenced from the answer as [id]
+#[derive(Debug, Clone, Serialize)]
+pub struct Citation {
+    pub id: usize,
+    pub path: String,
+    //1-based and inclusive, spanning the whole file unless the agent read a range
+    pub start_line: usize,
+    pub end_line: usize,
+    //None when the index doesn't know which commit the content is from, the url then follows the branch
+    pub commit: Option<String>,
+    pub url: String,
+    //Whether the answer refers to it
+    pub cited: bool,
+}
+
+#[derive(Default)]
+pub struct Sources {
+    citations: Vec<Citation>,
+    by_range: HashMap<(String, usize, usize), usize>,
+    //Every path a tool returned, including path searches whose files weren't read
+    paths: BTreeSet<String>,
+}
+
+impl Sources {
+    //Returns the id the model is told to cite the file by
+    pub fn add_file(&mut self, hosts: &Hosts, repository: &Repository, file: &File) -> usize {
+        self.add_lines(hosts, repository, file, (1, file.line_count.max(1)))
+    }
+
+    pub fn add_lines(
+        &mut self,
+        hosts: &Hosts,
+        repository: &Repository,
+        file: &File,
+        lines: (usize, usize),
+    ) -> usize {
+        self.paths.insert(file.path.clone());
+        let key = (file.path.clone(), lines.0, lines.1);
+        if let Some(id) = self.by_range.get(&key) {
+            return *id;
+        }
+        let id = self.citations.len() + 1;
+        //Links at the fetched commit show exactly the content the model read
+        let commit = file.indexed_commit.as_ref().or(file.last_commit.as_ref());
+        self.citations.push(Citation {
+            id,
+            path: file.path.clone(),
+            start_line: lines.0,
+            end_line: lines.1,
+            commit: commit.cloned(),
+            url: hosts.permalink(repository, commit.map(String::as_str), &file.path, lines),
+            cited: false,
+        });
+        self.by_range.insert(key, id);
+        id
+    }
+
+    pub fn add_path(&mut self, path: &str) {
+        self.paths.insert(path.to_string());
+    }
+
+    //Marks the citations the answer uses and returns the references that don't check out
+    pub fn verify(&mut self, answer: &str) -> Vec<String> {
+        let (prose, spans) = split_code(answer);
+        let mut unverified: Vec<String> = Vec::new();
+        for (id, attached) in cited_ids(&prose) {
+            match self.citations.get_mut(id.wrapping_sub(1)) {
+                Some(citation) => citation.cited = true,
+                //Unquoted code such as arr[0], rather than a citation
+                None if attached => {}
+                None => unverified.push(format!("[{id}]")),
+            }
+        }
+        for path in quoted_paths(&spans) {
+            if !self.returned(path) {
+                unverified.push(path.to_string());
+            }
+        }
+        let mut seen = BTreeSet::new();
+        unverified.retain(|reference| seen.insert(reference.clone()));
+        unverified
+    }
+
+    //Directories count when a returned path is inside them
+    fn returned(&self, path: &str) -> bool {
+        let directory = format!("{}/", path.trim_end_matches('/'));
+        self.paths
+            .iter()
+            .any(|returned| returned == path || returned.starts_with(&directory))
+    }
+
+    pub fn into_citations(self) -> Vec<Citation> {
+        self.citations
+    }
+}
+
+//Splits the answer into prose and code spans, fenced blocks are dropped since they're neither
+fn split_code(answer: &str) -> (Vec<&str>, Vec<&str>) {
+    let mut prose = Vec::new();
+    let mut spans = Vec::new();
+    let mut fenced = false;
+    for line in answer.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            fenced = !fenced;
+            continue;
+        }
+        if fenced {
+            continue;
+        }
+        //Backticks alternate between prose and code within a line
+        for (index, part) in line.split('`').enumerate() {
+            if index % 2 == 0 {
+                prose.push(part);
+            } else {
+                spans.push(part);
+            }
+        }
+    }
+    (prose, spans)
+}
+
+//Ids written as [1], several can be combined as [1][3]. Also tells whether the
+//marker is attached to an identifier, as indexing in unquoted code would be
+fn cited_ids(prose: &[&str]) -> Vec<(usize, bool)> {
+    let mut ids = Vec::new();
+    for text in prose {
+        for (start, _) in text.match_indices('[') {
+            let id = text[start + 1..]
+                .split_once(']')
+                .and_then(|(id, _)| id.trim().parse().ok());
+            if let Some(id) = id {
+                let attached = text[..start]
+                    .chars()
+                    .next_back()
+                    .is_some_and(|c| c.is_alphanumeric() || c == '_');
+                ids.push((id, attached));
+            }
+        }
+    }
+    ids
+}
+
+//Code spans that look like file paths, which is how the model is told to name files
+fn quoted_paths<'a>(spans: &[&'a str]) -> Vec<&'a str> {
+    spans
+        .iter()
+        //A line suffix such as src/main.rs:12 still names the file
+        .filter_map(|span| span.trim().trim_start_matches("./").split(':').next())
+        .filter(|span| span.contains('/') && !span.contains(char::is_whitespace))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::path::Path;
+
+    fn ids(answer: &str) -> Vec<(usize, bool)> {
+        cited_ids(&split_code(answer).0)
+    }
+
+    fn file(path: &str) -> File {
+        File::new(
+            path.to_string(),
+            "a\nb\nc\n".to_string(),
+            Some("abc123".into()),
+        )
+    }
+
+    #[test]
+    fn parses_citation_markers() {
+        assert_eq!(
+            ids("Embeddings are pooled [1], then normalized [2][3]. See [ 4 ]."),
+            [(1, false), (2, false), (3, false), (4, false)]
+        );
+        assert_eq!(ids("Done in main.rs[2]"), [(2, true)]);
+        assert!(ids("No citations [here] or [] or [-1]").is_empty());
+    }
+
+    #[test]
+    fn ignores_brackets_in_code() {
+        let answer = "The first item `arr[0]` is used [1].\n\
+                      ```rust\n\
+                      let v = values[2];\n\
+                      ```\n\
+                      ~~~\n\
+                      [3]\n\
+                      ~~~\n\
+                      Then `v[1]` and [4]";
+        assert_eq!(ids(answer), [(1, false), (4, false)]);
+    }
+
+    #[test]
+    fn finds_quoted_paths_outside_fences() {
+        let (_, spans) = split_code(
+            "See `src/main.rs:12` and `./src/db/mod.rs`, not `cargo run`.\n```\n`src/fake.rs`\n```",
+        );
+        assert_eq!(quoted_paths(&spans), ["src/main.rs", "src/db/mod.rs"]);
+    }
+
+    #[test]
+    fn verifies_citations_against_issued_ids() {
+        let hosts = Hosts::new(&Config::default()).unwrap();
+        let repository = Repository::local(Path::new(".")).unwrap();
+        let mut sources = Sources::default();
+        assert_eq!(
+            sources.add_file(&hosts, &repository, &file("src/main.rs")),
+            1
+        );
+        assert_eq!(
+            sources.add_lines(&hosts, &repository, &file("src/db/mod.rs"), (1, 2)),
+            2
+        );
+        assert_eq!(
+            sources.add_file(&hosts, &repository, &file("src/main.rs")),
+            1
+        );
+        sources.add_path("src/github/mod.rs");
+
+        let unverified = sources.verify(
+            "Main is in `src/main.rs` [1], `values[3]` and items[0] are code, \
+             the index lives under `src/github` and `src/llm/mod.rs` [7].",
+        );
+        assert_eq!(unverified, ["[7]", "src/llm/mod.rs"]);
+        let cited: Vec<bool> = sources
+            .into_citations()
+            .iter()
+            .map(|citation| citation.cited)
+            .collect();
+        assert_eq!(cited, [true, false]);
+    }
+}
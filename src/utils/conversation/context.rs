@@ -0,0 +1,389 @@
+use crate::config::LlmConfig;
+use crate::llm::{ChatMessage, ChatRequest, Role, ToolCall};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::HashMap;
+use tiktoken_rs::{cl100k_base, CoreBPE};
+
+//The encoding of the OpenAI chat models, other models tokenize differently but close enough to budget with
+static BPE: Lazy<CoreBPE> = Lazy::new(|| cl100k_base().expect("bundled encoding"));
+//Role and separators every message is wrapped in
+const TOKENS_PER_MESSAGE: usize = 4;
+//Room kept for the reply when the request doesn't set max_tokens
+const DEFAULT_REPLY_TOKENS: usize = 512;
+const DROPPED_MARKER: &str = "[elided]";
+
+pub fn count_tokens(text: &str) -> usize {
+    BPE.encode_with_special_tokens(text).len()
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ElisionKind {
+    //Cut to its first llm.max_result_tokens tokens
+    Truncated,
+    //Replaced by a summary written by the model
+    Summarized,
+    //Removed from the conversation to make room for newer results
+    Dropped,
+}
+
+//A tool result the model didn't get to see in full
+#[derive(Debug, Clone, Serialize)]
+pub struct Elision {
+    pub function: String,
+    pub arguments: String,
+    pub kind: ElisionKind,
+    pub tokens: usize,
+    pub kept_tokens: usize,
+}
+
+pub struct Context {
+    context_tokens: usize,
+    max_result_tokens: usize,
+    elided: Vec<Elision>,
+}
+
+impl Context {
+    pub fn new(config: &LlmConfig) -> Context {
+        Context {
+            context_tokens: config.context_tokens,
+            max_result_tokens: config.max_result_tokens,
+            elided: Vec::new(),
+        }
+    }
+
+    pub fn elided(&self) -> &[Elision] {
+        &self.elided
+    }
+
+    //Whether a tool result has to be shortened before it's added
+    pub fn exceeds_result_limit(&self, result: &str) -> bool {
+        count_tokens(result) > self.max_result_tokens
+    }
+
+    //Keeps the start of the result, where file paths and headers are
+    pub fn truncate_result(&mut self, call: &ToolCall, result: String) -> String {
+        let tokens = BPE.encode_with_special_tokens(&result);
+        if tokens.len() <= self.max_result_tokens {
+            return result;
+        }
+        let mut kept = truncate_tokens(&tokens, self.max_result_tokens);
+        self.record(
+            call,
+            ElisionKind::Truncated,
+            tokens.len(),
+            count_tokens(&kept),
+        );
+        kept.push_str(&format!(
+            "\n[truncated, {} of {} tokens shown]",
+            self.max_result_tokens,
+            tokens.len()
+        ));
+        kept
+    }
+
+    //Input for a summary, cut so the summarizing request itself fits
+    pub fn summary_input(&self, result: &str) -> String {
+        let tokens = BPE.encode_with_special_tokens(result);
+        truncate_tokens(&tokens, self.context_tokens / 2)
+    }
+
+    pub fn record_summary(&mut self, call: &ToolCall, result: &str, summary: &str) {
+        self.record(
+            call,
+            ElisionKind::Summarized,
+            count_tokens(result),
+            count_tokens(summary),
+        );
+    }
+
+    //Drops the oldest tool results until the request and the reply fit the context
+    pub fn fit(&mut self, request: &mut ChatRequest) {
+        let reply_tokens = request
+            .max_tokens
+            .map_or(DEFAULT_REPLY_TOKENS, |max_tokens| max_tokens as usize);
+        let tools_tokens =
+            serde_json::to_string(&request.tools).map_or(0, |tools| count_tokens(&tools));
+        let budget = self
+            .context_tokens
+            .saturating_sub(reply_tokens + tools_tokens);
+        let mut total: usize = request.messages.iter().map(message_tokens).sum();
+        if total <= budget {
+            return;
+        }
+
+        let calls: HashMap<String, ToolCall> = request
+            .messages
+            .iter()
+            .flat_map(|message| message.tool_calls.iter().cloned())
+            .map(|call| (call.id.clone(), call))
+            .collect();
+        for message in request.messages.iter_mut() {
+            if total <= budget {
+                break;
+            }
+            let call = match (&message.role, &message.tool_call_id) {
+                (Role::Tool, Some(id)) => calls.get(id),
+                _ => None,
+            };
+            let call = match call {
+                Some(call) if is_droppable(message) => call,
+                _ => continue,
+            };
+            let before = message_tokens(message);
+            //The result is replaced rather than removed, its call still needs an answer
+            message.content = Some(format!(
+                "{DROPPED_MARKER} the result of {} was removed to fit the context",
+                call.function.name
+            ));
+            let after = message_tokens(message);
+            total = (total + after).saturating_sub(before);
+            self.record(call, ElisionKind::Dropped, before, 0);
+        }
+        if total > budget {
+            tracing::warn!(
+                tokens = total,
+                budget,
+                "The conversation doesn't fit the context even without tool results"
+            );
+        }
+    }
+
+    //Listed in the answer prompt, so the model can tell which results it only saw part of
+    pub fn elision_notes(&self) -> Option<String> {
+        if self.elided.is_empty() {
+            return None;
+        }
+        let mut notes =
+            String::from("These function results were shortened or removed to fit the context:");
+        for elision in &self.elided {
+            notes.push_str(&format!(
+                "\n- {}({}): {}",
+                elision.function,
+                elision.arguments,
+                match elision.kind {
+                    ElisionKind::Truncated => "truncated",
+                    ElisionKind::Summarized => "summarized",
+                    ElisionKind::Dropped => "removed",
+                }
+            ));
+        }
+        Some(notes)
+    }
+
+    fn record(&mut self, call: &ToolCall, kind: ElisionKind, tokens: usize, kept_tokens: usize) {
+        tracing::debug!(function = %call.function.name, ?kind, tokens, kept_tokens, "Elided a tool result");
+        self.elided.push(Elision {
+            function: call.function.name.clone(),
+            arguments: call.function.arguments.clone(),
+            kind,
+            tokens,
+            kept_tokens,
+        });
+    }
+}
+
+//Empty results and ones already dropped gain nothing from dropping
+fn is_droppable(message: &ChatMessage) -> bool {
+    message
+        .content
+        .as_deref()
+        .is_some_and(|content| !content.is_empty() && !content.starts_with(DROPPED_MARKER))
+}
+
+fn message_tokens(message: &ChatMessage) -> usize {
+    let calls: usize = message
+        .tool_calls
+        .iter()
+        .map(|call| count_tokens(&call.function.name) + count_tokens(&call.function.arguments))
+        .sum();
+    TOKENS_PER_MESSAGE + message.content.as_deref().map_or(0, count_tokens) + calls
+}
+
+//A token boundary can split a character, in which case up to three more tokens are dropped
+fn truncate_tokens(tokens: &[usize], max_tokens: usize) -> String {
+    let limit = max_tokens.min(tokens.len());
+    let mut end = limit;
+    loop {
+        match BPE.decode(tokens[..end].to_vec()) {
+            Ok(text) => return text,
+            Err(_) if end > 0 && limit - end < 3 => end -= 1,
+            Err(_) => return String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::{ChatOptions, FunctionCall, ToolKind};
+
+    fn call(id: &str, name: &str) -> ToolCall {
+        ToolCall {
+            id: id.into(),
+            kind: ToolKind::Function,
+            function: FunctionCall {
+                name: name.into(),
+                arguments: format!(r#"{{"query": "{id}"}}"#),
+            },
+        }
+    }
+
+    //Two turns of two results each, followed by the answer prompt
+    fn request(user: &str) -> ChatRequest {
+        let mut request = ChatRequest::new(&LlmConfig::default(), &ChatOptions::default());
+        request.max_tokens = Some(100);
+        request.messages = vec![
+            ChatMessage::system("You answer questions about a repository."),
+            ChatMessage::user(user),
+        ];
+        for turn in [["1", "2"], ["3", "4"]] {
+            let calls: Vec<ToolCall> = turn.iter().map(|id| call(id, "search_codebase")).collect();
+            request.messages.push(ChatMessage {
+                role: Role::Assistant,
+                content: None,
+                tool_calls: calls.clone(),
+                tool_call_id: None,
+            });
+            for call in calls {
+                request
+                    .messages
+                    .push(ChatMessage::tool(&call.id, "src/main.rs\n".repeat(50)));
+            }
+        }
+        request
+            .messages
+            .push(ChatMessage::user("Answer the question."));
+        request
+    }
+
+    fn context(context_tokens: usize) -> Context {
+        Context::new(&LlmConfig {
+            context_tokens,
+            max_result_tokens: 20,
+            ..LlmConfig::default()
+        })
+    }
+
+    //Context that leaves the messages `over` tokens short
+    fn short_by(request: &ChatRequest, over: usize) -> Context {
+        let messages: usize = request.messages.iter().map(message_tokens).sum();
+        let tools = count_tokens(&serde_json::to_string(&request.tools).unwrap());
+        context(messages + tools + 100 - over)
+    }
+
+    fn dropped(request: &ChatRequest) -> Vec<&str> {
+        request
+            .messages
+            .iter()
+            .filter(|message| !is_droppable(message) && message.role == Role::Tool)
+            .filter_map(|message| message.tool_call_id.as_deref())
+            .collect()
+    }
+
+    #[test]
+    fn fitting_requests_are_left_alone() {
+        let mut request = request("Where is the server started?");
+        let mut context = short_by(&request, 0);
+        context.fit(&mut request);
+        assert!(dropped(&request).is_empty());
+        assert!(context.elided().is_empty());
+        assert!(context.elision_notes().is_none());
+    }
+
+    #[test]
+    fn oldest_results_are_dropped_first() {
+        let mut request = request("Where is the server started?");
+        let result_tokens = message_tokens(&request.messages[3]);
+        //Dropping a result still leaves its marker, so one result's worth takes two to make up
+        let mut context = short_by(&request, result_tokens + 1);
+        context.fit(&mut request);
+
+        assert_eq!(dropped(&request), vec!["1", "2"]);
+        let elided: Vec<(&str, usize, usize)> = context
+            .elided()
+            .iter()
+            .map(|elision| {
+                assert!(matches!(elision.kind, ElisionKind::Dropped));
+                (
+                    elision.arguments.as_str(),
+                    elision.tokens,
+                    elision.kept_tokens,
+                )
+            })
+            .collect();
+        assert_eq!(
+            elided,
+            vec![
+                (r#"{"query": "1"}"#, result_tokens, 0),
+                (r#"{"query": "2"}"#, result_tokens, 0)
+            ]
+        );
+        let notes = context.elision_notes().unwrap();
+        assert!(notes.contains(r#"search_codebase({"query": "2"}): removed"#));
+
+        //Dropped results stay dropped and aren't recorded again
+        context.fit(&mut request);
+        assert_eq!(context.elided().len(), 2);
+    }
+
+    #[test]
+    fn oversized_prompt_drops_every_result_and_keeps_the_rest() {
+        let question = "Where is the server started? ".repeat(200);
+        let mut request = request(&question);
+        let mut context = context(500);
+        context.fit(&mut request);
+
+        assert_eq!(dropped(&request), vec!["1", "2", "3", "4"]);
+        assert_eq!(context.elided().len(), 4);
+        assert_eq!(request.messages.len(), 9);
+        assert_eq!(
+            request.messages[1].content.as_deref(),
+            Some(question.as_str())
+        );
+        assert_eq!(
+            request.messages[8].content.as_deref(),
+            Some("Answer the question.")
+        );
+    }
+
+    #[test]
+    fn long_results_are_truncated_and_recorded() {
+        let mut context = context(1000);
+        let short = "src/main.rs".to_string();
+        assert!(!context.exceeds_result_limit(&short));
+        assert_eq!(
+            context.truncate_result(&call("1", "read_file"), short.clone()),
+            short
+        );
+
+        let long = "src/main.rs\n".repeat(50);
+        assert!(context.exceeds_result_limit(&long));
+        let truncated = context.truncate_result(&call("2", "read_file"), long.clone());
+        let total = count_tokens(&long);
+        assert!(truncated.ends_with(&format!("\n[truncated, 20 of {total} tokens shown]")));
+        assert!(long.starts_with(truncated.lines().next().unwrap()));
+
+        let [elision] = context.elided() else {
+            panic!("expected a single elision, got {:?}", context.elided());
+        };
+        assert!(matches!(elision.kind, ElisionKind::Truncated));
+        assert_eq!((elision.tokens, elision.kept_tokens), (total, 20));
+    }
+
+    #[test]
+    fn truncation_never_splits_a_character() {
+        let text = "🦀".repeat(10);
+        let tokens = BPE.encode_with_special_tokens(&text);
+        //Each crab takes more than one token, so most limits fall inside one
+        assert!(tokens.len() > 10);
+        for max_tokens in 0..=tokens.len() {
+            let kept = truncate_tokens(&tokens, max_tokens);
+            assert!(text.starts_with(&kept), "{max_tokens}: {kept:?}");
+            assert!(count_tokens(&kept) <= max_tokens);
+        }
+        assert_eq!(truncate_tokens(&tokens, tokens.len() + 5), text);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::github::File;
+
+use std::collections::BTreeMap;
+
+//Reads longer than this are cut, the model is told how to read on
+const MAX_READ_LINES: usize = 300;
+//Above this many files a listing only shows the entries directly under the directory
+const MAX_LIST_ENTRIES: usize = 200;
+
+pub struct Excerpt {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+//Numbered lines, so the model can tell which lines it's citing
+pub fn excerpt(file: &File, start: Option<usize>, end: Option<usize>) -> Result<Excerpt, String> {
+    let line_count = file.line_count.max(1);
+    let start = start.unwrap_or(1).max(1);
+    if start > line_count {
+        return Err(format!(
+            "{} has {line_count} lines, start_line {start} is past the end",
+            file.path
+        ));
+    }
+    let requested_end = end.unwrap_or(line_count).min(line_count);
+    if requested_end < start {
+        return Err(format!(
+            "end_line {requested_end} is before start_line {start}"
+        ));
+    }
+    let end = requested_end.min(start + MAX_READ_LINES - 1);
+
+    let mut text = format!(
+        "File path: {}\nLines {start}-{end} of {line_count}\n",
+        file.path
+    );
+    let lines = file.content.lines().enumerate().skip(start - 1);
+    for (number, line) in lines.take(end - start + 1) {
+        text.push_str(&format!("{}: {line}\n", number + 1));
+    }
+    if end < requested_end {
+        text.push_str(&format!(
+            "[{MAX_READ_LINES} lines shown, read on with start_line {}]",
+            end + 1
+        ));
+    }
+    Ok(Excerpt { start, end, text })
+}
+
+pub struct Listing {
+    pub text: String,
+    //Files named in the listing
+    pub paths: Vec<String>,
+}
+
+pub fn list_directory(paths: Vec<String>, directory: &str) -> Listing {
+    let directory = match directory.trim_start_matches("./").trim_matches('/') {
+        "." => "",
+        directory => directory,
+    };
+    let prefix = if directory.is_empty() {
+        String::new()
+    } else {
+        format!("{directory}/")
+    };
+    let mut under: Vec<String> = paths
+        .into_iter()
+        .filter(|path| path.starts_with(&prefix))
+        .collect();
+    under.sort();
+    let root = if directory.is_empty() { "." } else { directory };
+    if under.is_empty() {
+        return Listing {
+            text: format!("No files under {root}"),
+            paths: Vec::new(),
+        };
+    }
+
+    if under.len() <= MAX_LIST_ENTRIES {
+        let mut text = format!("{root}/\n");
+        let mut previous: Vec<&str> = Vec::new();
+        for path in &under {
+            let parts: Vec<&str> = path[prefix.len()..].split('/').collect();
+            //Directories shared with the previous path were printed already
+            let shared = previous
+                .iter()
+                .zip(&parts[..parts.len() - 1])
+                .take_while(|(a, b)| a == b)
+                .count();
+            for (depth, part) in parts.iter().enumerate().skip(shared) {
+                let slash = if depth + 1 < parts.len() { "/" } else { "" };
+                text.push_str(&format!("{}{part}{slash}\n", "  ".repeat(depth + 1)));
+            }
+            previous = parts[..parts.len() - 1].to_vec();
+        }
+        return Listing { text, paths: under };
+    }
+
+    //Too many files to show, directories are summarized by their file count
+    let mut entries: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut files: Vec<String> = Vec::new();
+    for path in &under {
+        match path[prefix.len()..].split_once('/') {
+            Some((child, _)) => *entries.entry(child).or_default() += 1,
+            None => files.push(path.clone()),
+        }
+    }
+    let mut text = format!(
+        "{root}/ has {} files, showing its entries only, list a subdirectory to see more\n",
+        under.len()
+    );
+    for (child, count) in &entries {
+        text.push_str(&format!("  {child}/ ({count} files)\n"));
+    }
+    for path in &files {
+        text.push_str(&format!("  {}\n", &path[prefix.len()..]));
+    }
+    Listing { text, paths: files }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(lines: usize) -> File {
+        let content: String = (1..=lines).map(|line| format!("line {line}\n")).collect();
+        File::new("src/lib.rs".into(), content, None)
+    }
+
+    fn paths(paths: &[&str]) -> Vec<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    #[test]
+    fn excerpt_numbers_the_requested_lines() {
+        let excerpt = excerpt(&file(10), Some(3), Some(4)).unwrap();
+        assert_eq!((excerpt.start, excerpt.end), (3, 4));
+        assert_eq!(
+            excerpt.text,
+            "File path: src/lib.rs\nLines 3-4 of 10\n3: line 3\n4: line 4\n"
+        );
+
+        //Missing bounds read the whole file and an end past it is clamped
+        let whole = super::excerpt(&file(3), None, Some(50)).unwrap();
+        assert_eq!((whole.start, whole.end), (1, 3));
+        let from_zero = super::excerpt(&file(3), Some(0), None).unwrap();
+        assert_eq!((from_zero.start, from_zero.end), (1, 3));
+    }
+
+    #[test]
+    fn excerpt_rejects_ranges_outside_the_file() {
+        assert_eq!(
+            excerpt(&file(10), Some(11), None).err().unwrap(),
+            "src/lib.rs has 10 lines, start_line 11 is past the end"
+        );
+        assert_eq!(
+            excerpt(&file(10), Some(5), Some(4)).err().unwrap(),
+            "end_line 4 is before start_line 5"
+        );
+        //An empty file still has a first line to read
+        let empty = excerpt(&file(0), None, None).unwrap();
+        assert_eq!((empty.start, empty.end), (1, 1));
+    }
+
+    #[test]
+    fn long_reads_are_cut_with_a_way_to_read_on() {
+        let file = file(MAX_READ_LINES + 50);
+        let first = excerpt(&file, None, None).unwrap();
+        assert_eq!((first.start, first.end), (1, MAX_READ_LINES));
+        assert!(first.text.ends_with(&format!(
+            "{MAX_READ_LINES}: line {MAX_READ_LINES}\n[{MAX_READ_LINES} lines shown, read on with start_line {}]",
+            MAX_READ_LINES + 1
+        )));
+
+        let rest = excerpt(&file, Some(MAX_READ_LINES + 1), None).unwrap();
+        assert_eq!(rest.end, MAX_READ_LINES + 50);
+        assert!(!rest.text.contains("lines shown"));
+    }
+
+    #[test]
+    fn listing_nests_directories() {
+        let listing = list_directory(
+            paths(&[
+                "src/main.rs",
+                "README.md",
+                "src/db/mod.rs",
+                "src/db/qdrant.rs",
+                "src/lib.rs",
+            ]),
+            "",
+        );
+        assert_eq!(
+            listing.text,
+            "./\n  README.md\n  src/\n    db/\n      mod.rs\n      qdrant.rs\n    lib.rs\n    main.rs\n"
+        );
+        assert_eq!(listing.paths.len(), 5);
+    }
+
+    #[test]
+    fn listed_directory_prefixes_are_normalized() {
+        let all = paths(&["src/main.rs", "src/db/mod.rs", "srcs/other.rs", "README.md"]);
+        for directory in ["src", "./src", "/src", "src/", "./src/"] {
+            let listing = list_directory(all.clone(), directory);
+            assert_eq!(
+                listing.text, "src/\n  db/\n    mod.rs\n  main.rs\n",
+                "{directory}"
+            );
+            assert_eq!(listing.paths, paths(&["src/db/mod.rs", "src/main.rs"]));
+        }
+        for root in ["", ".", "./", "/"] {
+            assert_eq!(list_directory(all.clone(), root).paths.len(), 4, "{root:?}");
+        }
+        let missing = list_directory(all, "./tests");
+        assert_eq!(missing.text, "No files under tests");
+        assert!(missing.paths.is_empty());
+    }
+
+    #[test]
+    fn large_listings_collapse_to_entries() {
+        let mut all: Vec<String> = (0..MAX_LIST_ENTRIES)
+            .map(|i| format!("src/generated/{i}.rs"))
+            .collect();
+        all.extend(paths(&["src/main.rs", "src/db/mod.rs", "src/db/qdrant.rs"]));
+
+        let listing = list_directory(all.clone(), "src");
+        assert_eq!(
+            listing.text,
+            format!(
+                "src/ has {} files, showing its entries only, list a subdirectory to see more\n  db/ (2 files)\n  generated/ ({MAX_LIST_ENTRIES} files)\n  main.rs\n",
+                MAX_LIST_ENTRIES + 3
+            )
+        );
+        //Only files named in the listing can be cited
+        assert_eq!(listing.paths, paths(&["src/main.rs"]));
+
+        //Exactly at the limit everything is still shown
+        let listing = list_directory(all, "src/generated");
+        assert_eq!(listing.paths.len(), MAX_LIST_ENTRIES);
+        assert!(listing.text.starts_with("src/generated/\n"));
+    }
+}
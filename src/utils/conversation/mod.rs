@@ -1,82 +1,606 @@
+mod citations;
+mod context;
+mod files;
 mod prompts;
 
-use crate::github::Repository;
-use crate::prelude::*;
-use openai_api_rs::v1::{
-    api::Client,
-    chat_completion::{
-        ChatCompletionMessage, ChatCompletionRequest, ChatCompletionResponse, MessageRole,
-    },
+use crate::auth::Tenant;
+use crate::config::Config;
+use crate::db::{RepositoryEmbeddingsDB, SearchFilter};
+use crate::embeddings::EmbeddingsModel;
+use crate::github::{File, Repository};
+use crate::hosts::Hosts;
+use crate::llm::{
+    self, ChatBackend, ChatMessage, ChatOptions, ChatRequest, ChatResponse, FunctionCall, ToolCall,
+    Usage,
 };
+use crate::metrics;
+use crate::prelude::*;
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 
-use serde::Deserialize;
-use std::env;
+use citations::{Citation, Sources};
+use context::{Context, Elision};
+use files::{excerpt, list_directory, Excerpt, Listing};
+use prompts::{Prompts, Rendered};
 
-use prompts::{generate_completion_request, system_message};
+const MAX_PATH_RESULTS: usize = 20;
+const STEP_LIMIT_REACHED: &str = "Not run, the step limit was reached";
 
 #[derive(Deserialize)]
 pub struct Query {
     pub repository: Repository,
     pub query: String,
+    #[serde(default)]
+    pub filter: SearchFilter,
+    #[serde(flatten)]
+    pub options: ChatOptions,
 }
 
-impl ToString for Query {
-    fn to_string(&self) -> String {
-        let Query {
-            repository:
-                Repository {
-                    owner,
-                    name,
-                    branch,
-                },
-            query,
-        } = self;
-        format!(
-            "##Repository Info##\nOwner:{}\nName:{}\nBranch:{}\n##User Query##\nQuery:{}",
-            owner, name, branch, query
-        )
+#[derive(Serialize)]
+pub struct Answer {
+    pub answer: String,
+    pub model: String,
+    pub prompt_version: String,
+    pub prompt_digest: String,
+    //The function calls the answer is based on, in the order they were made
+    pub steps: Vec<FunctionCall>,
+    //Every file the agent read, the answer refers to them by id
+    pub citations: Vec<Citation>,
+    //Citations and paths in the answer that no tool returned, the answer shouldn't be trusted on these
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unverified: Vec<String>,
+    pub usage: Usage,
+    //Results the answer is based on that the model only saw part of
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub elided: Vec<Elision>,
+}
+
+enum ToolOutput {
+    Files(Vec<File>),
+    Paths(Vec<String>),
+    File(File),
+    Excerpt(File, Excerpt),
+    Listing(Listing),
+    Text(String),
+}
+
+//Every tool's arguments, which ones are required depends on the tool
+#[derive(Deserialize)]
+struct Arguments {
+    query: Option<String>,
+    path: Option<String>,
+    start_line: Option<usize>,
+    end_line: Option<usize>,
+}
+
+fn required<T>(argument: Option<T>, name: &str) -> Result<T> {
+    argument.ok_or_else(|| Error::InvalidRequest(format!("{name} is required")))
+}
+
+//The model and the prompts it's given, shared by every conversation
+pub struct Agent {
+    backend: Arc<dyn ChatBackend>,
+    prompts: Prompts,
+}
+
+impl Agent {
+    pub fn new(config: &Config) -> Result<Agent> {
+        Ok(Agent {
+            backend: llm::backend(&config.llm)?,
+            prompts: Prompts::load(config.llm.prompts_dir.as_deref(), config.llm.reload_prompts)?,
+        })
     }
 }
 
 pub struct Conversation {
     query: Query,
-    client: Client,
-    messages: Vec<ChatCompletionMessage>,
+    backend: Arc<dyn ChatBackend>,
+    prompts: Rendered,
+    prompt_version: String,
+    prompt_digest: String,
+    db: Arc<dyn RepositoryEmbeddingsDB + Send + Sync>,
+    model: Arc<dyn EmbeddingsModel + Send + Sync>,
+    hosts: Arc<Hosts>,
+    config: Arc<Config>,
+    tenant: Arc<Tenant>,
+    request: ChatRequest,
+    context: Context,
+    sources: Sources,
+    steps: Vec<FunctionCall>,
+    usage: Usage,
 }
 
 impl Conversation {
-    pub fn new(query: Query) -> Self {
-        Self {
-            client: Client::new(env::var("OPENAI_API_KEY").unwrap().to_string()),
-            messages: vec![
-                ChatCompletionMessage {
-                    name: None,
-                    function_call: None,
-                    role: MessageRole::system,
-                    content: Some(system_message()),
-                },
-                ChatCompletionMessage {
-                    name: None,
-                    function_call: None,
-                    role: MessageRole::user,
-                    content: Some(query.to_string()),
-                },
-            ],
+    pub fn new(
+        query: Query,
+        agent: &Agent,
+        db: Arc<dyn RepositoryEmbeddingsDB + Send + Sync>,
+        model: Arc<dyn EmbeddingsModel + Send + Sync>,
+        hosts: Arc<Hosts>,
+        config: Arc<Config>,
+        tenant: Arc<Tenant>,
+    ) -> Result<Self> {
+        query.options.validate()?;
+        let prompt_set = agent.prompts.current();
+        let prompts = prompt_set.render(&query.repository, &query.query, config.llm.max_steps)?;
+        let mut request = ChatRequest::new(&config.llm, &query.options);
+        request.tools = prompts.tools.clone();
+        request.messages = vec![
+            ChatMessage::system(prompts.system.clone()),
+            ChatMessage::user(prompts.user.clone()),
+        ];
+        Ok(Self {
+            backend: agent.backend.clone(),
+            prompts,
+            prompt_version: prompt_set.version.clone(),
+            prompt_digest: prompt_set.digest.clone(),
+            context: Context::new(&config.llm),
+            sources: Sources::default(),
+            query,
+            db,
+            model,
+            hosts,
+            config,
+            tenant,
+            request,
+            steps: Vec::new(),
+            usage: Usage::default(),
+        })
+    }
+
+    fn append_message(&mut self, message: ChatMessage) {
+        self.request.messages.push(message);
+    }
+
+    async fn send_request(&mut self) -> Result<ChatMessage> {
+        self.context.fit(&mut self.request);
+        let response = self.chat(&self.request).await?;
+        self.usage.add(&response.usage);
+        Ok(response.message)
+    }
+
+    #[tracing::instrument(name = "agent_step", skip_all, fields(backend = self.backend.name(), model = %request.model, prompt_version = %self.prompt_version))]
+    async fn chat(&self, request: &ChatRequest) -> Result<ChatResponse> {
+        //Checked every step, a single conversation can use a large part of the budget
+        self.tenant.check_llm_budget()?;
+        let response = self.backend.chat(request).await;
+        let outcome = if response.is_ok() { "success" } else { "error" };
+        metrics::LLM_REQUESTS
+            .with_label_values(&[&request.model, outcome])
+            .inc();
+        let response = response?;
+        for (kind, tokens) in [
+            ("prompt", response.usage.prompt_tokens),
+            ("completion", response.usage.completion_tokens),
+        ] {
+            metrics::LLM_TOKENS
+                .with_label_values(&[&request.model, kind])
+                .inc_by(tokens);
+        }
+        self.tenant.record_llm_tokens(response.usage.total());
+        Ok(response)
+    }
+
+    //Keeps a single result from taking up the context
+    async fn shorten(&mut self, call: &ToolCall, result: String) -> Result<String> {
+        if !self.context.exceeds_result_limit(&result) {
+            return Ok(result);
+        }
+        if !self.config.llm.summarize_results {
+            return Ok(self.context.truncate_result(call, result));
+        }
+        let mut request = ChatRequest::new(&self.config.llm, &self.query.options);
+        request.max_tokens = Some(self.config.llm.max_result_tokens as u32);
+        request.messages = vec![
+            ChatMessage::system(self.prompts.summary.clone()),
+            ChatMessage::user(format!(
+                "Query: {}\n\nResult of {}({}):\n{}",
+                self.query.query,
+                call.function.name,
+                call.function.arguments,
+                self.context.summary_input(&result)
+            )),
+        ];
+        let response = self.chat(&request).await?;
+        self.usage.add(&response.usage);
+        match response.message.content {
+            Some(summary) if !summary.trim().is_empty() => {
+                self.context.record_summary(call, &result, &summary);
+                Ok(summary)
+            }
+            _ => Ok(self.context.truncate_result(call, result)),
+        }
+    }
+
+    pub async fn generate_answer(mut self) -> Result<Answer> {
+        'conversation: while self.steps.len() < self.config.llm.max_steps {
+            let message = self.send_request().await?;
+            //The model answered directly, which is as good as calling none
+            if message.tool_calls.is_empty() {
+                break 'conversation;
+            }
+            let calls = message.tool_calls.clone();
+            self.append_message(message);
+            //A turn can call more tools than there are steps left, the ones past the limit don't run
+            let mut allowed = self.config.llm.max_steps - self.steps.len();
+            let runs: Vec<bool> = calls
+                .iter()
+                .map(|call| {
+                    let run = call.function.name == "none" || allowed > 0;
+                    if call.function.name != "none" {
+                        allowed = allowed.saturating_sub(1);
+                    }
+                    run
+                })
+                .collect();
+            //Calls made in one turn don't depend on each other, so they run at once
+            let conversation = &self;
+            let results = join_all(calls.iter().zip(&runs).map(|(call, run)| async move {
+                if *run {
+                    conversation.run_tool(call).await
+                } else {
+                    Ok(ToolOutput::Text(STEP_LIMIT_REACHED.to_string()))
+                }
+            }))
+            .await;
+            //Every call needs a result, even when the turn also called none
+            let mut done = false;
+            for ((call, output), run) in calls.into_iter().zip(results).zip(runs) {
+                let result = self.render(output?);
+                let result = self.shorten(&call, result).await?;
+                self.append_message(ChatMessage::tool(&call.id, result));
+                if call.function.name == "none" {
+                    done = true;
+                } else if run {
+                    self.steps.push(call.function);
+                }
+            }
+            if done {
+                break 'conversation;
+            }
+        }
+
+        let mut prompt = self.prompts.answer.clone();
+        if let Some(notes) = self.context.elision_notes() {
+            prompt = format!("{prompt}\n\n{notes}");
+        }
+        self.append_message(ChatMessage::user(prompt));
+        self.request.allow_tool_calls = false;
+        let answer = self.send_request().await?.content.unwrap_or_default();
+        let unverified = self.sources.verify(&answer);
+        if !unverified.is_empty() {
+            tracing::warn!(?unverified, "The answer refers to sources no tool returned");
+        }
+        tracing::info!(
+            model = %self.request.model,
+            prompt_version = %self.prompt_version,
+            prompt_digest = %self.prompt_digest,
+            steps = self.steps.len(),
+            tokens = self.usage.total(),
+            "Answered query"
+        );
+        Ok(Answer {
+            answer,
+            model: self.request.model,
+            prompt_version: self.prompt_version,
+            prompt_digest: self.prompt_digest,
+            steps: self.steps,
+            citations: self.sources.into_citations(),
+            unverified,
+            usage: self.usage,
+            elided: self.context.elided().to_vec(),
+        })
+    }
+
+    async fn run_tool(&self, call: &ToolCall) -> Result<ToolOutput> {
+        let FunctionCall { name, arguments } = &call.function;
+        if name == "none" {
+            return Ok(ToolOutput::Text(String::new()));
+        }
+        tracing::debug!(function = %name, arguments = %arguments, "Agent called a function");
+        match self.call_function(&call.function).await {
+            Ok(output) => Ok(output),
+            //Errors are shown to the model so it can correct its arguments
+            Err(Error::InvalidRequest(message)) => Ok(ToolOutput::Text(message)),
+            Err(e) => Err(e),
+        }
+    }
+
+    //Files get their citation id here, calls run concurrently but are rendered in order
+    fn render(&mut self, output: ToolOutput) -> String {
+        let repository = &self.query.repository;
+        match output {
+            ToolOutput::Files(files) if files.is_empty() => "No files found".to_string(),
+            //The model only sees the paths, so hits can't be cited until they're read
+            ToolOutput::Files(files) => files
+                .iter()
+                .map(|file| {
+                    self.sources.add_path(&file.path);
+                    file.path.clone()
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ToolOutput::Paths(paths) if paths.is_empty() => "No paths found".to_string(),
+            ToolOutput::Paths(paths) => {
+                paths.iter().for_each(|path| self.sources.add_path(path));
+                paths.join("\n")
+            }
+            ToolOutput::File(file) => {
+                let id = self.sources.add_file(&self.hosts, repository, &file);
+                format!("[{id}] {}", file.to_string())
+            }
+            ToolOutput::Excerpt(file, excerpt) => {
+                let lines = (excerpt.start, excerpt.end);
+                let id = self
+                    .sources
+                    .add_lines(&self.hosts, repository, &file, lines);
+                format!("[{id}] {}", excerpt.text)
+            }
+            ToolOutput::Listing(listing) => {
+                listing
+                    .paths
+                    .iter()
+                    .for_each(|path| self.sources.add_path(path));
+                listing.text
+            }
+            ToolOutput::Text(text) => text,
+        }
+    }
+
+    async fn call_function(&self, call: &FunctionCall) -> Result<ToolOutput> {
+        let Arguments {
             query,
+            path,
+            start_line,
+            end_line,
+        } = serde_json::from_str(&call.arguments)
+            .map_err(|e| Error::InvalidRequest(format!("Invalid arguments: {e}")))?;
+        let repository = self.query.repository.clone();
+        match call.name.as_str() {
+            "search_codebase" => {
+                let query = required(query, "query")?;
+                let files = self
+                    .db
+                    .get_relevant_files(
+                        repository,
+                        self.model.embed(&query)?,
+                        self.config.retrieval.limit,
+                        &self.query.filter,
+                    )
+                    .await?;
+                Ok(ToolOutput::Files(files))
+            }
+            "search_path" => {
+                let query = required(query, "query")?;
+                let paths = self.db.get_file_paths(repository).await?.file_paths;
+                Ok(ToolOutput::Paths(search_paths(paths, &query)))
+            }
+            "search_file" => {
+                let query = required(query, "query")?;
+                let path = required(path, "path")?;
+                let filter = SearchFilter {
+                    path_prefix: Some(path.clone()),
+                    ..SearchFilter::default()
+                };
+                let file = self
+                    .db
+                    .get_relevant_files(repository, self.model.embed(&query)?, 1, &filter)
+                    .await?
+                    .into_iter()
+                    .find(|file| file.path == path);
+                Ok(file.map_or_else(
+                    || ToolOutput::Text(format!("{path} is not in the repository")),
+                    ToolOutput::File,
+                ))
+            }
+            "read_file" => {
+                let path = required(path, "path")?;
+                let path = path.trim_start_matches("./");
+                match self.db.get_file(repository, path).await? {
+                    Some(file) => Ok(excerpt(&file, start_line, end_line)
+                        .map_or_else(ToolOutput::Text, |excerpt| {
+                            ToolOutput::Excerpt(file, excerpt)
+                        })),
+                    None => Ok(ToolOutput::Text(format!(
+                        "{path} is not in the repository, list its directory to find the right path"
+                    ))),
+                }
+            }
+            "list_directory" => {
+                let paths = self.db.get_file_paths(repository).await?.file_paths;
+                Ok(ToolOutput::Listing(list_directory(
+                    paths,
+                    path.as_deref().unwrap_or_default(),
+                )))
+            }
+            name => Err(Error::InvalidRequest(format!("Unknown function {name}"))),
         }
     }
+}
+
+//Paths ranked by how many of the query's words they contain, shorter paths first on ties
+fn search_paths(paths: Vec<String>, query: &str) -> Vec<String> {
+    let query = query.to_lowercase();
+    let words: Vec<&str> = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let mut scored: Vec<(usize, String)> = paths
+        .into_iter()
+        .filter_map(|path| {
+            let lowercase = path.to_lowercase();
+            let score = words
+                .iter()
+                .filter(|word| lowercase.contains(*word))
+                .count();
+            (score > 0).then_some((score, path))
+        })
+        .collect();
+    scored.sort_by(|(a_score, a), (b_score, b)| b_score.cmp(a_score).then(a.len().cmp(&b.len())));
+    scored
+        .into_iter()
+        .take(MAX_PATH_RESULTS)
+        .map(|(_, path)| path)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Tenants;
+    use crate::config::AuthConfig;
+    use crate::db::MemoryDB;
+    use crate::embeddings::Embeddings;
+    use crate::github::{FileEmbeddings, RepositoryEmbeddings};
+    use crate::llm::{Role, Scripted, ToolKind};
+
+    //Only read_file and list_directory are scripted, they don't need embeddings
+    struct NoEmbeddings;
 
-    fn append_message(&mut self, message: ChatCompletionMessage) {
-        self.messages.push(message);
+    impl EmbeddingsModel for NoEmbeddings {
+        fn embed(&self, _string: &str) -> Result<Embeddings> {
+            Err(Error::Model("Not used by the scripted tools".into()))
+        }
     }
 
-    async fn send_request(&self, request: ChatCompletionRequest) -> Result<ChatCompletionResponse> {
-        Ok(self.client.chat_completion(request).await?)
+    fn repository() -> Repository {
+        Repository {
+            host: Default::default(),
+            owner: "owner".into(),
+            name: "repo".into(),
+            branch: "main".into(),
+            token: None,
+            tenant: None,
+        }
     }
 
-    pub async fn generate_answer(&self) {
-        'conversation: loop {
-            let _request = generate_completion_request(self.messages.clone());
+    fn call(id: &str, name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: id.into(),
+            kind: ToolKind::Function,
+            function: FunctionCall {
+                name: name.into(),
+                arguments: arguments.into(),
+            },
         }
     }
+
+    fn calls(calls: Vec<ToolCall>) -> ChatMessage {
+        ChatMessage {
+            role: Role::Assistant,
+            content: None,
+            tool_calls: calls,
+            tool_call_id: None,
+        }
+    }
+
+    fn reply(content: &str) -> ChatMessage {
+        ChatMessage {
+            role: Role::Assistant,
+            ..ChatMessage::user(content)
+        }
+    }
+
+    fn conversation(script: Vec<ChatMessage>, config: Config) -> Conversation {
+        let db = MemoryDB::with(vec![RepositoryEmbeddings {
+            repo_id: repository().to_string(),
+            file_embeddings: ["src/main.rs", "src/lib.rs"]
+                .into_iter()
+                .map(|path| FileEmbeddings {
+                    file: File::new(path.into(), "fn main() {}\n".into(), None),
+                    embeddings: vec![0.0; 4],
+                })
+                .collect(),
+        }]);
+        let agent = Agent {
+            backend: Arc::new(Scripted::new(script)),
+            prompts: Prompts::load(None, false).unwrap(),
+        };
+        let query = Query {
+            repository: repository(),
+            query: "Where does the program start?".into(),
+            filter: SearchFilter::default(),
+            options: ChatOptions::default(),
+        };
+        Conversation::new(
+            query,
+            &agent,
+            Arc::new(db),
+            Arc::new(NoEmbeddings),
+            Arc::new(Hosts::new(&config).unwrap()),
+            Arc::new(config),
+            Tenants::new(&AuthConfig::default()).owner(""),
+        )
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn answers_after_a_tool_call() {
+        let script = vec![
+            calls(vec![call("1", "read_file", r#"{"path": "./src/main.rs"}"#)]),
+            reply("I know enough"),
+            reply("It starts in main [1]"),
+        ];
+        let answer = conversation(script, Config::default())
+            .generate_answer()
+            .await
+            .unwrap();
+
+        assert_eq!(answer.answer, "It starts in main [1]");
+        let steps: Vec<&str> = answer.steps.iter().map(|step| step.name.as_str()).collect();
+        assert_eq!(steps, vec!["read_file"]);
+        assert_eq!(answer.citations.len(), 1);
+        assert!(answer.unverified.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn answers_once_max_steps_are_used() {
+        let mut config = Config::default();
+        config.llm.max_steps = 2;
+        //The third reply would be taken as another step if the loop didn't stop at two
+        let script = vec![
+            calls(vec![call("1", "list_directory", "{}")]),
+            calls(vec![call("2", "read_file", r#"{"path": "src/lib.rs"}"#)]),
+            reply("Both files define main"),
+        ];
+        let answer = conversation(script, config)
+            .generate_answer()
+            .await
+            .unwrap();
+
+        assert_eq!(answer.answer, "Both files define main");
+        assert_eq!(answer.steps.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn parallel_calls_past_max_steps_are_not_run() {
+        let mut config = Config::default();
+        config.llm.max_steps = 2;
+        let script = vec![
+            calls(vec![
+                call("1", "read_file", r#"{"path": "src/main.rs"}"#),
+                call("2", "none", "{}"),
+                call("3", "read_file", r#"{"path": "src/lib.rs"}"#),
+                call("4", "list_directory", "{}"),
+            ]),
+            reply("It starts in main [1]"),
+        ];
+        let answer = conversation(script, config)
+            .generate_answer()
+            .await
+            .unwrap();
+
+        let steps: Vec<&str> = answer
+            .steps
+            .iter()
+            .map(|step| step.arguments.as_str())
+            .collect();
+        assert_eq!(
+            steps,
+            vec![r#"{"path": "src/main.rs"}"#, r#"{"path": "src/lib.rs"}"#]
+        );
+        assert_eq!(answer.citations.len(), 2);
+    }
 }
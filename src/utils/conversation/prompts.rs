@@ -1,120 +1,394 @@
-use openai_api_rs::v1::chat_completion::{
-    ChatCompletionMessage, ChatCompletionRequest, Function, FunctionParameters, JSONSchemaDefine,
-    JSONSchemaType, GPT3_5_TURBO,
+use crate::github::Repository;
+use crate::llm::FunctionDefinition;
+use crate::prelude::*;
+
+use minijinja::Environment;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
 };
-use std::collections::HashMap;
-
-pub fn generate_completion_request(messages: Vec<ChatCompletionMessage>) -> ChatCompletionRequest {
-    ChatCompletionRequest {
-        model: GPT3_5_TURBO.into(),
-        messages,
-        functions: Some(functions()),
-        function_call: Some("auto".to_string()),
-        temperature: None,
-        top_p: None,
-        n: None,
-        stream: None,
-        stop: None,
-        max_tokens: None,
-        presence_penalty: None,
-        frequency_penalty: None,
-        logit_bias: None,
-        user: None,
+
+const MANIFEST: &str = "prompts.toml";
+const TEMPLATES: [&str; 5] = [
+    "system.j2",
+    "user.j2",
+    "answer.j2",
+    "summary.j2",
+    "tools.json.j2",
+];
+//The prompts the binary was built with, used when llm.prompts_dir isn't set
+const BUILTIN: [(&str, &str); 6] = [
+    (MANIFEST, include_str!("../../../prompts/prompts.toml")),
+    ("system.j2", include_str!("../../../prompts/system.j2")),
+    ("user.j2", include_str!("../../../prompts/user.j2")),
+    ("answer.j2", include_str!("../../../prompts/answer.j2")),
+    ("summary.j2", include_str!("../../../prompts/summary.j2")),
+    (
+        "tools.json.j2",
+        include_str!("../../../prompts/tools.json.j2"),
+    ),
+];
+//How often the directory is checked for changes, at most
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    version: String,
+}
+
+#[derive(Serialize)]
+struct RepositoryInfo<'a> {
+    owner: &'a str,
+    name: &'a str,
+    branch: &'a str,
+}
+
+#[derive(Serialize)]
+struct ToolInfo<'a> {
+    name: &'a str,
+    description: &'a str,
+}
+
+//Variables every template can use
+#[derive(Serialize)]
+struct Variables<'a> {
+    repository: RepositoryInfo<'a>,
+    query: &'a str,
+    max_steps: usize,
+    tools: Vec<ToolInfo<'a>>,
+}
+
+//What a conversation is started with, rendered once so a reload can't change prompts mid-conversation
+pub struct Rendered {
+    pub system: String,
+    pub user: String,
+    pub answer: String,
+    pub summary: String,
+    pub tools: Vec<FunctionDefinition>,
+}
+
+pub struct PromptSet {
+    pub version: String,
+    //Of the template sources, tells edited templates apart when the version wasn't bumped
+    pub digest: String,
+    env: Environment<'static>,
+}
+
+impl PromptSet {
+    fn builtin() -> Result<PromptSet> {
+        PromptSet::parse(
+            BUILTIN
+                .iter()
+                .map(|(name, source)| (name.to_string(), source.to_string()))
+                .collect(),
+        )
+    }
+
+    fn load(dir: &Path) -> Result<PromptSet> {
+        let sources = std::iter::once(MANIFEST)
+            .chain(TEMPLATES)
+            .map(|name| {
+                let path = dir.join(name);
+                std::fs::read_to_string(&path)
+                    .map(|source| (name.to_string(), source))
+                    .map_err(|e| Error::Config(format!("Failed to read {}: {e}", path.display())))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        PromptSet::parse(sources)
     }
+
+    fn parse(sources: Vec<(String, String)>) -> Result<PromptSet> {
+        let mut hasher = Sha256::new();
+        let mut env = Environment::new();
+        let mut manifest: Option<Manifest> = None;
+        for (name, source) in sources {
+            hasher.update(name.as_bytes());
+            hasher.update(source.as_bytes());
+            if name == MANIFEST {
+                manifest = Some(
+                    toml::from_str(&source)
+                        .map_err(|e| Error::Config(format!("Invalid {MANIFEST}: {e}")))?,
+                );
+                continue;
+            }
+            env.add_template_owned(name.clone(), source)
+                .map_err(|e| Error::Config(format!("Invalid prompt template {name}: {e}")))?;
+        }
+        let manifest = manifest.ok_or_else(|| Error::Config(format!("{MANIFEST} is missing")))?;
+        let digest = format!("{:x}", hasher.finalize());
+        Ok(PromptSet {
+            version: manifest.version,
+            digest: digest[..12].to_string(),
+            env,
+        })
+    }
+
+    pub fn render(
+        &self,
+        repository: &Repository,
+        query: &str,
+        max_steps: usize,
+    ) -> Result<Rendered> {
+        let mut variables = Variables {
+            repository: RepositoryInfo {
+                owner: &repository.owner,
+                name: &repository.name,
+                branch: &repository.branch,
+            },
+            query,
+            max_steps,
+            tools: Vec::new(),
+        };
+        let tools: Vec<FunctionDefinition> =
+            serde_json::from_str(&self.render_template("tools.json.j2", &variables)?)
+                .map_err(|e| Error::Config(format!("tools.json.j2 is not a list of tools: {e}")))?;
+        variables.tools = tools
+            .iter()
+            .map(|tool| ToolInfo {
+                name: &tool.name,
+                description: &tool.description,
+            })
+            .collect();
+        Ok(Rendered {
+            system: self.render_template("system.j2", &variables)?,
+            user: self.render_template("user.j2", &variables)?,
+            answer: self.render_template("answer.j2", &variables)?,
+            summary: self.render_template("summary.j2", &variables)?,
+            tools,
+        })
+    }
+
+    fn render_template(&self, name: &str, variables: &Variables) -> Result<String> {
+        self.env
+            .get_template(name)
+            .and_then(|template| template.render(variables))
+            .map_err(|e| Error::Config(format!("Failed to render prompt template {name}: {e}")))
+    }
+}
+
+struct ReloadState {
+    checked: Instant,
+    //Of the files last tried, whether they loaded or not
+    modified: Option<SystemTime>,
 }
 
-pub fn functions() -> Vec<Function> {
-    vec![
-        Function {
-            name: "none".into(),
-            description: Some("This is the final step, and signals that you have enough information to respond to the user's query.".into()),
-            parameters: Some(FunctionParameters {
-                schema_type: JSONSchemaType::Null,
-                properties: None,
-                required: None,
-            }.into())
-        },
-        Function {
-            name: "search_codebase".into(),
-            description: Some("Search the contents of files in a repository semantically. Results will not necessarily match search terms exactly, but should be related.".into()),
-            parameters: Some(FunctionParameters {
-                schema_type: JSONSchemaType::Object,
-                properties: Some(HashMap::from([
-                    ("query".into(), Box::new(JSONSchemaDefine {
-                        schema_type: Some(JSONSchemaType::String),
-                        description: Some("The query with which to search. This should consist of keywords that might match something in the repository, e.g. 'project dependencies'".to_string()),
-                        enum_values: None,
-                        properties: None,
-                        required: None,
-                        items: None,
-                    }))
-                ])),
-                required: Some(vec!["query".into()]),
-            }.into())
-        },
-        Function {
-            name: "search_path".into(),
-            description: Some("Search the pathnames in a repository. Results may not be exact matches, but will be similar by some edit-distance. Use when you want to find a specific file".into()),
-            parameters: Some(FunctionParameters {
-                schema_type: JSONSchemaType::Object,
-                properties: Some(HashMap::from([
-                    ("query".into(), Box::new(JSONSchemaDefine {
-                        schema_type: Some(JSONSchemaType::String),
-                        description: Some("The query with which to search. This should consist of keywords that might match a file path, e.g. 'src/components/Footer'.".to_string()),
-                        enum_values: None,
-                        properties: None,
-                        required: None,
-                        items: None,
-                    }))
-                ])),
-                required: Some(vec!["query".into()]),
-            }.into())
-        },
-        Function {
-            name: "search_file".into(),
-            description: Some("Search a file semantically. Results will not necessarily match search terms exactly, but should be related.".into()),
-            parameters: Some(FunctionParameters {
-                schema_type: JSONSchemaType::Object,
-                properties: Some(HashMap::from([
-                    ("query".into(), Box::new(JSONSchemaDefine {
-                        schema_type: Some(JSONSchemaType::String),
-                        description: Some("The query with which to search the file.".to_string()),
-                        enum_values: None,
-                        properties: None,
-                        required: None,
-                        items: None,
-                    })),
-                    ("path".into(), Box::new(JSONSchemaDefine {
-                        schema_type: Some(JSONSchemaType::Number),
-                        description: Some("The file path to search".to_string()),
-                        enum_values: None,
-                        properties: None,
-                        required: None,
-                        items: None,
-                    }))
-                ])),
-                required: Some(vec!["query".into(), "path".into()]),
-            }.into())
+//Serves the prompt set, reloading it when the files in llm.prompts_dir change
+pub struct Prompts {
+    dir: Option<PathBuf>,
+    reload: bool,
+    current: RwLock<Arc<PromptSet>>,
+    state: Mutex<ReloadState>,
+}
+
+impl Prompts {
+    pub fn load(dir: Option<&Path>, reload: bool) -> Result<Prompts> {
+        let prompts = match dir {
+            Some(dir) => PromptSet::load(dir)?,
+            None => PromptSet::builtin()?,
+        };
+        tracing::info!(version = %prompts.version, digest = %prompts.digest, "Loaded prompts");
+        Ok(Prompts {
+            dir: dir.map(Path::to_path_buf),
+            reload,
+            current: RwLock::new(Arc::new(prompts)),
+            state: Mutex::new(ReloadState {
+                checked: Instant::now(),
+                modified: dir.and_then(modified),
+            }),
+        })
+    }
+
+    pub fn current(&self) -> Arc<PromptSet> {
+        self.reload_if_changed();
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    //A broken edit keeps the previous prompts in use, so a typo can't take queries down
+    fn reload_if_changed(&self) {
+        let dir = match (&self.dir, self.reload) {
+            (Some(dir), true) => dir,
+            _ => return,
+        };
+        {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.checked.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+            state.checked = Instant::now();
+            let modified = modified(dir);
+            if modified == state.modified {
+                return;
+            }
+            state.modified = modified;
         }
-    ]
+        match PromptSet::load(dir) {
+            Ok(prompts) => {
+                tracing::info!(version = %prompts.version, digest = %prompts.digest, "Reloaded prompts");
+                *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(prompts);
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to reload prompts, keeping the previous ones")
+            }
+        }
+    }
 }
 
-pub fn system_message() -> String {
-    let mut s = String::new();
-    s.push_str(
-        r#"Your job is to choose a function that will help you answer a query about a repository
-Follow these rules at all times:
-- If the output of a function is empty, try the same function again with different arguments or try using a different function
-- If there have been 5 function calls, respond with functions.none
-- In most cases respond with functions.search_codebase or functions.search_path functions before responding with functions.none
-- Do not assume the structure of the codebase, or the existence of files or folders
-- Do NOT respond with a function that you've used before with the same arguments
-- When you have enough information to answer the  user's query respond with functions.none
-- If after making a path search the query can be answered by the existance of the paths, use the functions.none function
-- Only refer to paths that are returned by the functions.search_path function
-- Respond with functions to find information related to the query, until all relevant information has been found.
-- If after attempting to gather information you are still unsure how to answer the query, respond with the functions.none function
-- Always respond with a function call. Do NOT answer the question directly"#
-);
-    s
+//Newest modification time of the prompt files
+fn modified(dir: &Path) -> Option<SystemTime> {
+    std::iter::once(MANIFEST)
+        .chain(TEMPLATES)
+        .filter_map(|name| std::fs::metadata(dir.join(name)).ok()?.modified().ok())
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repository() -> Repository {
+        Repository {
+            host: Default::default(),
+            owner: "owner".into(),
+            name: "repo".into(),
+            branch: "main".into(),
+            token: None,
+            tenant: None,
+        }
+    }
+
+    fn builtin_sources() -> Vec<(String, String)> {
+        BUILTIN
+            .iter()
+            .map(|(name, source)| (name.to_string(), source.to_string()))
+            .collect()
+    }
+
+    fn write_builtin(dir: &Path) {
+        for (name, source) in BUILTIN {
+            std::fs::write(dir.join(name), source).unwrap();
+        }
+    }
+
+    //Writes a file and moves its modification time ahead, so the change is seen even within the
+    //file system's timestamp granularity
+    fn edit(dir: &Path, name: &str, source: &str, seconds_ahead: u64) {
+        let path = dir.join(name);
+        std::fs::write(&path, source).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(seconds_ahead))
+            .unwrap();
+    }
+
+    //Lets the next call check the directory without waiting for the interval
+    fn expire_check(prompts: &Prompts) {
+        let mut state = prompts.state.lock().unwrap();
+        state.checked = Instant::now()
+            .checked_sub(RELOAD_CHECK_INTERVAL)
+            .unwrap_or(state.checked);
+    }
+
+    #[test]
+    fn builtin_prompts_render() {
+        let prompts = Prompts::load(None, true).unwrap();
+        let prompt_set = prompts.current();
+        assert!(!prompt_set.version.is_empty());
+        assert_eq!(prompt_set.digest.len(), 12);
+
+        let rendered = prompt_set
+            .render(&repository(), "Where is main?", 5)
+            .unwrap();
+        assert!(rendered.user.contains("Owner:owner"));
+        assert!(rendered.user.contains("Query:Where is main?"));
+        let tools: Vec<&str> = rendered
+            .tools
+            .iter()
+            .map(|tool| tool.name.as_str())
+            .collect();
+        for tool in ["search_codebase", "read_file", "list_directory", "none"] {
+            assert!(tools.contains(&tool), "{tool} missing from {tools:?}");
+        }
+        for prompt in [&rendered.system, &rendered.answer, &rendered.summary] {
+            assert!(!prompt.trim().is_empty());
+        }
+    }
+
+    #[test]
+    fn digest_follows_the_sources() {
+        let builtin = PromptSet::parse(builtin_sources()).unwrap();
+        assert_eq!(
+            PromptSet::parse(builtin_sources()).unwrap().digest,
+            builtin.digest
+        );
+
+        let mut sources = builtin_sources();
+        sources[2].1.push_str("\nBe brief.");
+        let edited = PromptSet::parse(sources).unwrap();
+        assert_eq!(edited.version, builtin.version);
+        assert_ne!(edited.digest, builtin.digest);
+    }
+
+    #[test]
+    fn broken_sources_are_rejected() {
+        let without_manifest: Vec<_> = builtin_sources()
+            .into_iter()
+            .filter(|(name, _)| name != MANIFEST)
+            .collect();
+        let mut bad_template = builtin_sources();
+        bad_template[1].1 = "{% if query %}unclosed".into();
+        let mut bad_manifest = builtin_sources();
+        bad_manifest[0].1 = "version = \"4\"\nauthor = \"someone\"".into();
+
+        for sources in [without_manifest, bad_template, bad_manifest] {
+            assert!(matches!(PromptSet::parse(sources), Err(Error::Config(_))));
+        }
+    }
+
+    #[test]
+    fn reload_keeps_the_previous_set_until_an_edit_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        write_builtin(dir.path());
+        let prompts = Prompts::load(Some(dir.path()), true).unwrap();
+        let loaded = prompts.current();
+
+        //Unchanged files aren't loaded again
+        expire_check(&prompts);
+        assert!(Arc::ptr_eq(&prompts.current(), &loaded));
+
+        edit(dir.path(), "user.j2", "Query:{{ query", 10);
+        expire_check(&prompts);
+        assert!(Arc::ptr_eq(&prompts.current(), &loaded));
+
+        edit(dir.path(), "user.j2", "Query:{{ query }}", 20);
+        edit(dir.path(), MANIFEST, "version = \"4\"", 20);
+        //Changes within the interval wait for the next check
+        assert!(Arc::ptr_eq(&prompts.current(), &loaded));
+        expire_check(&prompts);
+        let reloaded = prompts.current();
+        assert_eq!(reloaded.version, "4");
+        assert_ne!(reloaded.digest, loaded.digest);
+        let rendered = reloaded.render(&repository(), "Where is main?", 5).unwrap();
+        assert_eq!(rendered.user, "Query:Where is main?");
+    }
+
+    #[test]
+    fn reload_can_be_turned_off() {
+        let dir = tempfile::tempdir().unwrap();
+        write_builtin(dir.path());
+        let prompts = Prompts::load(Some(dir.path()), false).unwrap();
+        let loaded = prompts.current();
+
+        edit(dir.path(), MANIFEST, "version = \"4\"", 10);
+        expire_check(&prompts);
+        assert!(Arc::ptr_eq(&prompts.current(), &loaded));
+    }
 }
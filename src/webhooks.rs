@@ -0,0 +1,582 @@
+use crate::{
+    auth::{Tenants, TENANT_SEPARATOR},
+    config::Config,
+    db::{QdrantDB, RepositoryEmbeddingsDB},
+    embeddings::Model,
+    github::{embed_files, Repository, RepositoryEmbeddings},
+    hosts::{Host, Hosts},
+    jobs::JobGuard,
+    limits::RateLimits,
+    prelude::*,
+};
+use futures::{channel::mpsc, StreamExt};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+use tracing::Instrument;
+
+pub const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+pub const EVENT_HEADER: &str = "x-github-event";
+pub const DELIVERY_HEADER: &str = "x-github-delivery";
+//Push payloads list at most this many commits, files changed by the rest aren't known
+const MAX_PAYLOAD_COMMITS: usize = 20;
+const REMEMBERED_DELIVERIES: usize = 1000;
+
+pub fn verify_signature(secret: &str, body: &[u8], signature: Option<&str>) -> Result<()> {
+    let signature = signature
+        .and_then(|signature| signature.strip_prefix("sha256="))
+        .ok_or_else(|| Error::Unauthenticated("Webhook signature is missing".into()))?;
+    let signature = hex::decode(signature)
+        .map_err(|_| Error::Unauthenticated("Webhook signature is malformed".into()))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| Error::Config(format!("Invalid webhook secret: {e}")))?;
+    mac.update(body);
+    //Constant time, so the signature can't be guessed byte by byte
+    mac.verify_slice(&signature)
+        .map_err(|_| Error::Unauthenticated("Webhook signature doesn't match".into()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushEvent {
+    #[serde(rename = "ref")]
+    pub git_ref: String,
+    pub after: String,
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub forced: bool,
+    pub repository: PushRepository,
+    #[serde(default)]
+    pub commits: Vec<PushCommit>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushRepository {
+    pub name: String,
+    pub owner: PushOwner,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushOwner {
+    pub login: Option<String>,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PushCommit {
+    #[serde(default)]
+    pub added: Vec<String>,
+    #[serde(default)]
+    pub modified: Vec<String>,
+    #[serde(default)]
+    pub removed: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Changes {
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+    //Set when the payload can't tell what changed, the whole branch is re-indexed instead
+    pub full: bool,
+}
+
+impl PushEvent {
+    //None for tag pushes and deleted branches
+    pub fn repository(&self) -> Option<Repository> {
+        if self.deleted {
+            return None;
+        }
+        let branch = self.git_ref.strip_prefix("refs/heads/")?;
+        let owner = self
+            .repository
+            .owner
+            .login
+            .clone()
+            .or_else(|| self.repository.owner.name.clone())?;
+        Some(Repository {
+            host: Host::GitHub,
+            owner,
+            name: self.repository.name.clone(),
+            branch: branch.to_string(),
+            token: None,
+            tenant: None,
+        })
+    }
+
+    pub fn changes(&self) -> Changes {
+        //Commits are listed oldest first, so a file's last mention decides whether it still exists
+        let mut state: BTreeMap<&str, bool> = BTreeMap::new();
+        for commit in &self.commits {
+            for path in commit.added.iter().chain(&commit.modified) {
+                state.insert(path, true);
+            }
+            for path in &commit.removed {
+                state.insert(path, false);
+            }
+        }
+        let (changed, removed): (Vec<_>, Vec<_>) =
+            state.into_iter().partition(|(_, exists)| *exists);
+        Changes {
+            changed: changed
+                .into_iter()
+                .map(|(path, _)| path.to_string())
+                .collect(),
+            removed: removed
+                .into_iter()
+                .map(|(path, _)| path.to_string())
+                .collect(),
+            //Force pushes can drop commits whose files are then never listed
+            full: self.forced
+                || self.commits.is_empty()
+                || self.commits.len() >= MAX_PAYLOAD_COMMITS,
+        }
+    }
+}
+
+//Collections indexing the repository, once per tenant that indexed it
+pub fn tracking_collections(repositories: Vec<String>, repository: &Repository) -> Vec<String> {
+    let repo_id = repository.to_string();
+    repositories
+        .into_iter()
+        .filter(|collection| {
+            collection == &repo_id
+                || collection
+                    .strip_suffix(&repo_id)
+                    .is_some_and(|prefix| prefix.ends_with(TENANT_SEPARATOR))
+        })
+        .collect()
+}
+
+pub struct Reindex {
+    pub repository: Repository,
+    pub commit: String,
+    pub collections: Vec<String>,
+    pub changes: Changes,
+    //Held from the moment the push is accepted so shutdown waits for queued work too
+    pub jobs: Vec<JobGuard>,
+}
+
+//GitHub redelivers on timeouts and on request, the same delivery is only applied once
+pub struct Deliveries {
+    seen: Mutex<(HashSet<String>, VecDeque<String>)>,
+}
+
+impl Deliveries {
+    fn new() -> Deliveries {
+        Deliveries {
+            seen: Mutex::new((HashSet::new(), VecDeque::new())),
+        }
+    }
+
+    //False when the delivery was seen before
+    pub fn insert(&self, id: &str) -> bool {
+        let mut seen = self.lock();
+        let (ids, order) = &mut *seen;
+        if !ids.insert(id.to_string()) {
+            return false;
+        }
+        order.push_back(id.to_string());
+        if order.len() > REMEMBERED_DELIVERIES {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    //For deliveries that couldn't be accepted, so their redelivery is
+    pub fn forget(&self, id: &str) {
+        let mut seen = self.lock();
+        let (ids, order) = &mut *seen;
+        if ids.remove(id) {
+            order.retain(|seen| seen != id);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, (HashSet<String>, VecDeque<String>)> {
+        self.seen.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+//Pushes are applied one at a time in the order they arrived, so an older push can't overwrite a newer one
+pub struct Queue {
+    sender: mpsc::UnboundedSender<Reindex>,
+    pub deliveries: Deliveries,
+}
+
+impl Queue {
+    pub fn start(
+        db: Arc<QdrantDB>,
+        model: Arc<Model>,
+        hosts: Arc<Hosts>,
+        config: Arc<Config>,
+        limits: Arc<RateLimits>,
+        tenants: Arc<Tenants>,
+    ) -> Queue {
+        let (sender, mut receiver) = mpsc::unbounded::<Reindex>();
+        actix_web::rt::spawn(async move {
+            while let Some(reindex) = receiver.next().await {
+                let span = tracing::info_span!(
+                    "reindex",
+                    repo_id = %reindex.repository.to_string(),
+                    commit = %reindex.commit,
+                    full = reindex.changes.full
+                );
+                let result = apply(&reindex, &db, &model, &hosts, &config, &limits, &tenants)
+                    .instrument(span.clone())
+                    .await;
+                if let Err(e) = result {
+                    tracing::error!(parent: &span, error = %e, "Webhook re-index failed");
+                }
+            }
+        });
+        Queue {
+            sender,
+            deliveries: Deliveries::new(),
+        }
+    }
+
+    pub fn enqueue(&self, reindex: Reindex) -> Result<()> {
+        self.sender
+            .unbounded_send(reindex)
+            .map_err(|_| Error::ShuttingDown)
+    }
+}
+
+async fn apply(
+    reindex: &Reindex,
+    db: &Arc<QdrantDB>,
+    model: &Arc<Model>,
+    hosts: &Arc<Hosts>,
+    config: &Arc<Config>,
+    limits: &RateLimits,
+    tenants: &Tenants,
+) -> Result<()> {
+    let _permit = limits.embeddings.wait().await;
+    let Reindex {
+        repository,
+        commit,
+        collections,
+        changes,
+        jobs,
+    } = reindex;
+
+    let files = if changes.full {
+        let repository = hosts.resolve(repository.clone()).await?;
+        hosts.fetch_repo_files(&repository, &config.fetch).await?
+    } else {
+        hosts
+            .fetch_files(repository, commit, &changes.changed, &config.fetch)
+            .await?
+    };
+    //Embedded once for every tenant tracking the branch, off the runtime threads
+    let model = model.clone();
+    let embedded_repository = repository.clone();
+    let embeddings = actix_web::rt::task::spawn_blocking(move || {
+        embed_files(embedded_repository, files, model.as_ref())
+    })
+    .await
+    .map_err(|e| Error::Model(e.to_string()))?;
+    jobs.iter().for_each(JobGuard::writing);
+
+    //A tenant over its quota or a failed write doesn't hold up the other tenants
+    let mut failed = 0;
+    for collection in collections {
+        let result = store(
+            db.as_ref(),
+            collection,
+            &embeddings,
+            changes,
+            tenants,
+            config.fetch.max_file_count as usize,
+        )
+        .await;
+        if let Err(e) = result {
+            tracing::error!(%collection, error = %e, "Unable to update collection");
+            failed += 1;
+        }
+    }
+    tracing::info!(
+        files = embeddings.file_embeddings.len(),
+        removed = changes.removed.len(),
+        collections = collections.len(),
+        failed,
+        "Applied push"
+    );
+    Ok(())
+}
+
+//Builds a new version of the collection and swaps it in, so searches never see a half-applied push
+async fn store<D: RepositoryEmbeddingsDB>(
+    db: &D,
+    collection: &str,
+    embeddings: &RepositoryEmbeddings,
+    changes: &Changes,
+    tenants: &Tenants,
+    max_file_count: usize,
+) -> Result<()> {
+    let updated = if changes.full {
+        RepositoryEmbeddings {
+            repo_id: collection.to_string(),
+            ..embeddings.clone()
+        }
+    } else {
+        //Changed files that weren't embedded (too big, binary, failed) would otherwise keep their old content
+        let embedded: HashSet<&str> = embeddings
+            .file_embeddings
+            .iter()
+            .map(|file| file.file.path.as_str())
+            .collect();
+        let removed: Vec<String> = changes
+            .removed
+            .iter()
+            .chain(
+                changes
+                    .changed
+                    .iter()
+                    .filter(|path| !embedded.contains(path.as_str())),
+            )
+            .cloned()
+            .collect();
+        db.get_repo_embeddings(collection).await?.with_changes(
+            embeddings.file_embeddings.clone(),
+            &removed,
+            max_file_count,
+        )
+    };
+    tenants
+        .owner(collection)
+        .check_vector_quota(db, collection, updated.file_embeddings.len() as u64)
+        .await?;
+    db.insert_repo_embeddings(updated).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthConfig;
+    use crate::db::MemoryDB;
+    use crate::github::{File, FileEmbeddings};
+
+    const PUSH: &[u8] = include_bytes!("../fixtures/webhooks/push.json");
+    const PING: &[u8] = include_bytes!("../fixtures/webhooks/ping.json");
+    const SECRET: &str = "It's a Secret to Everybody";
+    //HMAC-SHA256 of the fixtures with SECRET, computed independently
+    const PUSH_SIGNATURE: &str =
+        "sha256=ee529357206f163eb8194d170a8cbba28c0efa5b29ed86b683bcd1a16eea481b";
+    const PING_SIGNATURE: &str =
+        "sha256=0af921a5c38627bb41a70ffdd06f69e698fd61546102a08fe77d2c68fbc575bc";
+
+    #[test]
+    fn accepts_valid_signatures() {
+        verify_signature(SECRET, PUSH, Some(PUSH_SIGNATURE)).unwrap();
+        verify_signature(SECRET, PING, Some(PING_SIGNATURE)).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_signatures() {
+        let unauthenticated = |result: Result<()>| matches!(result, Err(Error::Unauthenticated(_)));
+        assert!(unauthenticated(verify_signature(SECRET, PUSH, None)));
+        assert!(unauthenticated(verify_signature(
+            SECRET,
+            PUSH,
+            Some(PING_SIGNATURE)
+        )));
+        assert!(unauthenticated(verify_signature(
+            "other secret",
+            PUSH,
+            Some(PUSH_SIGNATURE)
+        )));
+        assert!(unauthenticated(verify_signature(
+            SECRET,
+            PUSH,
+            Some(PUSH_SIGNATURE.trim_start_matches("sha256="))
+        )));
+        assert!(unauthenticated(verify_signature(
+            SECRET,
+            PUSH,
+            Some("sha256=zz")
+        )));
+
+        let mut tampered = PUSH.to_vec();
+        tampered[0] = b' ';
+        assert!(unauthenticated(verify_signature(
+            SECRET,
+            &tampered,
+            Some(PUSH_SIGNATURE)
+        )));
+    }
+
+    #[test]
+    fn deduplicates_deliveries() {
+        let deliveries = Deliveries::new();
+        assert!(deliveries.insert("72d3162e-cc78-11e3-81ab-4c9367dc0958"));
+        assert!(!deliveries.insert("72d3162e-cc78-11e3-81ab-4c9367dc0958"));
+        assert!(deliveries.insert("0b989ba4-242f-11e5-81e1-c7b6966d2516"));
+
+        deliveries.forget("72d3162e-cc78-11e3-81ab-4c9367dc0958");
+        assert!(deliveries.insert("72d3162e-cc78-11e3-81ab-4c9367dc0958"));
+    }
+
+    #[test]
+    fn forgets_the_oldest_deliveries() {
+        let deliveries = Deliveries::new();
+        for id in 0..=REMEMBERED_DELIVERIES {
+            assert!(deliveries.insert(&id.to_string()));
+        }
+        assert!(deliveries.insert("0"));
+        assert!(!deliveries.insert(&REMEMBERED_DELIVERIES.to_string()));
+    }
+
+    #[test]
+    fn diffs_push_payloads() {
+        let event: PushEvent = serde_json::from_slice(PUSH).unwrap();
+        let changes = event.changes();
+        assert_eq!(
+            changes.changed,
+            [
+                "README.md",
+                "onn.example.toml",
+                "src/config.rs",
+                "src/main.rs"
+            ]
+        );
+        assert_eq!(changes.removed, ["src/env.rs"]);
+        assert!(!changes.full);
+
+        let repository = event.repository().unwrap();
+        assert_eq!(
+            repository.full_name(),
+            "Anush008/Embedding-generation-proto"
+        );
+        assert_eq!(repository.branch, "master");
+        assert_eq!(event.after, "59b20b8d5c6ff8d09518454d4dd8b7a30f095ab5");
+    }
+
+    #[test]
+    fn later_commits_decide_whether_a_file_exists() {
+        let mut event: PushEvent = serde_json::from_slice(PUSH).unwrap();
+        event.commits.push(PushCommit {
+            added: vec!["src/env.rs".into()],
+            modified: Vec::new(),
+            removed: vec!["README.md".into()],
+        });
+        let changes = event.changes();
+        assert_eq!(
+            changes.changed,
+            [
+                "onn.example.toml",
+                "src/config.rs",
+                "src/env.rs",
+                "src/main.rs"
+            ]
+        );
+        assert_eq!(changes.removed, ["README.md"]);
+    }
+
+    #[test]
+    fn reindexes_everything_when_the_payload_is_incomplete() {
+        let mut event: PushEvent = serde_json::from_slice(PUSH).unwrap();
+        event.forced = true;
+        assert!(event.changes().full);
+
+        event.forced = false;
+        event.commits.clear();
+        assert!(event.changes().full);
+    }
+
+    #[test]
+    fn ignores_tags_deleted_branches_and_pings() {
+        let mut event: PushEvent = serde_json::from_slice(PUSH).unwrap();
+        event.git_ref = "refs/tags/v1.0".into();
+        assert!(event.repository().is_none());
+
+        let mut event: PushEvent = serde_json::from_slice(PUSH).unwrap();
+        event.deleted = true;
+        assert!(event.repository().is_none());
+
+        assert!(serde_json::from_slice::<PushEvent>(PING).is_err());
+    }
+
+    #[test]
+    fn finds_collections_of_every_tenant() {
+        let event: PushEvent = serde_json::from_slice(PUSH).unwrap();
+        let repository = event.repository().unwrap();
+        let repo_id = repository.to_string();
+        let collections = tracking_collections(
+            vec![
+                repo_id.clone(),
+                format!("acme{TENANT_SEPARATOR}{repo_id}"),
+                format!("{repo_id}-fork"),
+                format!("acme{TENANT_SEPARATOR}other-repo-master"),
+            ],
+            &repository,
+        );
+        assert_eq!(
+            collections,
+            [repo_id.clone(), format!("acme{TENANT_SEPARATOR}{repo_id}")]
+        );
+    }
+
+    fn embedded(path: &str, content: &str) -> FileEmbeddings {
+        FileEmbeddings {
+            file: File::new(path.to_string(), content.to_string(), None),
+            embeddings: vec![0.0; 4],
+        }
+    }
+
+    #[actix_web::test]
+    async fn drops_changed_files_that_were_not_embedded_again() {
+        let db = MemoryDB::with(vec![RepositoryEmbeddings {
+            repo_id: "owner-repo-main".into(),
+            file_embeddings: vec![
+                embedded("README.md", "old"),
+                embedded("assets/logo.bin", "old"),
+                embedded("src/env.rs", "old"),
+                embedded("src/lib.rs", "unchanged"),
+            ],
+        }]);
+        let changes = Changes {
+            changed: vec![
+                "README.md".into(),
+                "assets/logo.bin".into(),
+                "src/a.rs".into(),
+                "src/b.rs".into(),
+            ],
+            removed: vec!["src/env.rs".into()],
+            full: false,
+        };
+        //The binary file was skipped when fetching, so only the others come back embedded
+        let embeddings = RepositoryEmbeddings {
+            repo_id: "owner-repo-main".into(),
+            file_embeddings: vec![
+                embedded("README.md", "new"),
+                embedded("src/a.rs", "new"),
+                embedded("src/b.rs", "new"),
+            ],
+        };
+        let tenants = Tenants::new(&AuthConfig::default());
+
+        store(&db, "owner-repo-main", &embeddings, &changes, &tenants, 3)
+            .await
+            .unwrap();
+        let stored = db.get_repo_embeddings("owner-repo-main").await.unwrap();
+        let files: Vec<(&str, &str)> = stored
+            .file_embeddings
+            .iter()
+            .map(|file| (file.file.path.as_str(), file.file.content.as_str()))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("README.md", "new"),
+                ("src/lib.rs", "unchanged"),
+                ("src/a.rs", "new")
+            ]
+        );
+    }
+}